@@ -1,53 +1,414 @@
-use airscan_lib::{fetch_result, post_scanrequest};
-use clap::Parser;
-use url::Url;
+use std::{path::PathBuf, time::Duration};
+
+#[cfg(feature = "ocr")]
+use airscan_lib::ocr::TesseractEngine;
+use airscan_lib::{
+    discover, unique_path, AirscanError, CancellationToken, ColorMode, Credentials,
+    DiscoveryOptions, FetchOptions, FilenameContext, FilenameTemplate, InputSource, Intent,
+    KnownScanners, PaperSize, PdfSink, PinCallback, Progress, ProgressCallback, Region,
+    ScanSettings, ScannerClient, TiffCompression, TiffSink, TlsVerification,
+};
+use clap::{Parser, Subcommand};
 
 /// Scan from an AirScan capable scanner
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// URL of the scannner, defaults to http://brother/eSCL
-    #[arg(short, long, default_value = "http://192.168.2.38/eSCL")]
-    url: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Also print the requests sent to the scanner and its responses
+    #[arg(short, long, global = true)]
+    verbose: bool,
+
+    /// URL of the scannner, e.g. http://192.168.2.38/eSCL. Defaults to the first discovered scanner
+    #[arg(short, long)]
+    url: Option<String>,
+
+    /// Trust https scanners whose certificate is signed by a CA in this PEM file
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["insecure", "known_scanners"])]
+    cacert: Option<PathBuf>,
+
+    /// Trust the certificate an https scanner presents the first time and pin it in FILE,
+    /// ~/.config/airscan/known_scanners if no FILE is given
+    #[arg(long, value_name = "FILE")]
+    known_scanners: Option<Option<PathBuf>>,
+
+    /// Accept any certificate of an https scanner
+    #[arg(long, conflicts_with = "known_scanners")]
+    insecure: bool,
+
+    /// Username for scanners that require a login, also read from AIRSCAN_USERNAME
+    #[arg(long, conflicts_with = "netrc")]
+    username: Option<String>,
+
+    /// Password for --username, also read from AIRSCAN_PASSWORD
+    #[arg(long, requires = "username")]
+    password: Option<String>,
 
-    /// Source
+    /// Read the login for the scanner from a netrc FILE, $NETRC or ~/.netrc if no FILE is given
+    #[arg(long, value_name = "FILE")]
+    netrc: Option<Option<PathBuf>>,
+
+    /// Source: Platen or Feeder
     #[arg(short, long, default_value = "Feeder")]
-    source: String,
+    source: InputSource,
+
+    /// Color mode: BlackAndWhite1 (bw), Grayscale8 (gray), Grayscale16, RGB24 (color) or RGB48
+    #[arg(short, long, default_value = "RGB24")]
+    color: ColorMode,
+
+    /// Intent: Document, TextAndGraphic, Photo, Preview, Object or BusinessCard
+    #[arg(short, long)]
+    intent: Option<Intent>,
+
+    /// Brightness, in the range of the scanner, e.g. -50 to 50 or 0 to 2000
+    #[arg(long, allow_hyphen_values = true)]
+    brightness: Option<i32>,
+
+    /// Contrast, in the range of the scanner
+    #[arg(long, allow_hyphen_values = true)]
+    contrast: Option<i32>,
+
+    /// Gamma, in the range of the scanner
+    #[arg(long, allow_hyphen_values = true)]
+    gamma: Option<i32>,
+
+    /// Highlight, in the range of the scanner
+    #[arg(long, allow_hyphen_values = true)]
+    highlight: Option<i32>,
+
+    /// Shadow, in the range of the scanner
+    #[arg(long, allow_hyphen_values = true)]
+    shadow: Option<i32>,
+
+    /// Sharpening, in the range of the scanner
+    #[arg(long, allow_hyphen_values = true)]
+    sharpen: Option<i32>,
+
+    /// Black and white threshold for --color bw, usually 0 to 255
+    #[arg(long, allow_hyphen_values = true)]
+    threshold: Option<i32>,
 
     /// Resolution
     #[arg(short, long, default_value = "300")]
-    resolution: String,
+    resolution: u32,
 
-    /// Format jpg or pdf
+    /// Format jpg, png, pdf or tiff. tiff combines the pages into one file, Group 4 compressed
+    /// with --color BlackAndWhite1 and LZW compressed otherwise. tiff-g4 scans in black and
+    /// white (or grayscale) for Group 4, tiff-lzw and tiff-jpeg select the compression. Pages
+    /// are scanned as PNG if the scanner offers it, as JPEG for tiff-jpeg
     #[arg(short, long, default_value = "pdf")]
     format: String,
+
+    /// Output file name with placeholders {page:03}, {date}, {time}, {job_uuid}, {scanner}
+    /// and {ext}. Feeder pages are numbered name-1.ext, ... unless it contains {page}
+    #[arg(short, long, default_value = "scan.{ext}")]
+    output: FilenameTemplate,
+
+    /// Paper size: a3, a4, a5, a6, b5, letter, legal, business-card, 3.5x5, 4x6, 5x7, 8x10
+    #[arg(short, long, conflicts_with = "region")]
+    paper: Option<PaperSize>,
+
+    /// Custom scan region WIDTHxHEIGHT[+X+Y][mm|in], e.g. 80x200mm or 4x6+1+1in
+    #[arg(long)]
+    region: Option<Region>,
+
+    /// Scan both sides of each page, requires the Feeder source
+    #[arg(short, long)]
+    duplex: bool,
+
+    /// Rotate back side pages by 180°, for feeders that deliver them upside down
+    #[arg(long, requires = "duplex")]
+    rotate_back: bool,
+
+    /// Drop pages with less ink coverage than PERCENT, also on the scanner if it supports it
+    #[arg(long, value_name = "PERCENT", num_args = 0..=1, default_missing_value = "0.1")]
+    skip_blank: Option<f64>,
+
+    /// Straighten pages that were fed in at an angle
+    #[arg(long)]
+    deskew: bool,
+
+    /// Crop pages to the paper, removing the dark scan background around it
+    #[arg(long)]
+    crop: bool,
+
+    /// Scan JPEG pages and combine them into one PDF, for scanners without PDF output
+    #[arg(long, conflicts_with = "format")]
+    combine_pdf: bool,
+
+    /// Recognize text with tesseract in LANG, e.g. deu+eng, and make the combined PDF searchable
+    #[cfg(feature = "ocr")]
+    #[arg(long, value_name = "LANG", num_args = 0..=1, default_missing_value = "eng")]
+    #[arg(requires = "combine_pdf")]
+    ocr: Option<String>,
+
+    /// Also save the recognized text as plain text next to the PDF
+    #[cfg(feature = "ocr")]
+    #[arg(long, requires = "ocr")]
+    ocr_text: bool,
+
+    /// Also save the recognized text with its positions as hOCR next to the PDF
+    #[cfg(feature = "ocr")]
+    #[arg(long, requires = "ocr")]
+    ocr_hocr: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// List eSCL scanners announced via mDNS on the local network
+    Discover {
+        /// Seconds to wait for announcements
+        #[arg(short, long, default_value = "3")]
+        timeout: u64,
+    },
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let opt = Args::parse();
-
-    let multifile = if &opt.source == "Feeder" && opt.format != "pdf" {
-        true
+    log::set_logger(&StderrLogger)?;
+    log::set_max_level(if opt.verbose {
+        log::LevelFilter::Debug
     } else {
-        false
+        log::LevelFilter::Info
+    });
+
+    if let Some(Command::Discover { timeout }) = opt.command {
+        let options = DiscoveryOptions {
+            timeout: Duration::from_secs(timeout),
+            ..DiscoveryOptions::default()
+        };
+        for scanner in discover(&options).await? {
+            println!(
+                "{}\t{}\t{}",
+                scanner.url,
+                scanner.make_and_model.as_deref().unwrap_or(&scanner.name),
+                scanner.input_sources.join(",")
+            );
+        }
+        return Ok(());
+    }
+
+    if opt.duplex && opt.source != InputSource::Feeder {
+        return Err("--duplex requires --source Feeder".into());
+    }
+
+    let tiff = tiff_compression(&opt.format, opt.color)?;
+    let multifile = opt.source == InputSource::Feeder && opt.format != "pdf";
+    let format = match tiff {
+        Some(compression) => compression.scan_document_format(None).to_string(),
+        None if opt.combine_pdf => document_format("jpg"),
+        None => document_format(&opt.format),
     };
 
-    let result = post_scanrequest(
-        &opt.url,
-        &opt.source,
-        &opt.resolution,
-        &format!("application/{}", opt.format),
-        "RGB24",
-    )
-    .await?;
+    let mut settings = ScanSettings::builder()
+        .input_source(opt.source)
+        .resolution(opt.resolution)
+        .document_format(format)
+        .color_mode(opt.color);
+    if opt.duplex {
+        settings = settings.duplex(true);
+    }
+    if let Some(intent) = opt.intent {
+        settings = settings.intent(intent);
+    }
+    if let Some(paper) = opt.paper {
+        settings = settings.scan_region(paper);
+    }
+    if let Some(region) = opt.region {
+        settings = settings.scan_region(region);
+    }
+    let mut settings = settings.build();
+    settings.brightness = opt.brightness;
+    settings.contrast = opt.contrast;
+    settings.gamma = opt.gamma;
+    settings.highlight = opt.highlight;
+    settings.shadow = opt.shadow;
+    settings.sharpen = opt.sharpen;
+    settings.threshold = opt.threshold;
 
-    let valid_result_url =
-        Url::parse(result.as_str()).unwrap_or_else(|_error| panic!("unvalid scanner data"));
+    let cancel = CancellationToken::new();
+    tokio::spawn({
+        let cancel = cancel.clone();
+        async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                cancel.cancel();
+            }
+        }
+    });
 
-    let outfile = format!("scan.{}", opt.format);
+    let tls = tls_verification(&opt)?;
+    let url = match opt.url.clone() {
+        Some(url) => url,
+        None => discover(&DiscoveryOptions::default())
+            .await?
+            .into_iter()
+            .next()
+            .map(|scanner| scanner.url)
+            .ok_or("No scanner found, use --url to select one")?,
+    };
+    let mut client = ScannerClient::builder(&url)
+        .tls(tls)
+        .on_pin(PinCallback::new(|scanner, fingerprint| {
+            eprintln!(
+                "Trusting certificate {} of {} from now on",
+                fingerprint, scanner
+            )
+        }));
+    if let Some(credentials) = credentials(&opt, &url)? {
+        client = client.credentials(credentials);
+    }
+    let client = client.build()?;
+    if opt.skip_blank.is_some() || tiff.is_some() {
+        if let Ok(capabilities) = client.capabilities().await {
+            if opt.skip_blank.is_some() && capabilities.supports_blank_page_removal() {
+                settings.blank_page_removal = Some(true);
+            }
+            if let Some(compression) = tiff {
+                let caps = capabilities.input_caps(settings.input_source, opt.duplex);
+                settings.color_mode = compression.scan_color_mode(settings.color_mode, caps);
+                settings.document_format = compression.scan_document_format(caps).to_string();
+            }
+        }
+    }
+    let location = tokio::select! {
+        location = client.submit_job(&settings) => location?,
+        _ = cancel.cancelled() => return Err(AirscanError::Cancelled.into()),
+    };
 
-    fetch_result(valid_result_url, &outfile, multifile).await?;
+    let options = FetchOptions {
+        rotate_back_side: opt.rotate_back,
+        cancel,
+        progress: Some(ProgressCallback::new(print_progress)),
+        blank_page_threshold: opt.skip_blank.map(|percent| percent / 100.0),
+        deskew: opt.deskew,
+        crop: opt.crop,
+        document_format: Some(settings.document_format.clone()),
+    };
+    let output_file = |extension: &str| {
+        let context = FilenameContext {
+            extension: extension.to_string(),
+            ..FilenameContext::for_job(&location)
+        };
+        let path = opt.output.render(&context)?;
+        if let Some(parent) = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            std::fs::create_dir_all(parent)?;
+        }
+        Ok::<_, Box<dyn std::error::Error>>(unique_path(path)?)
+    };
+    if opt.combine_pdf {
+        let path = output_file("pdf")?;
+        #[allow(unused_mut)]
+        let mut sink = PdfSink::new(&path, settings.x_resolution, settings.y_resolution);
+        #[cfg(feature = "ocr")]
+        if let Some(language) = &opt.ocr {
+            sink = sink.with_ocr(TesseractEngine::new().with_language(language));
+            if opt.ocr_text {
+                sink = sink.with_text_sidecar(output_file("txt")?);
+            }
+            if opt.ocr_hocr {
+                sink = sink.with_hocr_sidecar(output_file("hocr")?);
+            }
+        }
+        client.fetch_to_sink(&location, &mut sink, &options).await?;
+        eprintln!("\nSaved {}", path.display());
+    } else if let Some(compression) = tiff {
+        let path = output_file("tiff")?;
+        let mut sink = TiffSink::new(&path, settings.x_resolution, settings.y_resolution)
+            .with_compression(compression);
+        client.fetch_to_sink(&location, &mut sink, &options).await?;
+        eprintln!("\nSaved {}", path.display());
+    } else {
+        client
+            .fetch_to_files(&location, &opt.output, multifile, &options)
+            .await?;
+    }
+    eprintln!();
 
     Ok(())
 }
+
+fn tls_verification(opt: &Args) -> Result<TlsVerification, Box<dyn std::error::Error>> {
+    if let Some(path) = &opt.cacert {
+        return Ok(TlsVerification::CaBundle(path.clone()));
+    }
+    if opt.insecure {
+        return Ok(TlsVerification::Insecure);
+    }
+    Ok(match &opt.known_scanners {
+        Some(Some(path)) => TlsVerification::TrustOnFirstUse(path.clone()),
+        Some(None) => TlsVerification::TrustOnFirstUse(
+            KnownScanners::default_path().ok_or("No home directory for --known-scanners")?,
+        ),
+        None => TlsVerification::Standard,
+    })
+}
+
+/// Login from the command line, the environment or a netrc file, in that order.
+fn credentials(opt: &Args, url: &str) -> Result<Option<Credentials>, Box<dyn std::error::Error>> {
+    if let Some(username) = &opt.username {
+        let password = match &opt.password {
+            Some(password) => password.clone(),
+            None => std::env::var("AIRSCAN_PASSWORD").unwrap_or_default(),
+        };
+        return Ok(Some(Credentials::new(username, password)));
+    }
+    let Some(netrc) = &opt.netrc else {
+        return Ok(Credentials::from_env());
+    };
+    let path = match netrc {
+        Some(path) => path.clone(),
+        None => Credentials::netrc_path().ok_or("No home directory for --netrc")?,
+    };
+    Ok(Credentials::from_netrc(path, url)?)
+}
+
+/// Prints messages of the library to stderr, stdout is kept for scan results.
+struct StderrLogger;
+
+impl log::Log for StderrLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.target().starts_with("airscan")
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("{}", record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+fn print_progress(page: u32, progress: Progress) {
+    let received = progress.bytes_received / 1024;
+    match progress.content_length {
+        Some(length) => eprint!("\rPage {}: {} of {} KiB", page, received, length / 1024),
+        None => eprint!("\rPage {}: {} KiB", page, received),
+    }
+}
+
+/// TIFF compression for `tiff` and `tiff-<compression>` formats, by color mode for `tiff`.
+fn tiff_compression(
+    format: &str,
+    color: ColorMode,
+) -> Result<Option<TiffCompression>, AirscanError> {
+    match format.split_once('-') {
+        Some(("tiff", compression)) => compression.parse().map(Some),
+        None if format == "tiff" => Ok(Some(TiffCompression::for_color_mode(color))),
+        _ => Ok(None),
+    }
+}
+
+fn document_format(format: &str) -> String {
+    match format {
+        "jpg" | "jpeg" => String::from("image/jpeg"),
+        "png" => String::from("image/png"),
+        _ => format!("application/{}", format),
+    }
+}
@@ -0,0 +1,392 @@
+//! HTTP Basic and Digest authentication for scanners that protect their eSCL endpoints.
+
+use std::{
+    borrow::Cow,
+    fmt,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use digest_auth::{AuthContext, HttpMethod, WwwAuthenticateHeader};
+use reqwest::{
+    header::{HeaderMap, HeaderValue, AUTHORIZATION, WWW_AUTHENTICATE},
+    Request, Url,
+};
+
+use crate::error::{AirscanError, Result};
+
+const USERNAME_VAR: &str = "AIRSCAN_USERNAME";
+const PASSWORD_VAR: &str = "AIRSCAN_PASSWORD";
+
+/// Username and password for a scanner. The password is left out of `Debug` output.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl Credentials {
+    pub fn new(username: impl Into<String>, password: impl Into<String>) -> Self {
+        Credentials {
+            username: username.into(),
+            password: password.into(),
+        }
+    }
+
+    /// From `AIRSCAN_USERNAME` and `AIRSCAN_PASSWORD`, `None` without a username.
+    pub fn from_env() -> Option<Self> {
+        let username = std::env::var(USERNAME_VAR).ok()?;
+        let password = std::env::var(PASSWORD_VAR).unwrap_or_default();
+        Some(Credentials::new(username, password))
+    }
+
+    /// Login for the host of the scanner at `url` from a netrc file, or its `default`
+    /// entry if no `machine` matches. `macdef` bodies are skipped, malformed entries are errors.
+    pub fn from_netrc(path: impl AsRef<Path>, url: &str) -> Result<Option<Self>> {
+        let path = path.as_ref();
+        let url = Url::parse(url)?;
+        let host = url
+            .host_str()
+            .ok_or_else(|| AirscanError::InvalidValue(format!("No host in {}", url)))?;
+        netrc_entry(&std::fs::read_to_string(path)?, host)
+            .map_err(|reason| AirscanError::InvalidValue(format!("{}: {}", path.display(), reason)))
+    }
+
+    /// `$NETRC`, or `~/.netrc`.
+    pub fn netrc_path() -> Option<PathBuf> {
+        std::env::var_os("NETRC")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".netrc")))
+    }
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .finish_non_exhaustive()
+    }
+}
+
+fn netrc_entry(content: &str, host: &str) -> std::result::Result<Option<Credentials>, String> {
+    let mut words = Vec::new();
+    let mut lines = content.lines();
+    while let Some(line) = lines.next() {
+        for word in line.split_whitespace() {
+            if word == "macdef" {
+                // The macro name ends the line, its body runs up to the next blank line.
+                lines.by_ref().find(|line| line.trim().is_empty());
+                break;
+            }
+            words.push(word);
+        }
+    }
+
+    // (machine, login, password), `None` for the default entry.
+    let mut entries: Vec<(Option<&str>, &str, &str)> = Vec::new();
+    let mut tokens = words.into_iter();
+    while let Some(token) = tokens.next() {
+        let mut value = || {
+            tokens
+                .next()
+                .ok_or_else(|| format!("{} without a value", token))
+        };
+        match token {
+            "machine" => entries.push((Some(value()?), "", "")),
+            "default" => entries.push((None, "", "")),
+            "login" | "password" | "account" => {
+                let value = value()?;
+                let entry = entries
+                    .last_mut()
+                    .ok_or_else(|| format!("{} outside of a machine entry", token))?;
+                match token {
+                    "login" => entry.1 = value,
+                    "password" => entry.2 = value,
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(entries
+        .iter()
+        .find(|(machine, ..)| machine.is_some_and(|machine| machine.eq_ignore_ascii_case(host)))
+        .or_else(|| entries.iter().find(|(machine, ..)| machine.is_none()))
+        .map(|(_, login, password)| Credentials::new(*login, *password)))
+}
+
+/// Scheme the scanner asked for in its last `WWW-Authenticate` challenge.
+#[derive(Debug)]
+enum Challenge {
+    Basic,
+    Digest(WwwAuthenticateHeader),
+}
+
+/// Answers authentication challenges of one scanner, shared by clones of its client.
+#[derive(Debug)]
+pub(crate) struct Authenticator {
+    credentials: Credentials,
+    challenge: Mutex<Option<Challenge>>,
+}
+
+impl Authenticator {
+    pub(crate) fn new(credentials: Credentials) -> Self {
+        Authenticator {
+            credentials,
+            challenge: Mutex::new(None),
+        }
+    }
+
+    /// Adds an `Authorization` header answering the last challenge, nothing before the first.
+    pub(crate) fn authorize(&self, request: &mut Request) -> Result<()> {
+        let mut challenge = self.challenge.lock().unwrap();
+        let authorization = match challenge.as_mut() {
+            None => return Ok(()),
+            Some(Challenge::Basic) => {
+                let Credentials { username, password } = &self.credentials;
+                format!(
+                    "Basic {}",
+                    STANDARD.encode(format!("{}:{}", username, password))
+                )
+            }
+            Some(Challenge::Digest(prompt)) => {
+                let url = request.url();
+                let uri = match url.query() {
+                    Some(query) => format!("{}?{}", url.path(), query),
+                    None => url.path().to_string(),
+                };
+                let context = AuthContext::new_with_method(
+                    self.credentials.username.as_str(),
+                    self.credentials.password.as_str(),
+                    uri,
+                    request.body().and_then(|body| body.as_bytes()),
+                    HttpMethod(Cow::Borrowed(request.method().as_str())),
+                );
+                prompt
+                    .respond(&context)
+                    .map_err(|error| {
+                        AirscanError::InvalidResponse(format!("Digest challenge: {}", error))
+                    })?
+                    .to_header_string()
+            }
+        };
+        let value = HeaderValue::from_str(&authorization).map_err(|_| {
+            AirscanError::InvalidValue(String::from("Username contains invalid characters"))
+        })?;
+        request.headers_mut().insert(AUTHORIZATION, value);
+        Ok(())
+    }
+
+    /// Remembers the challenge of a `401` response, preferring Digest over Basic.
+    /// Returns `false` if the scanner offers neither.
+    pub(crate) fn challenge(&self, headers: &HeaderMap) -> Result<bool> {
+        let challenges: Vec<&str> = headers
+            .get_all(WWW_AUTHENTICATE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .collect();
+        let scheme = |name: &str| {
+            challenges.iter().copied().find(|challenge| {
+                challenge
+                    .split_whitespace()
+                    .next()
+                    .is_some_and(|scheme| scheme.eq_ignore_ascii_case(name))
+            })
+        };
+
+        let challenge = if let Some(digest) = scheme("Digest") {
+            let (_, parameters) = digest.trim_start().split_at("Digest".len());
+            let prompt = digest_auth::parse(parameters).map_err(|error| {
+                AirscanError::InvalidResponse(format!("Digest challenge: {}", error))
+            })?;
+            Challenge::Digest(prompt)
+        } else if scheme("Basic").is_some() {
+            Challenge::Basic
+        } else {
+            return Ok(false);
+        };
+        *self.challenge.lock().unwrap() = Some(challenge);
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mockito::{Matcher, Server};
+
+    use super::*;
+    use crate::client::ScannerClient;
+
+    const STATUS: &str = include_str!("../testdata/status/brother_idle_jobs.xml");
+    const DIGEST_CHALLENGE: &str =
+        r#"Digest realm="eSCL", qop="auth", algorithm=MD5, nonce="0a4f113b", opaque="5ccc069c""#;
+
+    fn client(server: &Server, credentials: Option<Credentials>) -> ScannerClient {
+        let builder = ScannerClient::builder(&format!("{}/eSCL", server.url()));
+        match credentials {
+            Some(credentials) => builder.credentials(credentials),
+            None => builder,
+        }
+        .build()
+        .unwrap()
+    }
+
+    /// Whether `request` answers [`DIGEST_CHALLENGE`] with the password `secret`.
+    fn valid_digest(request: &mockito::Request) -> bool {
+        let Some(header) = request.header("authorization").first().copied() else {
+            return false;
+        };
+        let Ok(authorization) =
+            digest_auth::AuthorizationHeader::parse(header.to_str().unwrap_or_default())
+        else {
+            return false;
+        };
+        let mut prompt = digest_auth::parse(&DIGEST_CHALLENGE["Digest".len()..]).unwrap();
+        prompt.nc = authorization.nc - 1;
+        let mut context = AuthContext::new_with_method(
+            authorization.username.as_str(),
+            "secret",
+            request.path_and_query(),
+            Option::<&[u8]>::None,
+            HttpMethod(Cow::Borrowed(request.method())),
+        );
+        context.set_custom_cnonce(authorization.cnonce.clone().unwrap_or_default());
+        prompt.respond(&context).unwrap().response == authorization.response
+    }
+
+    #[tokio::test]
+    async fn basic_authentication() {
+        let mut server = Server::new_async().await;
+        let _challenge = server
+            .mock("GET", "/eSCL/ScannerStatus")
+            .match_header("authorization", Matcher::Missing)
+            .with_status(401)
+            .with_header("www-authenticate", r#"Basic realm="eSCL""#)
+            .create_async()
+            .await;
+        // scan:secret
+        let _status = server
+            .mock("GET", "/eSCL/ScannerStatus")
+            .match_header("authorization", "Basic c2NhbjpzZWNyZXQ=")
+            .with_body(STATUS)
+            .expect(2)
+            .create_async()
+            .await;
+
+        let client = client(&server, Some(Credentials::new("scan", "secret")));
+        client.status().await.unwrap();
+        // Sent right away once the scanner asked for it.
+        client.clone().status().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn digest_authentication() {
+        let mut server = Server::new_async().await;
+        let _challenge = server
+            .mock("GET", "/eSCL/ScannerStatus")
+            .match_request(|request| !valid_digest(request))
+            .with_status(401)
+            .with_header("www-authenticate", r#"Basic realm="eSCL""#)
+            .with_header("www-authenticate", DIGEST_CHALLENGE)
+            .create_async()
+            .await;
+        let _status = server
+            .mock("GET", "/eSCL/ScannerStatus")
+            .match_request(valid_digest)
+            .with_body(STATUS)
+            .create_async()
+            .await;
+
+        client(&server, Some(Credentials::new("scan", "secret")))
+            .status()
+            .await
+            .unwrap();
+        let result = client(&server, Some(Credentials::new("scan", "wrong")))
+            .status()
+            .await;
+
+        assert!(matches!(
+            result,
+            Err(AirscanError::AuthenticationFailed { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn missing_credentials() {
+        let mut server = Server::new_async().await;
+        let _challenge = server
+            .mock("GET", "/eSCL/ScannerStatus")
+            .with_status(401)
+            .with_header("www-authenticate", r#"Negotiate"#)
+            .create_async()
+            .await;
+
+        let result = client(&server, None).status().await;
+        assert!(matches!(
+            result,
+            Err(AirscanError::AuthenticationRequired { .. })
+        ));
+        // Credentials do not help with an unsupported scheme.
+        let result = client(&server, Some(Credentials::new("scan", "secret")))
+            .status()
+            .await;
+        assert!(matches!(
+            result,
+            Err(AirscanError::AuthenticationFailed { .. })
+        ));
+    }
+
+    #[test]
+    fn parse_netrc() {
+        let netrc = "machine 192.168.1.20 login scan password secret\n\
+                     machine mfp.example.com\n  login admin\n  password 'pa55'\n\
+                     default login guest password guest\n";
+
+        assert_eq!(
+            netrc_entry(netrc, "192.168.1.20"),
+            Ok(Some(Credentials::new("scan", "secret")))
+        );
+        assert_eq!(
+            netrc_entry(netrc, "MFP.example.com"),
+            Ok(Some(Credentials::new("admin", "'pa55'")))
+        );
+        assert_eq!(
+            netrc_entry(netrc, "other"),
+            Ok(Some(Credentials::new("guest", "guest")))
+        );
+        assert_eq!(netrc_entry("machine a login b", "other"), Ok(None));
+        assert!(!format!("{:?}", Credentials::new("scan", "secret")).contains("secret"));
+    }
+
+    #[test]
+    fn skip_netrc_macros() {
+        let netrc = "macdef init\n\
+                     machine scanner login macro password body\n\
+                     \n\
+                     machine scanner account office login scan password secret\n";
+
+        assert_eq!(
+            netrc_entry(netrc, "scanner"),
+            Ok(Some(Credentials::new("scan", "secret")))
+        );
+    }
+
+    #[test]
+    fn reject_malformed_netrc() {
+        for netrc in [
+            "machine",
+            "machine scanner login",
+            "login scan password secret",
+        ] {
+            assert!(netrc_entry(netrc, "scanner").is_err(), "{}", netrc);
+        }
+
+        let path = std::env::temp_dir().join(format!("airscan-netrc-{}", std::process::id()));
+        std::fs::write(&path, "machine scanner password").unwrap();
+        let result = Credentials::from_netrc(&path, "http://scanner/eSCL");
+        assert!(matches!(result, Err(AirscanError::InvalidValue(_))));
+        std::fs::remove_file(path).unwrap();
+    }
+}
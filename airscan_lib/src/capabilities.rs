@@ -0,0 +1,483 @@
+use serde::{Deserialize, Deserializer};
+
+use crate::{client::ScannerClient, error::Result, settings::InputSource, version::EsclVersion};
+
+/// Parsed `ScannerCapabilities` document of an eSCL scanner.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ScannerCapabilities {
+    pub version: String,
+    pub make_and_model: Option<String>,
+    pub serial_number: Option<String>,
+    #[serde(rename = "UUID")]
+    pub uuid: Option<String>,
+    pub platen: Option<Platen>,
+    pub adf: Option<Adf>,
+    #[serde(default)]
+    pub blank_page_detection: bool,
+    #[serde(default)]
+    pub blank_page_detection_and_removal: bool,
+    pub brightness_support: Option<Range>,
+    pub contrast_support: Option<Range>,
+    pub gamma_support: Option<Range>,
+    pub highlight_support: Option<Range>,
+    pub shadow_support: Option<Range>,
+    pub sharpen_support: Option<Range>,
+    pub threshold_support: Option<Range>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Platen {
+    pub platen_input_caps: Option<InputCaps>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Adf {
+    pub adf_simplex_input_caps: Option<InputCaps>,
+    pub adf_duplex_input_caps: Option<InputCaps>,
+    pub feeder_capacity: Option<u32>,
+    #[serde(default, deserialize_with = "list")]
+    pub adf_options: Vec<String>,
+}
+
+/// Capabilities of a single input source. Sizes are in 1/300 inch.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct InputCaps {
+    pub min_width: u32,
+    pub max_width: u32,
+    pub min_height: u32,
+    pub max_height: u32,
+    pub max_scan_regions: Option<u32>,
+    #[serde(default, deserialize_with = "list")]
+    pub setting_profiles: Vec<SettingProfile>,
+    #[serde(default, deserialize_with = "list")]
+    pub supported_intents: Vec<String>,
+    pub max_optical_x_resolution: Option<u32>,
+    pub max_optical_y_resolution: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct SettingProfile {
+    #[serde(default, deserialize_with = "list")]
+    pub color_modes: Vec<String>,
+    #[serde(default)]
+    pub document_formats: DocumentFormats,
+    #[serde(default)]
+    pub supported_resolutions: SupportedResolutions,
+}
+
+/// Formats of a setting profile, split into `pwg:DocumentFormat` and
+/// `scan:DocumentFormatExt` entries.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(from = "List<DocumentFormatEntry>")]
+pub struct DocumentFormats {
+    pub document_format: Vec<String>,
+    pub document_format_ext: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct SupportedResolutions {
+    #[serde(default, deserialize_with = "list")]
+    pub discrete_resolutions: Vec<DiscreteResolution>,
+    pub resolution_range: Option<ResolutionRange>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct DiscreteResolution {
+    pub x_resolution: u32,
+    pub y_resolution: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ResolutionRange {
+    pub x_resolution_range: Range,
+    pub y_resolution_range: Range,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Range {
+    pub min: i32,
+    pub max: i32,
+    pub normal: Option<i32>,
+    pub step: Option<i32>,
+}
+
+impl Range {
+    pub fn contains(&self, value: i32) -> bool {
+        if value < self.min || value > self.max {
+            return false;
+        }
+        match self.step {
+            Some(step) if step > 1 => (value - self.min) % step == 0,
+            _ => true,
+        }
+    }
+}
+
+impl ScannerCapabilities {
+    pub fn from_xml(xml: &str) -> Result<Self> {
+        Ok(serde_xml_rs::from_str(xml)?)
+    }
+
+    pub fn platen_caps(&self) -> Option<&InputCaps> {
+        self.platen.as_ref()?.platen_input_caps.as_ref()
+    }
+
+    pub fn adf_simplex_caps(&self) -> Option<&InputCaps> {
+        self.adf.as_ref()?.adf_simplex_input_caps.as_ref()
+    }
+
+    pub fn adf_duplex_caps(&self) -> Option<&InputCaps> {
+        self.adf.as_ref()?.adf_duplex_input_caps.as_ref()
+    }
+
+    /// Capabilities of the given source; the duplex caps for a duplex feeder scan.
+    pub fn input_caps(&self, source: InputSource, duplex: bool) -> Option<&InputCaps> {
+        match (source, duplex) {
+            (InputSource::Platen, _) => self.platen_caps(),
+            (InputSource::Feeder, false) => self.adf_simplex_caps(),
+            (InputSource::Feeder, true) => self.adf_duplex_caps(),
+            (InputSource::Camera, _) => None,
+        }
+    }
+
+    /// Parsed [`ScannerCapabilities::version`], `None` if the scanner sends an invalid one.
+    pub fn escl_version(&self) -> Option<EsclVersion> {
+        self.version.parse().ok()
+    }
+
+    pub fn supports_duplex(&self) -> bool {
+        self.adf_duplex_caps().is_some()
+    }
+
+    pub fn feeder_capacity(&self) -> Option<u32> {
+        self.adf.as_ref()?.feeder_capacity
+    }
+
+    /// Whether the scanner can drop blank pages itself, see [`ScanSettings::blank_page_removal`].
+    ///
+    /// [`ScanSettings::blank_page_removal`]: crate::ScanSettings::blank_page_removal
+    pub fn supports_blank_page_removal(&self) -> bool {
+        self.blank_page_detection_and_removal
+    }
+
+    /// Range of an image adjustment by its element name, e.g. `Brightness`.
+    pub(crate) fn adjustment_range(&self, name: &str) -> Option<&Range> {
+        match name {
+            "Brightness" => self.brightness_support.as_ref(),
+            "Contrast" => self.contrast_support.as_ref(),
+            "Gamma" => self.gamma_support.as_ref(),
+            "Highlight" => self.highlight_support.as_ref(),
+            "Shadow" => self.shadow_support.as_ref(),
+            "Sharpen" => self.sharpen_support.as_ref(),
+            "Threshold" => self.threshold_support.as_ref(),
+            _ => None,
+        }
+    }
+}
+
+impl InputCaps {
+    /// All discrete resolutions of all profiles, sorted and deduplicated.
+    pub fn discrete_resolutions(&self) -> Vec<u32> {
+        let mut resolutions: Vec<u32> = self
+            .setting_profiles
+            .iter()
+            .flat_map(|profile| &profile.supported_resolutions.discrete_resolutions)
+            .map(|resolution| resolution.x_resolution)
+            .collect();
+        resolutions.sort_unstable();
+        resolutions.dedup();
+        resolutions
+    }
+
+    pub fn supports_resolution(&self, resolution: u32) -> bool {
+        self.setting_profiles.iter().any(|profile| {
+            let supported = &profile.supported_resolutions;
+            supported
+                .discrete_resolutions
+                .iter()
+                .any(|r| r.x_resolution == resolution && r.y_resolution == resolution)
+                || supported.resolution_range.is_some_and(|range| {
+                    range.x_resolution_range.contains(resolution as i32)
+                        && range.y_resolution_range.contains(resolution as i32)
+                })
+        })
+    }
+
+    pub fn color_modes(&self) -> Vec<&str> {
+        collect_unique(
+            self.setting_profiles
+                .iter()
+                .flat_map(|profile| &profile.color_modes),
+        )
+    }
+
+    /// Both `DocumentFormat` and `DocumentFormatExt` entries of all profiles.
+    pub fn document_formats(&self) -> Vec<&str> {
+        collect_unique(self.setting_profiles.iter().flat_map(|profile| {
+            profile
+                .document_formats
+                .document_format
+                .iter()
+                .chain(&profile.document_formats.document_format_ext)
+        }))
+    }
+}
+
+fn collect_unique<'a>(values: impl Iterator<Item = &'a String>) -> Vec<&'a str> {
+    let mut unique: Vec<&str> = Vec::new();
+    for value in values {
+        if !unique.contains(&value.as_str()) {
+            unique.push(value);
+        }
+    }
+    unique
+}
+
+pub async fn get_capabilities(url: &str) -> Result<ScannerCapabilities> {
+    ScannerClient::new(url)?.capabilities().await
+}
+
+/// Wrapper element whose children are all items of the same list,
+/// e.g. `<scan:ColorModes><scan:ColorMode>..</scan:ColorMode></scan:ColorModes>`.
+#[derive(Deserialize)]
+#[serde(bound(deserialize = "T: Deserialize<'de>"))]
+pub(crate) struct List<T> {
+    #[serde(rename = "$value", default)]
+    items: Vec<T>,
+}
+
+pub(crate) fn list<'de, D, T>(deserializer: D) -> std::result::Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    List::deserialize(deserializer).map(|list| list.items)
+}
+
+#[derive(Deserialize)]
+enum DocumentFormatEntry {
+    DocumentFormat(String),
+    DocumentFormatExt(String),
+}
+
+impl From<List<DocumentFormatEntry>> for DocumentFormats {
+    fn from(list: List<DocumentFormatEntry>) -> Self {
+        let mut formats = DocumentFormats::default();
+        for entry in list.items {
+            match entry {
+                DocumentFormatEntry::DocumentFormat(format) => formats.document_format.push(format),
+                DocumentFormatEntry::DocumentFormatExt(format) => {
+                    formats.document_format_ext.push(format)
+                }
+            }
+        }
+        formats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::AirscanError;
+
+    const BROTHER: &str = include_str!("../testdata/capabilities/brother_mfc_l2710dw.xml");
+    const HP: &str = include_str!("../testdata/capabilities/hp_color_laserjet_m479fdw.xml");
+    const CANON: &str = include_str!("../testdata/capabilities/canon_imageclass_mf644cdw.xml");
+    const KYOCERA: &str = include_str!("../testdata/capabilities/kyocera_ecosys_m2540dn.xml");
+
+    #[test]
+    fn parse_brother() {
+        let caps = ScannerCapabilities::from_xml(BROTHER).unwrap();
+
+        assert_eq!(caps.version, "2.62");
+        assert_eq!(
+            caps.make_and_model.as_deref(),
+            Some("Brother MFC-L2710DW series")
+        );
+        let platen = caps.platen_caps().unwrap();
+        assert_eq!(platen.max_width, 2550);
+        assert_eq!(platen.max_height, 3508);
+        assert_eq!(platen.discrete_resolutions(), vec![100, 150, 200, 300, 600]);
+        assert_eq!(
+            platen.color_modes(),
+            vec!["BlackAndWhite1", "Grayscale8", "RGB24"]
+        );
+        assert_eq!(
+            platen.document_formats(),
+            vec!["application/pdf", "image/jpeg"]
+        );
+
+        let adf = caps.adf_simplex_caps().unwrap();
+        assert_eq!(adf.max_height, 4200);
+        assert!(!adf.supports_resolution(600));
+        assert!(caps.adf_duplex_caps().is_none());
+        assert!(!caps.supports_duplex());
+        assert_eq!(caps.feeder_capacity(), Some(50));
+    }
+
+    #[test]
+    fn parse_adjustment_ranges() {
+        let hp = ScannerCapabilities::from_xml(HP).unwrap();
+        let brother = ScannerCapabilities::from_xml(BROTHER).unwrap();
+
+        assert_eq!(
+            hp.sharpen_support,
+            Some(Range {
+                min: 0,
+                max: 4,
+                normal: Some(2),
+                step: Some(1),
+            })
+        );
+        assert_eq!(hp.adjustment_range("Threshold").unwrap().max, 255);
+        assert!(hp.gamma_support.is_none());
+        assert_eq!(brother.adjustment_range("Contrast").unwrap().min, -50);
+        assert!(brother.threshold_support.is_none());
+        assert!(ScannerCapabilities::from_xml(CANON)
+            .unwrap()
+            .brightness_support
+            .is_none());
+    }
+
+    #[test]
+    fn parse_hp_with_duplex_and_format_ext() {
+        let caps = ScannerCapabilities::from_xml(HP).unwrap();
+
+        assert_eq!(caps.version, "2.63");
+        let profile = &caps.platen_caps().unwrap().setting_profiles[0];
+        assert_eq!(
+            profile.document_formats.document_format,
+            vec!["image/jpeg", "application/pdf"]
+        );
+        assert_eq!(
+            profile.document_formats.document_format_ext,
+            vec!["image/jpeg", "application/pdf"]
+        );
+        assert!(caps.platen_caps().unwrap().supports_resolution(1200));
+
+        assert!(caps.supports_duplex());
+        let duplex = caps.adf_duplex_caps().unwrap();
+        assert_eq!(duplex.min_width, 8);
+        assert_eq!(duplex.discrete_resolutions(), vec![75, 200, 300]);
+        assert_eq!(
+            caps.adf.as_ref().unwrap().adf_options,
+            vec!["DetectPaperLoaded", "SelectSinglePage", "Duplex"]
+        );
+        assert!(caps.blank_page_detection);
+        assert!(caps.supports_blank_page_removal());
+        assert!(!ScannerCapabilities::from_xml(BROTHER)
+            .unwrap()
+            .supports_blank_page_removal());
+    }
+
+    #[test]
+    fn parse_canon_single_line() {
+        let caps = ScannerCapabilities::from_xml(CANON).unwrap();
+
+        assert_eq!(caps.version, "2.6");
+        assert!(caps.serial_number.is_none());
+        let platen = caps.platen_caps().unwrap();
+        assert_eq!(platen.min_width, 300);
+        assert_eq!(platen.color_modes(), vec!["Grayscale8", "RGB24"]);
+        assert_eq!(
+            platen.supported_intents,
+            vec!["Document", "TextAndGraphic", "Photo", "Preview"]
+        );
+        assert!(!caps
+            .adf_simplex_caps()
+            .unwrap()
+            .supported_intents
+            .contains(&String::from("Preview")));
+        assert!(platen
+            .document_formats()
+            .contains(&"application/octet-stream"));
+        assert_eq!(
+            caps.adf_duplex_caps().unwrap().discrete_resolutions(),
+            vec![150, 300]
+        );
+    }
+
+    #[test]
+    fn parse_kyocera_resolution_range() {
+        let caps = ScannerCapabilities::from_xml(KYOCERA).unwrap();
+
+        assert_eq!(caps.version, "2.0");
+        let platen = caps.platen_caps().unwrap();
+        let range = platen.setting_profiles[0]
+            .supported_resolutions
+            .resolution_range
+            .unwrap();
+        assert_eq!(range.x_resolution_range.min, 75);
+        assert_eq!(range.x_resolution_range.normal, Some(300));
+        assert!(platen.discrete_resolutions().is_empty());
+        assert!(platen.supports_resolution(250));
+        assert!(!platen.supports_resolution(1200));
+        assert_eq!(caps.feeder_capacity(), Some(75));
+    }
+
+    #[test]
+    fn range_respects_step() {
+        let range = Range {
+            min: 100,
+            max: 600,
+            normal: None,
+            step: Some(100),
+        };
+        assert!(range.contains(300));
+        assert!(!range.contains(250));
+        assert!(!range.contains(700));
+    }
+
+    #[test]
+    fn parse_invalid_xml() {
+        assert!(matches!(
+            ScannerCapabilities::from_xml("<scan:ScannerCapabilities>"),
+            Err(AirscanError::Xml(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_get_capabilities_success() {
+        let mut server = mockito::Server::new_async().await;
+        let _m = server
+            .mock("GET", "/ScannerCapabilities")
+            .with_status(200)
+            .with_header("content-type", "text/xml")
+            .with_body(BROTHER)
+            .create_async()
+            .await;
+
+        let caps = get_capabilities(server.url().as_str()).await.unwrap();
+
+        assert_eq!(
+            caps.uuid.as_deref(),
+            Some("e3248000-80ce-11db-8000-3c2af490c39a")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_capabilities_error() {
+        let mut server = mockito::Server::new_async().await;
+        let _m = server
+            .mock("GET", "/ScannerCapabilities")
+            .with_status(500)
+            .create_async()
+            .await;
+
+        let result = get_capabilities(server.url().as_str()).await;
+
+        assert!(matches!(
+            result,
+            Err(AirscanError::Http { status, .. }) if status == 500
+        ));
+    }
+}
@@ -0,0 +1,1136 @@
+use std::{sync::Arc, time::Duration};
+
+use bytes::Bytes;
+use image::DynamicImage;
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE},
+    Certificate, Client, ClientBuilder, Proxy, RequestBuilder, Response, StatusCode, Url,
+};
+use tokio::{io::AsyncWriteExt, time::sleep};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    auth::{Authenticator, Credentials},
+    capabilities::{InputCaps, ScannerCapabilities},
+    document::{Document, Progress, ProgressCallback},
+    error::{AirscanError, Result},
+    filename::{create_unique, FilenameContext, FilenameTemplate},
+    postprocess::{self, PageTransform},
+    retry::{retry_after, Retry, RetryPolicy},
+    settings::{ColorMode, InputSource, Intent, ScanSettings},
+    sink::{extension_for, PageMetadata, PageSink},
+    status::{scanner_url_for_job, JobInfo, JobState, ScannerStatus},
+    tls::{self, PinCallback, TlsVerification},
+};
+
+const DEFAULT_USER_AGENT: &str = concat!("airscan-rust/", env!("CARGO_PKG_VERSION"));
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(120);
+/// Resolution of [`ScannerClient::preview`] scans if the scanner offers it, else its lowest.
+const PREVIEW_RESOLUTION: u32 = 75;
+
+#[derive(Debug, Clone, Default)]
+pub struct FetchOptions {
+    /// Rotate every second page by 180°, for duplex feeders that deliver
+    /// back sides upside down. Only applies to JPEG and PNG pages.
+    pub rotate_back_side: bool,
+    /// Deletes the job on the scanner and stops fetching once cancelled.
+    pub cancel: CancellationToken,
+    /// Reports the download progress of each page.
+    pub progress: Option<ProgressCallback>,
+    /// Drops JPEG and PNG pages whose [`postprocess::ink_coverage`] is below this value,
+    /// e.g. [`postprocess::DEFAULT_BLANK_THRESHOLD`].
+    pub blank_page_threshold: Option<f64>,
+    /// Straightens skewed JPEG and PNG pages, see [`postprocess::deskew`].
+    pub deskew: bool,
+    /// Crops JPEG and PNG pages to the paper, see [`postprocess::crop_to_page`].
+    pub crop: bool,
+    /// Format requested in the scan settings, e.g. `image/jpeg`. Names and describes pages
+    /// the scanner sends without a known `Content-Type`.
+    pub document_format: Option<String>,
+}
+
+impl FetchOptions {
+    fn report_progress(&self, page: u32, progress: Progress) {
+        if let Some(callback) = &self.progress {
+            callback.call(page, progress);
+        }
+    }
+
+    /// Whether scanned page `page` has to be read into memory for [`FetchOptions::process_page`].
+    fn buffers_page(&self, page: u32) -> bool {
+        self.blank_page_threshold.is_some() || !self.transform(page).is_identity()
+    }
+
+    /// Content type of `document`, the requested format if the scanner sends none.
+    fn content_type(&self, document: &Document) -> Option<String> {
+        document
+            .content_type()
+            .or(self.document_format.as_deref())
+            .map(str::to_string)
+    }
+
+    /// File extension for a page of `content_type`, falling back to the requested format.
+    fn extension(&self, content_type: Option<&str>) -> &'static str {
+        content_type
+            .and_then(extension_for)
+            .or_else(|| self.document_format.as_deref().and_then(extension_for))
+            .unwrap_or("bin")
+    }
+
+    fn transform(&self, page: u32) -> PageTransform {
+        PageTransform {
+            rotate_180: self.rotate_back_side && page.is_multiple_of(2),
+            deskew: self.deskew,
+            crop: self.crop,
+        }
+    }
+
+    /// Drops blank pages, then rotates back sides, deskews and crops. Returns `None` for a
+    /// dropped page.
+    fn process_page(&self, page: u32, content: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if !postprocess::is_image(&content) {
+            return Ok(Some(content));
+        }
+        if let Some(threshold) = self.blank_page_threshold {
+            if postprocess::is_blank(&content, threshold)? {
+                log::info!("Dropping blank page {}", page);
+                return Ok(None);
+            }
+        }
+        let transform = self.transform(page);
+        if transform.is_identity() {
+            return Ok(Some(content));
+        }
+        Ok(Some(transform.apply(&content)?))
+    }
+}
+
+/// What happened to the next page of a job.
+enum NextPage {
+    Saved,
+    Dropped,
+    Done,
+}
+
+/// HTTP client for a single eSCL scanner, e.g. `http://192.168.2.38/eSCL`.
+#[derive(Debug, Clone)]
+pub struct ScannerClient {
+    base_url: String,
+    client: Client,
+    retry_policy: RetryPolicy,
+    auth: Option<Arc<Authenticator>>,
+}
+
+impl ScannerClient {
+    pub fn new(url: &str) -> Result<Self> {
+        ScannerClient::builder(url).build()
+    }
+
+    pub fn builder(url: &str) -> ScannerClientBuilder {
+        ScannerClientBuilder::new(url)
+    }
+
+    /// Uses a preconfigured reqwest client, e.g. with a custom TLS setup.
+    pub fn with_client(url: &str, client: Client) -> Self {
+        ScannerClient {
+            base_url: url.trim_end_matches('/').to_string(),
+            client,
+            retry_policy: RetryPolicy::default(),
+            auth: None,
+        }
+    }
+
+    /// Client with default settings for the scanner that owns the job at `location`.
+    pub fn for_job(location: &Url) -> Result<Self> {
+        let url = scanner_url_for_job(location).ok_or_else(|| {
+            AirscanError::InvalidValue(format!("Not a scan job location: {}", location))
+        })?;
+        ScannerClient::new(&url)
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry_policy
+    }
+
+    pub async fn capabilities(&self) -> Result<ScannerCapabilities> {
+        ScannerCapabilities::from_xml(&self.get_xml("ScannerCapabilities").await?)
+    }
+
+    pub async fn status(&self) -> Result<ScannerStatus> {
+        ScannerStatus::from_xml(&self.get_xml("ScannerStatus").await?)
+    }
+
+    /// Posts the scan settings and returns the job location.
+    /// Fails right away if the scanner cannot be reached or rejects the capabilities request,
+    /// but submits unvalidated settings if it has no readable capabilities.
+    pub async fn submit_job(&self, settings: &ScanSettings) -> Result<Url> {
+        let mut settings = settings.clone();
+        match self.capabilities().await {
+            Ok(capabilities) => {
+                for element in settings.negotiate_version(&capabilities) {
+                    log::warn!(
+                        "Leaving out {}, unknown to eSCL {}",
+                        element,
+                        settings.version.unwrap_or_default()
+                    );
+                }
+                settings.clamp_regions(&capabilities);
+                settings.validate(&capabilities)?;
+            }
+            // Scanners without usable capabilities still get the job, unvalidated.
+            Err(AirscanError::Http {
+                status: StatusCode::NOT_FOUND | StatusCode::NOT_IMPLEMENTED,
+                ..
+            }) => {
+                log::debug!("No capabilities, submitting unvalidated settings");
+            }
+            Err(error @ (AirscanError::Xml(_) | AirscanError::InvalidResponse(_))) => {
+                log::debug!(
+                    "Unreadable capabilities, submitting unvalidated settings: {}",
+                    error
+                );
+            }
+            Err(error) => return Err(error),
+        }
+
+        let post_url = format!("{}/ScanJobs", self.base_url);
+        log::debug!("Send request to {}", post_url);
+
+        let request = settings.to_xml()?;
+        log::debug!("{}", request);
+
+        let mut retry = Retry::new(self.retry_policy);
+
+        loop {
+            match send_post(self, &post_url, &request).await {
+                Ok(ScannerResponse::Success(url)) => return Ok(url),
+                Ok(ScannerResponse::Busy(retry_after)) => {
+                    if let Ok(status) = self.status().await {
+                        check_adf_state(&status, settings.input_source)?;
+                    }
+                    wait_while_busy(&mut retry, retry_after).await?;
+                }
+                Err(AirscanError::Transport(error)) => {
+                    wait_after_error(&mut retry, error).await?;
+                }
+                Err(e) => return Err(e),
+            };
+        }
+    }
+
+    /// Fetches the next page of a job into memory, waiting while the scanner is busy.
+    /// Returns `None` once the job has no more pages.
+    pub async fn next_document(&self, location: &Url) -> Result<Option<Vec<u8>>> {
+        match self.open_next_document(location).await? {
+            Some(document) => Ok(Some(document.bytes().await?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Like [`ScannerClient::next_document`], but returns as soon as the scanner starts
+    /// sending the page, so its body can be streamed.
+    pub async fn open_next_document(&self, location: &Url) -> Result<Option<Document>> {
+        let next_document = location.join("NextDocument")?;
+        let mut retry = Retry::new(self.retry_policy);
+
+        loop {
+            let response = match self.send(self.client.get(next_document.clone())).await {
+                Ok(response) => response,
+                Err(AirscanError::Transport(error)) => {
+                    wait_after_error(&mut retry, error).await?;
+                    continue;
+                }
+                Err(error) => return Err(error),
+            };
+
+            log::debug!("{} {}", next_document, response.status());
+            match response.status() {
+                status if status.is_success() => return Ok(Some(Document::new(response))),
+                StatusCode::NOT_FOUND => return Ok(None),
+                StatusCode::SERVICE_UNAVAILABLE => {
+                    let retry_after = retry_after(response.headers());
+                    match self.job_info(location).await {
+                        Some(job)
+                            if matches!(job.job_state, JobState::Canceled | JobState::Aborted) =>
+                        {
+                            return Err(AirscanError::JobFailed {
+                                state: job.job_state,
+                                reasons: job.job_state_reasons,
+                            });
+                        }
+                        Some(job)
+                            if job.job_state.is_final() && job.images_to_transfer == Some(0) =>
+                        {
+                            return Ok(None);
+                        }
+                        _ => wait_while_busy(&mut retry, retry_after).await?,
+                    }
+                }
+                _ => return Err(AirscanError::from_response(response).await),
+            }
+        }
+    }
+
+    /// Writes the pages of a job to `outfile`, see [`ScannerClient::fetch_to_files`].
+    pub async fn fetch_result(
+        &self,
+        location: &Url,
+        outfile: &str,
+        multi: bool,
+        options: &FetchOptions,
+    ) -> Result<()> {
+        // Plain file names keep their braces.
+        let template = match outfile.parse::<FilenameTemplate>() {
+            Ok(template) if template.has_placeholders() => template,
+            _ => FilenameTemplate::literal(outfile),
+        };
+        self.fetch_to_files(location, &template, multi, options)
+            .await
+    }
+
+    /// Writes the pages of a job to files named by `template`. Without a `{page}` placeholder,
+    /// pages are numbered `name-1.ext`, `name-2.ext`, ... when `multi` is set. Existing files
+    /// are not overwritten. Returns [`AirscanError::Cancelled`] after cancelling the job
+    /// when `options.cancel` fires.
+    pub async fn fetch_to_files(
+        &self,
+        location: &Url,
+        template: &FilenameTemplate,
+        multi: bool,
+        options: &FetchOptions,
+    ) -> Result<()> {
+        log::debug!("Fetching pages of {}", location);
+        let mut context = FilenameContext::for_job(location);
+        let mut scanned = 0;
+
+        loop {
+            scanned += 1;
+            let next = tokio::select! {
+                biased;
+                _ = options.cancel.cancelled() => return Err(self.abort_job(location).await),
+                next = self.save_next_document(location, template, &mut context, scanned, multi, options) => next?,
+            };
+            match next {
+                NextPage::Done => break,
+                NextPage::Dropped => continue,
+                NextPage::Saved => context.page += 1,
+            }
+            if !multi {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Streams the page scanned as number `scanned` to the file for `context.page`.
+    async fn save_next_document(
+        &self,
+        location: &Url,
+        template: &FilenameTemplate,
+        context: &mut FilenameContext,
+        scanned: u32,
+        multi: bool,
+        options: &FetchOptions,
+    ) -> Result<NextPage> {
+        let Some(document) = self.open_next_document(location).await? else {
+            return Ok(NextPage::Done);
+        };
+        let metadata = PageMetadata {
+            number: context.page,
+            content_type: options.content_type(&document),
+        };
+        context.extension = options
+            .extension(metadata.content_type.as_deref())
+            .to_string();
+        let path = if multi {
+            template.render_page(context)?
+        } else {
+            template.render(context)?
+        };
+        let progress = |progress| options.report_progress(scanned, progress);
+
+        if options.buffers_page(scanned) {
+            let mut content = Vec::new();
+            document.write_to(&mut content, progress).await?;
+            let Some(content) = options.process_page(scanned, content)? else {
+                return Ok(NextPage::Dropped);
+            };
+            let (mut dest, path) = create_unique(&path).await?;
+            log::info!("Saving page {} to {}", metadata.number, path.display());
+            dest.write_all(&content).await?;
+            dest.flush().await?;
+        } else {
+            let (mut dest, path) = create_unique(&path).await?;
+            log::info!("Saving page {} to {}", metadata.number, path.display());
+            document.write_to(&mut dest, progress).await?;
+        }
+        Ok(NextPage::Saved)
+    }
+
+    /// Hands every page of a job to `sink` and returns the number of pages.
+    /// The sink is aborted if fetching fails or `options.cancel` fires.
+    pub async fn fetch_to_sink<S: PageSink>(
+        &self,
+        location: &Url,
+        sink: &mut S,
+        options: &FetchOptions,
+    ) -> Result<u32> {
+        sink.begin_job(location).await?;
+        let result = tokio::select! {
+            biased;
+            _ = options.cancel.cancelled() => Err(self.abort_job(location).await),
+            result = self.send_pages(location, sink, options) => result,
+        };
+        match result {
+            Ok(pages) => {
+                sink.end_job().await?;
+                Ok(pages)
+            }
+            Err(error) => {
+                sink.abort().await?;
+                Err(error)
+            }
+        }
+    }
+
+    async fn send_pages<S: PageSink>(
+        &self,
+        location: &Url,
+        sink: &mut S,
+        options: &FetchOptions,
+    ) -> Result<u32> {
+        let mut scanned = 0;
+        let mut count = 0;
+        while let Some(document) = self.open_next_document(location).await? {
+            scanned += 1;
+            let content_type = options.content_type(&document);
+            let mut content = Vec::new();
+            document
+                .write_to(&mut content, |progress| {
+                    options.report_progress(scanned, progress)
+                })
+                .await?;
+            let Some(content) = options.process_page(scanned, content)? else {
+                continue;
+            };
+            count += 1;
+            let metadata = PageMetadata {
+                number: count,
+                content_type,
+            };
+            sink.page(Bytes::from(content), &metadata).await?;
+        }
+        Ok(count)
+    }
+
+    /// Cancels the job after `FetchOptions::cancel` fired.
+    async fn abort_job(&self, location: &Url) -> AirscanError {
+        log::info!("Cancelling scan job {}", location);
+        match self.cancel_job(location).await {
+            Ok(()) => AirscanError::Cancelled,
+            Err(error) => error,
+        }
+    }
+
+    /// Scans the platen at a low resolution with the `Preview` intent and decodes the page,
+    /// e.g. for a thumbnail before the actual scan.
+    pub async fn preview(&self) -> Result<DynamicImage> {
+        let capabilities = self.capabilities().await?;
+        let caps = capabilities.platen_caps().ok_or_else(|| {
+            AirscanError::Unsupported(String::from("Scanner has no platen for a preview"))
+        })?;
+        let location = self.submit_job(&preview_settings(caps)).await?;
+        let page = self.next_document(&location).await?.ok_or_else(|| {
+            AirscanError::InvalidResponse(String::from("Preview scan returned no page"))
+        })?;
+        Ok(image::load_from_memory(&page)?)
+    }
+
+    /// Deletes the job at `location`. A job that is already gone is not an error.
+    pub async fn cancel_job(&self, location: &Url) -> Result<()> {
+        let response = self
+            .send(self.client.delete(location.as_str().trim_end_matches('/')))
+            .await?;
+
+        if response.status().is_success() || response.status() == StatusCode::NOT_FOUND {
+            return Ok(());
+        }
+        Err(AirscanError::from_response(response).await)
+    }
+
+    async fn get_xml(&self, path: &str) -> Result<String> {
+        let response = self
+            .send(self.client.get(format!("{}/{}", self.base_url, path)))
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(AirscanError::from_response(response).await);
+        }
+        Ok(response.text().await?)
+    }
+
+    /// Sends a request, answering an authentication challenge if the scanner asks for one.
+    async fn send(&self, request: RequestBuilder) -> Result<Response> {
+        let mut request = request.build()?;
+        let retry = request.try_clone();
+        if let Some(auth) = &self.auth {
+            auth.authorize(&mut request)?;
+        }
+        let response = self.client.execute(request).await?;
+        if response.status() != StatusCode::UNAUTHORIZED {
+            return Ok(response);
+        }
+
+        let url = response.url().to_string();
+        let Some(auth) = &self.auth else {
+            return Err(AirscanError::AuthenticationRequired { url });
+        };
+        // Also answered after sending credentials, the nonce of a Digest challenge may have expired.
+        let (Some(mut request), true) = (retry, auth.challenge(response.headers())?) else {
+            return Err(AirscanError::AuthenticationFailed { url });
+        };
+        auth.authorize(&mut request)?;
+        let response = self.client.execute(request).await?;
+        if response.status() == StatusCode::UNAUTHORIZED {
+            return Err(AirscanError::AuthenticationFailed { url });
+        }
+        Ok(response)
+    }
+
+    async fn job_info(&self, location: &Url) -> Option<JobInfo> {
+        let status = self.status().await.ok()?;
+        status.find_job(location).cloned()
+    }
+}
+
+/// Settings for a quick color platen scan in a format that can be decoded.
+fn preview_settings(caps: &InputCaps) -> ScanSettings {
+    let resolution = if caps.supports_resolution(PREVIEW_RESOLUTION) {
+        PREVIEW_RESOLUTION
+    } else {
+        caps.discrete_resolutions()
+            .first()
+            .copied()
+            .unwrap_or(PREVIEW_RESOLUTION)
+    };
+    let format = if caps.document_formats().contains(&"image/jpeg") {
+        "image/jpeg"
+    } else {
+        "image/png"
+    };
+    let color_mode = if caps.color_modes().contains(&ColorMode::RGB24.as_str()) {
+        ColorMode::RGB24
+    } else {
+        ColorMode::Grayscale8
+    };
+    let mut settings = ScanSettings::builder()
+        .input_source(InputSource::Platen)
+        .resolution(resolution)
+        .document_format(format)
+        .color_mode(color_mode);
+    if caps.supported_intents.is_empty()
+        || caps
+            .supported_intents
+            .iter()
+            .any(|intent| intent == "Preview")
+    {
+        settings = settings.intent(Intent::Preview);
+    }
+    settings.build()
+}
+
+#[derive(Debug)]
+pub struct ScannerClientBuilder {
+    base_url: String,
+    connect_timeout: Duration,
+    read_timeout: Duration,
+    user_agent: String,
+    proxy: Option<Proxy>,
+    keep_alive: bool,
+    headers: HeaderMap,
+    retry_policy: RetryPolicy,
+    tls: TlsVerification,
+    on_pin: Option<PinCallback>,
+    credentials: Option<Credentials>,
+}
+
+impl ScannerClientBuilder {
+    fn new(url: &str) -> Self {
+        ScannerClientBuilder {
+            base_url: url.to_string(),
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            proxy: None,
+            keep_alive: true,
+            headers: HeaderMap::new(),
+            retry_policy: RetryPolicy::default(),
+            tls: TlsVerification::default(),
+            on_pin: None,
+            credentials: None,
+        }
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Maximum time between two reads of a response, e.g. while a page is being scanned.
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = timeout;
+        self
+    }
+
+    pub fn user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.user_agent = user_agent.into();
+        self
+    }
+
+    pub fn proxy(mut self, proxy: Proxy) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// Reuse connections between requests, enabled by default.
+    pub fn keep_alive(mut self, keep_alive: bool) -> Self {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    /// Adds a header sent with every request.
+    pub fn header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.headers.insert(name, value);
+        self
+    }
+
+    /// How to retry while the scanner is busy or the connection drops.
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = policy;
+        self
+    }
+
+    /// How to check the certificate of an `https` scanner.
+    pub fn tls(mut self, verification: TlsVerification) -> Self {
+        self.tls = verification;
+        self
+    }
+
+    /// Reports certificates pinned by [`TlsVerification::TrustOnFirstUse`], which are
+    /// logged otherwise.
+    pub fn on_pin(mut self, callback: PinCallback) -> Self {
+        self.on_pin = Some(callback);
+        self
+    }
+
+    /// Username and password for scanners that ask for HTTP Basic or Digest authentication.
+    pub fn credentials(mut self, credentials: Credentials) -> Self {
+        self.credentials = Some(credentials);
+        self
+    }
+
+    pub fn build(self) -> Result<ScannerClient> {
+        let mut builder = Client::builder()
+            .connect_timeout(self.connect_timeout)
+            .read_timeout(self.read_timeout)
+            .user_agent(self.user_agent)
+            .default_headers(self.headers);
+        if let Some(proxy) = self.proxy {
+            builder = builder.proxy(proxy);
+        }
+        if !self.keep_alive {
+            builder = builder.pool_max_idle_per_host(0);
+        }
+        builder = match &self.tls {
+            TlsVerification::Standard => builder,
+            TlsVerification::CaBundle(path) => {
+                let certificates = Certificate::from_pem_bundle(&std::fs::read(path)?)?;
+                if certificates.is_empty() {
+                    return Err(AirscanError::InvalidValue(format!(
+                        "No certificates in {}",
+                        path.display()
+                    )));
+                }
+                certificates.into_iter().fold(
+                    builder.use_rustls_tls().tls_built_in_root_certs(false),
+                    ClientBuilder::add_root_certificate,
+                )
+            }
+            TlsVerification::TrustOnFirstUse(path) => builder
+                .use_preconfigured_tls(tls::pinning_config(&self.base_url, path, self.on_pin)?),
+            TlsVerification::Insecure => builder.danger_accept_invalid_certs(true),
+        };
+        Ok(ScannerClient {
+            retry_policy: self.retry_policy,
+            auth: self
+                .credentials
+                .map(|credentials| Arc::new(Authenticator::new(credentials))),
+            ..ScannerClient::with_client(&self.base_url, builder.build()?)
+        })
+    }
+}
+
+enum ScannerResponse {
+    Success(Url),
+    Busy(Option<Duration>),
+}
+
+async fn send_post(
+    client: &ScannerClient,
+    post_url: &str,
+    request: &str,
+) -> Result<ScannerResponse> {
+    let response = client
+        .send(
+            client
+                .client
+                .post(post_url)
+                .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+                .body(request.to_string()),
+        )
+        .await?;
+
+    if response.status().is_success() {
+        let location_url = response
+            .headers()
+            .get("location")
+            .and_then(|header| header.to_str().ok())
+            .and_then(|location_string| Url::parse(&format!("{}/", location_string)).ok());
+
+        return match location_url {
+            Some(url) => Ok(ScannerResponse::Success(url)),
+            None => Err(AirscanError::InvalidResponse(String::from(
+                "Scan job created without a valid Location header",
+            ))),
+        };
+    }
+
+    if response.status() == StatusCode::SERVICE_UNAVAILABLE {
+        return Ok(ScannerResponse::Busy(retry_after(response.headers())));
+    }
+    Err(AirscanError::from_response(response).await)
+}
+
+fn check_adf_state(status: &ScannerStatus, source: InputSource) -> Result<()> {
+    match status.adf_state {
+        Some(adf_state) if source == InputSource::Feeder && adf_state.is_error() => {
+            Err(adf_state.into())
+        }
+        _ => Ok(()),
+    }
+}
+
+async fn wait_while_busy(retry: &mut Retry, retry_after: Option<Duration>) -> Result<()> {
+    let delay = retry.next_delay(retry_after).ok_or(AirscanError::Busy {
+        attempts: retry.attempts() + 1,
+    })?;
+    log::info!("Scanner seems busy (HTTP 503), retrying in {:?}", delay);
+    sleep(delay).await;
+    Ok(())
+}
+
+/// Waits before retrying a request that failed to connect, other errors are returned.
+async fn wait_after_error(retry: &mut Retry, error: reqwest::Error) -> Result<()> {
+    if !error.is_connect() || !retry.retries_connect_errors() {
+        return Err(error.into());
+    }
+    let Some(delay) = retry.next_delay(None) else {
+        return Err(error.into());
+    };
+    log::warn!("Connection to scanner failed, retrying in {:?}", delay);
+    sleep(delay).await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::virtual_scanner::{VirtualPage, VirtualScanner};
+
+    #[tokio::test]
+    async fn preview_scan() {
+        let png = postprocess::test_png(&[0, 128, 255, 64], 2);
+        let scanner = VirtualScanner::builder()
+            .page(VirtualPage::new(png, "image/png"))
+            .start()
+            .await
+            .unwrap();
+
+        let preview = ScannerClient::new(&scanner.url())
+            .unwrap()
+            .preview()
+            .await
+            .unwrap();
+
+        assert_eq!(preview.into_luma8().into_raw(), vec![0, 128, 255, 64]);
+        let settings = &scanner.scan_settings()[0];
+        assert!(settings.contains("<scan:Intent>Preview</scan:Intent>"));
+        assert!(settings.contains("<pwg:InputSource>Platen</pwg:InputSource>"));
+        assert!(settings.contains("<scan:XResolution>75</scan:XResolution>"));
+        assert!(settings.contains("<pwg:DocumentFormat>image/jpeg</pwg:DocumentFormat>"));
+    }
+
+    #[test]
+    fn preview_settings_fall_back() {
+        let caps = ScannerCapabilities::from_xml(include_str!(
+            "../testdata/capabilities/canon_imageclass_mf644cdw.xml"
+        ))
+        .unwrap();
+        let mut platen = caps.platen_caps().unwrap().clone();
+
+        let settings = preview_settings(&platen);
+        assert_eq!(settings.x_resolution, 150);
+        assert_eq!(settings.intent, Some(Intent::Preview));
+        platen
+            .supported_intents
+            .retain(|intent| intent != "Preview");
+        assert_eq!(preview_settings(&platen).intent, None);
+    }
+
+    #[tokio::test]
+    async fn test_send_post_success() {
+        let mut server = mockito::Server::new_async().await;
+
+        let _m = server
+            .mock("POST", "/")
+            .with_header("content-type", "application/x-www-form-urlencoded")
+            .with_body("request")
+            .with_status(200)
+            .with_header("location", "http://example.com")
+            .create_async()
+            .await;
+
+        let client = ScannerClient::new(&server.url()).unwrap();
+        let result = send_post(&client, server.url().as_str(), "request")
+            .await
+            .unwrap();
+
+        match result {
+            ScannerResponse::Success(url) => assert_eq!(url.as_str(), "http://example.com/"),
+            _ => panic!("Unexpected result"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_post_without_location() {
+        let mut server = mockito::Server::new_async().await;
+
+        let _m = server
+            .mock("POST", "/")
+            .with_status(201)
+            .create_async()
+            .await;
+
+        let client = ScannerClient::new(&server.url()).unwrap();
+        let result = send_post(&client, server.url().as_str(), "request").await;
+
+        assert!(matches!(result, Err(AirscanError::InvalidResponse(_))));
+    }
+
+    #[tokio::test]
+    async fn test_send_post_busy() {
+        let mut server = mockito::Server::new_async().await;
+
+        let _m = server
+            .mock("POST", "/")
+            .with_header("content-type", "application/x-www-form-urlencoded")
+            .with_body("request")
+            .with_status(503)
+            .create_async()
+            .await;
+
+        let client = ScannerClient::new(&server.url()).unwrap();
+        let result = send_post(&client, server.url().as_str(), "request")
+            .await
+            .unwrap();
+
+        match result {
+            ScannerResponse::Busy(retry_after) => assert_eq!(retry_after, None),
+            _ => panic!("Unexpected result"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_post_error() {
+        let mut server = mockito::Server::new_async().await;
+
+        let _m = server
+            .mock("POST", "/")
+            .with_header("content-type", "application/x-www-form-urlencoded")
+            .with_body("request")
+            .with_status(500)
+            .create_async()
+            .await;
+
+        let client = ScannerClient::new(&server.url()).unwrap();
+        let result = send_post(&client, server.url().as_str(), "request").await;
+
+        assert!(matches!(
+            result,
+            Err(AirscanError::Http { status, .. }) if status == 500
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_builder_sends_user_agent_and_headers() {
+        let mut server = mockito::Server::new_async().await;
+        let _m = server
+            .mock("GET", "/eSCL/ScannerStatus")
+            .match_header("user-agent", "test-agent/1.0")
+            .match_header("x-scanner-token", "secret")
+            .with_status(200)
+            .with_body(include_str!("../testdata/status/hp_processing.xml"))
+            .create_async()
+            .await;
+
+        let client = ScannerClient::builder(&format!("{}/eSCL/", server.url()))
+            .user_agent("test-agent/1.0")
+            .header(
+                HeaderName::from_static("x-scanner-token"),
+                HeaderValue::from_static("secret"),
+            )
+            .connect_timeout(Duration::from_secs(1))
+            .read_timeout(Duration::from_secs(5))
+            .keep_alive(false)
+            .build()
+            .unwrap();
+
+        assert_eq!(client.base_url(), format!("{}/eSCL", server.url()));
+        let status = client.status().await.unwrap();
+        assert_eq!(status.jobs.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_read_timeout() {
+        let mut server = mockito::Server::new_async().await;
+        let _m = server
+            .mock("GET", "/ScannerCapabilities")
+            .with_status(200)
+            .with_chunked_body(|writer| {
+                std::thread::sleep(Duration::from_millis(500));
+                writer.write_all(b"<scan:ScannerCapabilities/>")
+            })
+            .create_async()
+            .await;
+
+        let client = ScannerClient::builder(&server.url())
+            .read_timeout(Duration::from_millis(100))
+            .build()
+            .unwrap();
+
+        assert!(matches!(
+            client.capabilities().await,
+            Err(AirscanError::Transport(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_submit_job_and_next_document() {
+        let mut server = mockito::Server::new_async().await;
+        let location = format!("{}/eSCL/ScanJobs/42", server.url());
+        let _post = server
+            .mock("POST", "/eSCL/ScanJobs")
+            .with_status(201)
+            .with_header("location", &location)
+            .create_async()
+            .await;
+        let _page = server
+            .mock("GET", "/eSCL/ScanJobs/42/NextDocument")
+            .with_status(200)
+            .with_body("page")
+            .create_async()
+            .await;
+
+        let client = ScannerClient::with_client(&format!("{}/eSCL", server.url()), Client::new());
+        let job = client.submit_job(&ScanSettings::default()).await.unwrap();
+
+        assert_eq!(job.as_str(), format!("{}/", location));
+        assert_eq!(
+            client.next_document(&job).await.unwrap(),
+            Some(b"page".to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_submit_job_stops_when_capabilities_are_refused() {
+        let mut server = mockito::Server::new_async().await;
+        let _capabilities = server
+            .mock("GET", "/eSCL/ScannerCapabilities")
+            .with_status(401)
+            .create_async()
+            .await;
+        let post = server
+            .mock("POST", "/eSCL/ScanJobs")
+            .with_status(201)
+            .expect(0)
+            .create_async()
+            .await;
+
+        let client = ScannerClient::new(&format!("{}/eSCL", server.url())).unwrap();
+        let result = client.submit_job(&ScanSettings::default()).await;
+
+        assert!(matches!(
+            result,
+            Err(AirscanError::AuthenticationRequired { .. })
+        ));
+        post.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_cancel_job() {
+        let mut server = mockito::Server::new_async().await;
+        let delete = server
+            .mock("DELETE", "/eSCL/ScanJobs/42")
+            .with_status(200)
+            .create_async()
+            .await;
+        let _gone = server
+            .mock("DELETE", "/eSCL/ScanJobs/43")
+            .with_status(404)
+            .create_async()
+            .await;
+        let _error = server
+            .mock("DELETE", "/eSCL/ScanJobs/44")
+            .with_status(500)
+            .create_async()
+            .await;
+
+        let client = ScannerClient::new(&format!("{}/eSCL", server.url())).unwrap();
+        let job = |id| Url::parse(&format!("{}/eSCL/ScanJobs/{}/", server.url(), id)).unwrap();
+
+        client.cancel_job(&job(42)).await.unwrap();
+        delete.assert_async().await;
+        client.cancel_job(&job(43)).await.unwrap();
+        assert!(matches!(
+            client.cancel_job(&job(44)).await,
+            Err(AirscanError::Http { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_submit_job_honors_retry_after() {
+        let mut server = mockito::Server::new_async().await;
+        let location = format!("{}/eSCL/ScanJobs/42", server.url());
+        let busy = server
+            .mock("POST", "/eSCL/ScanJobs")
+            .with_status(503)
+            .with_header("retry-after", "0")
+            .expect(1)
+            .create_async()
+            .await;
+        let created = server
+            .mock("POST", "/eSCL/ScanJobs")
+            .with_status(201)
+            .with_header("location", &location)
+            .create_async()
+            .await;
+
+        let client = ScannerClient::builder(&format!("{}/eSCL", server.url()))
+            .retry_policy(RetryPolicy::fixed(Duration::from_secs(60)))
+            .build()
+            .unwrap();
+        let job = client.submit_job(&ScanSettings::default()).await.unwrap();
+
+        assert_eq!(job.as_str(), format!("{}/", location));
+        busy.assert_async().await;
+        created.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_busy_gives_up_after_max_attempts() {
+        let mut server = mockito::Server::new_async().await;
+        let busy = server
+            .mock("GET", "/eSCL/ScanJobs/42/NextDocument")
+            .with_status(503)
+            .expect(3)
+            .create_async()
+            .await;
+
+        let client = ScannerClient::builder(&format!("{}/eSCL", server.url()))
+            .retry_policy(RetryPolicy::fixed(Duration::ZERO).with_max_attempts(2))
+            .build()
+            .unwrap();
+        let job = Url::parse(&format!("{}/eSCL/ScanJobs/42/", server.url())).unwrap();
+
+        assert!(matches!(
+            client.next_document(&job).await,
+            Err(AirscanError::Busy { attempts: 3 })
+        ));
+        busy.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_connect_errors() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let url = format!("http://127.0.0.1:{}/eSCL", port);
+        let job = Url::parse(&format!("{}/ScanJobs/42/", url)).unwrap();
+
+        let client = ScannerClient::builder(&url)
+            .retry_policy(RetryPolicy::fixed(Duration::from_millis(10)).with_max_attempts(2))
+            .build()
+            .unwrap();
+        assert!(matches!(
+            client.next_document(&job).await,
+            Err(AirscanError::Transport(error)) if error.is_connect()
+        ));
+        assert!(matches!(
+            client.submit_job(&ScanSettings::default()).await,
+            Err(AirscanError::Transport(error)) if error.is_connect()
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_result_cancel_deletes_job() {
+        let mut server = mockito::Server::new_async().await;
+        let _busy = server
+            .mock("GET", "/eSCL/ScanJobs/42/NextDocument")
+            .with_status(503)
+            .create_async()
+            .await;
+        let delete = server
+            .mock("DELETE", "/eSCL/ScanJobs/42")
+            .with_status(200)
+            .create_async()
+            .await;
+
+        let client = ScannerClient::builder(&format!("{}/eSCL", server.url()))
+            .retry_policy(RetryPolicy::fixed(Duration::from_millis(20)))
+            .build()
+            .unwrap();
+        let job = Url::parse(&format!("{}/eSCL/ScanJobs/42/", server.url())).unwrap();
+        let options = FetchOptions::default();
+        let cancel = options.cancel.clone();
+        tokio::spawn(async move {
+            sleep(Duration::from_millis(100)).await;
+            cancel.cancel();
+        });
+
+        let result = client
+            .fetch_result(&job, "test_cancel.txt", false, &options)
+            .await;
+
+        assert!(matches!(result, Err(AirscanError::Cancelled)));
+        delete.assert_async().await;
+        assert!(!std::path::Path::new("test_cancel.txt").exists());
+    }
+
+    #[test]
+    fn client_for_job_location() {
+        let location =
+            Url::parse("http://192.168.2.38/eSCL/ScanJobs/b30a11f0-5834-11b2-8325-3c2af490c39a/")
+                .unwrap();
+
+        let client = ScannerClient::for_job(&location).unwrap();
+
+        assert_eq!(client.base_url(), "http://192.168.2.38/eSCL");
+    }
+}
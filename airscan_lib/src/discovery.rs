@@ -0,0 +1,291 @@
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use mdns_sd::{IfKind, ServiceDaemon, ServiceEvent, ServiceInfo};
+use tokio::time::{sleep_until, Instant};
+
+use crate::error::{AirscanError, Result};
+
+/// DNS-SD service type of eSCL scanners reachable over HTTP.
+pub const USCAN_SERVICE: &str = "_uscan._tcp.local.";
+/// DNS-SD service type of eSCL scanners reachable over HTTPS.
+pub const USCANS_SERVICE: &str = "_uscans._tcp.local.";
+
+const DEFAULT_RESOURCE_PATH: &str = "eSCL";
+const DEFAULT_DISCOVERY_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiscoveryOptions {
+    /// How long to listen for announcements.
+    pub timeout: Duration,
+    /// Also browse on loopback interfaces, e.g. for scanners announced by this host.
+    pub include_loopback: bool,
+}
+
+impl Default for DiscoveryOptions {
+    fn default() -> Self {
+        DiscoveryOptions {
+            timeout: DEFAULT_DISCOVERY_TIMEOUT,
+            include_loopback: false,
+        }
+    }
+}
+
+/// eSCL scanner announced via mDNS, with the fields of its TXT record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiscoveredScanner {
+    /// Service instance name, e.g. `Brother MFC-L2710DW series`.
+    pub name: String,
+    pub hostname: String,
+    pub addresses: Vec<IpAddr>,
+    pub port: u16,
+    /// Announced as `_uscans._tcp`.
+    pub secure: bool,
+    /// eSCL base URL, e.g. `http://192.168.2.38:80/eSCL`.
+    pub url: String,
+    /// `rs`: path of the eSCL resources, without slashes.
+    pub resource_path: String,
+    /// `UUID`
+    pub uuid: Option<String>,
+    /// `ty`: make and model.
+    pub make_and_model: Option<String>,
+    /// `cs`: color spaces, e.g. `color`, `grayscale`, `binary`.
+    pub color_spaces: Vec<String>,
+    /// `pdl`: supported document formats.
+    pub document_formats: Vec<String>,
+    /// `is`: input sources, e.g. `platen`, `adf`.
+    pub input_sources: Vec<String>,
+    /// `duplex`
+    pub duplex: bool,
+    /// `note`: usually the location of the scanner.
+    pub note: Option<String>,
+}
+
+impl DiscoveredScanner {
+    fn from_service_info(info: &ServiceInfo) -> Self {
+        let secure = info.get_type() == USCANS_SERVICE;
+        let txt = |key: &str| {
+            info.get_property_val_str(key)
+                .map(str::trim)
+                .filter(|value| !value.is_empty())
+                .map(str::to_string)
+        };
+        let list = |key: &str| {
+            txt(key)
+                .map(|value| {
+                    value
+                        .split(',')
+                        .map(|item| item.trim().to_string())
+                        .filter(|item| !item.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+
+        let name = info
+            .get_fullname()
+            .strip_suffix(info.get_type())
+            .unwrap_or(info.get_fullname())
+            .trim_end_matches('.')
+            .to_string();
+        let hostname = info.get_hostname().trim_end_matches('.').to_string();
+        let mut addresses: Vec<IpAddr> = info.get_addresses().iter().copied().collect();
+        // Prefer IPv4, it needs no interface scope.
+        addresses.sort_by_key(|address| (address.is_ipv6(), *address));
+        let resource_path = match info.get_property_val_str("rs") {
+            Some(rs) => rs.trim().trim_matches('/').to_string(),
+            None => DEFAULT_RESOURCE_PATH.to_string(),
+        };
+
+        let host = match addresses.first() {
+            Some(address) => SocketAddr::new(*address, info.get_port()).to_string(),
+            None => format!("{}:{}", hostname, info.get_port()),
+        };
+        let scheme = if secure { "https" } else { "http" };
+        let url = if resource_path.is_empty() {
+            format!("{}://{}", scheme, host)
+        } else {
+            format!("{}://{}/{}", scheme, host, resource_path)
+        };
+
+        DiscoveredScanner {
+            name,
+            hostname,
+            addresses,
+            port: info.get_port(),
+            secure,
+            url,
+            resource_path,
+            uuid: txt("UUID"),
+            make_and_model: txt("ty"),
+            color_spaces: list("cs"),
+            document_formats: list("pdl"),
+            input_sources: list("is"),
+            duplex: txt("duplex").is_some_and(|duplex| duplex.eq_ignore_ascii_case("T")),
+            note: txt("note"),
+        }
+    }
+}
+
+impl From<mdns_sd::Error> for AirscanError {
+    fn from(error: mdns_sd::Error) -> Self {
+        AirscanError::Discovery(error.to_string())
+    }
+}
+
+/// Browses `_uscan._tcp` and `_uscans._tcp` until `options.timeout` has passed.
+/// Scanners are sorted by name, each announced service instance is listed once.
+pub async fn discover(options: &DiscoveryOptions) -> Result<Vec<DiscoveredScanner>> {
+    let daemon = ServiceDaemon::new()?;
+    if options.include_loopback {
+        daemon.enable_interface(vec![IfKind::LoopbackV4, IfKind::LoopbackV6])?;
+    }
+    let result = browse(&daemon, options.timeout).await;
+    // Ignoring the status, the daemon thread stops either way.
+    let _ = daemon.shutdown();
+    result
+}
+
+async fn browse(daemon: &ServiceDaemon, timeout: Duration) -> Result<Vec<DiscoveredScanner>> {
+    let deadline = Instant::now() + timeout;
+    let http = daemon.browse(USCAN_SERVICE)?;
+    let https = daemon.browse(USCANS_SERVICE)?;
+    let mut scanners = BTreeMap::new();
+
+    loop {
+        let event = tokio::select! {
+            event = http.recv_async() => event,
+            event = https.recv_async() => event,
+            _ = sleep_until(deadline) => break,
+        };
+        match event {
+            Ok(ServiceEvent::ServiceResolved(info)) => {
+                let scanner = DiscoveredScanner::from_service_info(&info);
+                log::debug!("Found scanner {} at {}", scanner.name, scanner.url);
+                scanners.insert(info.get_fullname().to_string(), scanner);
+            }
+            Ok(_) => {}
+            Err(_) => break,
+        }
+    }
+
+    let mut scanners: Vec<DiscoveredScanner> = scanners.into_values().collect();
+    scanners.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(scanners)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn brother_txt() -> Vec<(&'static str, &'static str)> {
+        vec![
+            ("txtvers", "1"),
+            ("rs", "/eSCL/"),
+            ("UUID", "e3248000-80ce-11db-8000-30055c773bcf"),
+            ("ty", "Brother MFC-L2710DW series"),
+            ("cs", "binary,grayscale,color"),
+            ("pdl", "application/pdf,image/jpeg"),
+            ("is", "platen,adf"),
+            ("duplex", "F"),
+            ("note", "Office 2nd floor"),
+        ]
+    }
+
+    #[test]
+    fn scanner_from_txt_record() {
+        let info = ServiceInfo::new(
+            USCAN_SERVICE,
+            "Brother MFC-L2710DW series",
+            "BRW30055C773BCF.local.",
+            "192.168.2.38",
+            80,
+            &brother_txt()[..],
+        )
+        .unwrap();
+
+        let scanner = DiscoveredScanner::from_service_info(&info);
+
+        assert_eq!(scanner.name, "Brother MFC-L2710DW series");
+        assert_eq!(scanner.hostname, "BRW30055C773BCF.local");
+        assert_eq!(scanner.url, "http://192.168.2.38:80/eSCL");
+        assert_eq!(scanner.resource_path, "eSCL");
+        assert!(!scanner.secure);
+        assert_eq!(
+            scanner.uuid.as_deref(),
+            Some("e3248000-80ce-11db-8000-30055c773bcf")
+        );
+        assert_eq!(
+            scanner.make_and_model.as_deref(),
+            Some("Brother MFC-L2710DW series")
+        );
+        assert_eq!(scanner.color_spaces, vec!["binary", "grayscale", "color"]);
+        assert_eq!(
+            scanner.document_formats,
+            vec!["application/pdf", "image/jpeg"]
+        );
+        assert_eq!(scanner.input_sources, vec!["platen", "adf"]);
+        assert!(!scanner.duplex);
+        assert_eq!(scanner.note.as_deref(), Some("Office 2nd floor"));
+    }
+
+    #[test]
+    fn secure_scanner_without_txt_record() {
+        let info = ServiceInfo::new(
+            USCANS_SERVICE,
+            "HP Color LaserJet MFP M479fdw",
+            "hp-m479.local.",
+            "fd00::10,10.0.0.7",
+            443,
+            None,
+        )
+        .unwrap();
+
+        let scanner = DiscoveredScanner::from_service_info(&info);
+
+        assert!(scanner.secure);
+        assert_eq!(scanner.url, "https://10.0.0.7:443/eSCL");
+        assert_eq!(scanner.addresses.len(), 2);
+        assert_eq!(scanner.uuid, None);
+        assert!(scanner.input_sources.is_empty());
+    }
+
+    #[tokio::test]
+    async fn discover_local_responder() {
+        let responder = ServiceDaemon::new().unwrap();
+        responder
+            .enable_interface(vec![IfKind::LoopbackV4])
+            .unwrap();
+        let mut txt = brother_txt();
+        txt.retain(|(key, _)| !matches!(*key, "rs" | "duplex"));
+        txt.push(("rs", "scanner/eSCL"));
+        txt.push(("duplex", "T"));
+        let info = ServiceInfo::new(
+            USCAN_SERVICE,
+            "airscan test responder",
+            "airscan-test.local.",
+            "127.0.0.1",
+            8080,
+            &txt[..],
+        )
+        .unwrap();
+        responder.register(info).unwrap();
+
+        let options = DiscoveryOptions {
+            timeout: Duration::from_secs(2),
+            include_loopback: true,
+        };
+        let scanners = discover(&options).await.unwrap();
+        let _ = responder.shutdown();
+
+        let scanner = scanners
+            .iter()
+            .find(|scanner| scanner.name == "airscan test responder")
+            .expect("responder was not discovered");
+        assert_eq!(scanner.url, "http://127.0.0.1:8080/scanner/eSCL");
+        assert!(scanner.duplex);
+    }
+}
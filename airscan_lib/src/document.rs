@@ -0,0 +1,234 @@
+use std::{fmt, sync::Arc};
+
+use bytes::Bytes;
+use futures_util::{stream, Stream};
+use reqwest::{header::CONTENT_TYPE, Response};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use crate::error::Result;
+
+/// Largest buffer reserved up front for `Content-Length`, which scanners may overstate.
+const MAX_PREALLOCATION: u64 = 16 * 1024 * 1024;
+
+/// Download progress of a single page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    pub bytes_received: u64,
+    /// `Content-Length` of the page, if the scanner sent one.
+    pub content_length: Option<u64>,
+}
+
+/// Called with the page number, starting at 1, and the progress of that page.
+#[derive(Clone)]
+pub struct ProgressCallback(Arc<dyn Fn(u32, Progress) + Send + Sync>);
+
+impl ProgressCallback {
+    pub fn new(callback: impl Fn(u32, Progress) + Send + Sync + 'static) -> Self {
+        ProgressCallback(Arc::new(callback))
+    }
+
+    pub(crate) fn call(&self, page: u32, progress: Progress) {
+        (self.0)(page, progress)
+    }
+}
+
+impl fmt::Debug for ProgressCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ProgressCallback")
+    }
+}
+
+/// A page returned by `NextDocument` whose body has not been read yet.
+#[derive(Debug)]
+pub struct Document {
+    response: Response,
+    content_length: Option<u64>,
+    bytes_received: u64,
+}
+
+impl Document {
+    pub(crate) fn new(response: Response) -> Self {
+        // Read up front, reqwest reports the remaining length once the body is consumed.
+        let content_length = response.content_length();
+        Document {
+            response,
+            content_length,
+            bytes_received: 0,
+        }
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+    }
+
+    pub fn content_length(&self) -> Option<u64> {
+        self.content_length
+    }
+
+    pub fn progress(&self) -> Progress {
+        Progress {
+            bytes_received: self.bytes_received,
+            content_length: self.content_length(),
+        }
+    }
+
+    /// Next chunk of the body, `None` once it is complete.
+    pub async fn chunk(&mut self) -> Result<Option<Bytes>> {
+        let chunk = self.response.chunk().await?;
+        if let Some(chunk) = &chunk {
+            self.bytes_received += chunk.len() as u64;
+        }
+        Ok(chunk)
+    }
+
+    /// Copies the body to `writer` as it arrives, reporting the progress after every chunk.
+    /// Returns the number of bytes written.
+    pub async fn write_to<W>(
+        mut self,
+        writer: &mut W,
+        mut progress: impl FnMut(Progress),
+    ) -> Result<u64>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        while let Some(chunk) = self.chunk().await? {
+            writer.write_all(&chunk).await?;
+            progress(self.progress());
+        }
+        writer.flush().await?;
+        Ok(self.bytes_received)
+    }
+
+    /// Reads the whole body into memory.
+    pub async fn bytes(mut self) -> Result<Vec<u8>> {
+        let capacity = self.content_length().unwrap_or(0).min(MAX_PREALLOCATION);
+        let mut content = Vec::with_capacity(capacity as usize);
+        while let Some(chunk) = self.chunk().await? {
+            content.extend_from_slice(&chunk);
+        }
+        Ok(content)
+    }
+
+    pub fn into_stream(self) -> impl Stream<Item = Result<Bytes>> {
+        stream::try_unfold(self, |mut document| async move {
+            Ok(document.chunk().await?.map(|chunk| (chunk, document)))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use futures_util::TryStreamExt;
+    use reqwest::Url;
+
+    use super::*;
+    use crate::{
+        client::{FetchOptions, ScannerClient},
+        settings::ScanSettings,
+        virtual_scanner::{VirtualPage, VirtualScanner},
+    };
+
+    async fn serve_page(server: &mut mockito::ServerGuard, body: &[u8]) -> (ScannerClient, Url) {
+        server
+            .mock("GET", "/eSCL/ScanJobs/1/NextDocument")
+            .with_status(200)
+            .with_header("content-type", "image/jpeg")
+            .with_body(body)
+            .create_async()
+            .await;
+        let client = ScannerClient::new(&format!("{}/eSCL", server.url())).unwrap();
+        let job = Url::parse(&format!("{}/eSCL/ScanJobs/1/", server.url())).unwrap();
+        (client, job)
+    }
+
+    #[tokio::test]
+    async fn write_to_reports_progress() {
+        let mut server = mockito::Server::new_async().await;
+        let body: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+        let (client, job) = serve_page(&mut server, &body).await;
+
+        let document = client.open_next_document(&job).await.unwrap().unwrap();
+        assert_eq!(document.content_type(), Some("image/jpeg"));
+        assert_eq!(document.content_length(), Some(body.len() as u64));
+
+        let mut reports = Vec::new();
+        let mut content = Vec::new();
+        let written = document
+            .write_to(&mut content, |progress| reports.push(progress))
+            .await
+            .unwrap();
+
+        assert_eq!(written, body.len() as u64);
+        assert_eq!(content, body);
+        assert!(reports
+            .windows(2)
+            .all(|pair| pair[0].bytes_received < pair[1].bytes_received));
+        assert_eq!(
+            reports.last(),
+            Some(&Progress {
+                bytes_received: body.len() as u64,
+                content_length: Some(body.len() as u64)
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn stream_chunks() {
+        let mut server = mockito::Server::new_async().await;
+        let (client, job) = serve_page(&mut server, b"streamed page").await;
+
+        let document = client.open_next_document(&job).await.unwrap().unwrap();
+        let chunks: Vec<Bytes> = document.into_stream().try_collect().await.unwrap();
+
+        assert_eq!(chunks.concat(), b"streamed page");
+    }
+
+    #[tokio::test]
+    async fn fetch_result_progress_callback() {
+        let scanner = VirtualScanner::builder()
+            .page(VirtualPage::new(vec![7; 4096], "image/jpeg"))
+            .adf_sheets(2)
+            .start()
+            .await
+            .unwrap();
+        let client = ScannerClient::new(&scanner.url()).unwrap();
+        let settings = ScanSettings::builder()
+            .input_source(crate::InputSource::Feeder)
+            .document_format("image/jpeg")
+            .build();
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let options = FetchOptions {
+            progress: Some(ProgressCallback::new({
+                let reports = reports.clone();
+                move |page, progress| reports.lock().unwrap().push((page, progress))
+            })),
+            ..FetchOptions::default()
+        };
+
+        let location = client.submit_job(&settings).await.unwrap();
+        client
+            .fetch_result(&location, "test_progress.jpg", true, &options)
+            .await
+            .unwrap();
+
+        let reports = reports.lock().unwrap();
+        let last_of_page = |page| {
+            reports
+                .iter()
+                .rfind(|(number, _)| *number == page)
+                .map(|(_, progress)| progress.bytes_received)
+        };
+        assert_eq!(last_of_page(1), Some(4096));
+        assert_eq!(last_of_page(2), Some(4096));
+        for page in 1..=2 {
+            let filename = format!("test_progress-{}.jpg", page);
+            assert_eq!(std::fs::read(&filename).unwrap(), vec![7; 4096]);
+            std::fs::remove_file(filename).unwrap();
+        }
+    }
+}
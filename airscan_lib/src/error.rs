@@ -0,0 +1,103 @@
+use reqwest::StatusCode;
+use thiserror::Error;
+
+use crate::status::{AdfState, JobState};
+
+pub type Result<T, E = AirscanError> = std::result::Result<T, E>;
+
+#[derive(Debug, Error)]
+pub enum AirscanError {
+    #[error("Scanner responded with HTTP {status}: {body}")]
+    Http { status: StatusCode, body: String },
+
+    #[error("Scanner at {url} requires credentials")]
+    AuthenticationRequired { url: String },
+
+    #[error("Scanner at {url} rejected the credentials")]
+    AuthenticationFailed { url: String },
+
+    #[error("Scanner is busy for too long, gave up after {attempts} attempts")]
+    Busy { attempts: u32 },
+
+    #[error("Scanner ADF is empty")]
+    AdfEmpty,
+
+    #[error("Scanner ADF is jammed")]
+    AdfJam,
+
+    #[error("Scanner ADF is not ready: {0:?}")]
+    AdfNotReady(AdfState),
+
+    #[error("Scan job {state:?}: {}", reasons.join(", "))]
+    JobFailed {
+        state: JobState,
+        reasons: Vec<String>,
+    },
+
+    #[error("Scan job was cancelled")]
+    Cancelled,
+
+    #[error("Scanner discovery failed: {0}")]
+    Discovery(String),
+
+    #[error("Unsupported setting: {0}")]
+    Unsupported(String),
+
+    #[error("Invalid value: {0}")]
+    InvalidValue(String),
+
+    #[error("Invalid scanner response: {0}")]
+    InvalidResponse(String),
+
+    #[error("XML error: {0}")]
+    Xml(String),
+
+    #[error("Image error: {0}")]
+    Image(#[from] image::ImageError),
+
+    #[cfg(feature = "ocr")]
+    #[error("OCR failed: {0}")]
+    Ocr(String),
+
+    #[error("TIFF error: {0}")]
+    Tiff(#[from] tiff::TiffError),
+
+    #[error("Invalid URL: {0}")]
+    Url(#[from] url::ParseError),
+
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("HTTP transport error: {0}")]
+    Transport(#[from] reqwest::Error),
+}
+
+impl From<serde_xml_rs::Error> for AirscanError {
+    fn from(error: serde_xml_rs::Error) -> Self {
+        AirscanError::Xml(error.to_string())
+    }
+}
+
+impl From<xml::writer::Error> for AirscanError {
+    fn from(error: xml::writer::Error) -> Self {
+        AirscanError::Xml(error.to_string())
+    }
+}
+
+impl From<AdfState> for AirscanError {
+    fn from(state: AdfState) -> Self {
+        match state {
+            AdfState::Empty => AirscanError::AdfEmpty,
+            AdfState::Jam => AirscanError::AdfJam,
+            state => AirscanError::AdfNotReady(state),
+        }
+    }
+}
+
+impl AirscanError {
+    pub(crate) async fn from_response(response: reqwest::Response) -> Self {
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+        AirscanError::Http { status, body }
+    }
+}
@@ -0,0 +1,422 @@
+use std::{
+    fmt::Write,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use chrono::{DateTime, Local};
+use reqwest::Url;
+use tokio::fs::{self, File, OpenOptions};
+
+use crate::error::{AirscanError, Result};
+
+/// Highest suffix tried before giving up on finding a free file name.
+const MAX_COLLISIONS: u32 = 10_000;
+/// Widest `{page:N}` padding, enough for any `u32` page number.
+const MAX_PAGE_WIDTH: usize = 10;
+
+/// Output file name with placeholders, e.g. `scans/{date}/scan-{page:03}.{ext}`.
+///
+/// Supported placeholders are `{page}` (zero padded with `{page:03}`), `{date}`
+/// (`2024-01-31`), `{time}` (`142501`), `{job_uuid}`, `{scanner}` and `{ext}`.
+/// `{{` and `}}` stand for literal braces.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilenameTemplate {
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    Page { width: usize },
+    Date,
+    Time,
+    JobUuid,
+    Scanner,
+    Extension,
+}
+
+/// Values for the placeholders of a [`FilenameTemplate`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilenameContext {
+    /// Page number within the job, starting at 1.
+    pub page: u32,
+    /// Replaces `{date}` and `{time}`.
+    pub started: DateTime<Local>,
+    pub job_uuid: Option<String>,
+    pub scanner: Option<String>,
+    /// Replaces `{ext}`, without the dot.
+    pub extension: String,
+}
+
+impl Default for FilenameContext {
+    fn default() -> Self {
+        FilenameContext {
+            page: 1,
+            started: Local::now(),
+            job_uuid: None,
+            scanner: None,
+            extension: String::from("bin"),
+        }
+    }
+}
+
+impl FilenameContext {
+    /// Takes the job UUID and the scanner host from a job location.
+    pub fn for_job(location: &Url) -> Self {
+        FilenameContext {
+            job_uuid: location
+                .path_segments()
+                .and_then(|mut segments| segments.rfind(|segment| !segment.is_empty()))
+                .map(str::to_string),
+            scanner: location.host_str().map(str::to_string),
+            ..FilenameContext::default()
+        }
+    }
+}
+
+impl FromStr for FilenameTemplate {
+    type Err = AirscanError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = |reason: String| {
+            AirscanError::InvalidValue(format!("Invalid file name template {}: {}", s, reason))
+        };
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = s.chars();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let rest = chars.as_str();
+                    let end = rest
+                        .find('}')
+                        .ok_or_else(|| invalid(String::from("unclosed {")))?;
+                    let placeholder = &rest[..end];
+                    chars = rest[end + 1..].chars();
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(match placeholder.split_once(':') {
+                        Some(("page", width)) => Part::Page {
+                            width: width
+                                .parse()
+                                .ok()
+                                .filter(|width| *width <= MAX_PAGE_WIDTH)
+                                .ok_or_else(|| invalid(format!("bad page width {}", width)))?,
+                        },
+                        Some(_) => {
+                            return Err(invalid(format!("no format for {{{}}}", placeholder)))
+                        }
+                        None => match placeholder {
+                            "page" => Part::Page { width: 0 },
+                            "date" => Part::Date,
+                            "time" => Part::Time,
+                            "job_uuid" => Part::JobUuid,
+                            "scanner" => Part::Scanner,
+                            "ext" => Part::Extension,
+                            _ => return Err(invalid(format!("unknown {{{}}}", placeholder))),
+                        },
+                    });
+                }
+                '}' => return Err(invalid(String::from("unmatched }"))),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        Ok(FilenameTemplate { parts })
+    }
+}
+
+impl FilenameTemplate {
+    /// Template for exactly `name`, braces included.
+    pub fn literal(name: impl Into<String>) -> Self {
+        FilenameTemplate {
+            parts: vec![Part::Literal(name.into())],
+        }
+    }
+
+    pub(crate) fn has_placeholders(&self) -> bool {
+        self.parts
+            .iter()
+            .any(|part| !matches!(part, Part::Literal(_)))
+    }
+
+    /// Whether the template gives every page its own name.
+    pub fn has_page(&self) -> bool {
+        self.parts
+            .iter()
+            .any(|part| matches!(part, Part::Page { .. }))
+    }
+
+    pub fn render(&self, context: &FilenameContext) -> Result<PathBuf> {
+        let mut rendered = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(literal) => rendered.push_str(literal),
+                Part::Page { width } => {
+                    let _ = write!(rendered, "{:0width$}", context.page, width = *width);
+                }
+                Part::Date => {
+                    let _ = write!(rendered, "{}", context.started.format("%Y-%m-%d"));
+                }
+                Part::Time => {
+                    let _ = write!(rendered, "{}", context.started.format("%H%M%S"));
+                }
+                Part::JobUuid => {
+                    rendered.push_str(&sanitize(context.job_uuid.as_deref().unwrap_or("unknown")))
+                }
+                Part::Scanner => {
+                    rendered.push_str(&sanitize(context.scanner.as_deref().unwrap_or("unknown")))
+                }
+                Part::Extension => rendered.push_str(&sanitize(&context.extension)),
+            }
+        }
+
+        let path = PathBuf::from(&rendered);
+        if path.file_name().is_none() || rendered.ends_with(std::path::is_separator) {
+            return Err(AirscanError::InvalidValue(format!(
+                "File name template renders to {}, which is not a file name",
+                path.display()
+            )));
+        }
+        Ok(path)
+    }
+
+    /// Like [`FilenameTemplate::render`], but numbers the pages as `name-1.ext`, `name-2.ext`, ...
+    /// if the template has no `{page}`.
+    pub fn render_page(&self, context: &FilenameContext) -> Result<PathBuf> {
+        let path = self.render(context)?;
+        if self.has_page() {
+            return Ok(path);
+        }
+        Ok(with_suffix(&path, &format!("-{}", context.page)))
+    }
+}
+
+/// Keeps placeholder values from adding directories or characters some file systems reject.
+fn sanitize(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+/// Inserts `suffix` between the file stem and the extension of `path`.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_stem().unwrap_or_default().to_os_string();
+    name.push(suffix);
+    if let Some(extension) = path.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+    path.with_file_name(name)
+}
+
+/// `path` and then `name_1.ext`, `name_2.ext`, ... as alternatives if it is taken.
+fn candidates(path: &Path) -> impl Iterator<Item = PathBuf> + '_ {
+    std::iter::once(path.to_path_buf())
+        .chain((1..=MAX_COLLISIONS).map(move |n| with_suffix(path, &format!("_{}", n))))
+}
+
+fn no_free_name(path: &Path) -> AirscanError {
+    AirscanError::InvalidValue(format!("No free file name for {}", path.display()))
+}
+
+/// First name for `path` that does not exist yet.
+pub fn unique_path(path: impl AsRef<Path>) -> Result<PathBuf> {
+    let path = path.as_ref();
+    candidates(path)
+        .find(|candidate| !candidate.exists())
+        .ok_or_else(|| no_free_name(path))
+}
+
+/// Creates the file under the first free name for `path`, along with missing directories.
+pub(crate) async fn create_unique(path: &Path) -> Result<(File, PathBuf)> {
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        fs::create_dir_all(parent).await?;
+    }
+    for candidate in candidates(path) {
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&candidate)
+            .await
+        {
+            Ok(file) => return Ok((file, candidate)),
+            Err(error) if error.kind() == ErrorKind::AlreadyExists => continue,
+            Err(error) => return Err(error.into()),
+        }
+    }
+    Err(no_free_name(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::{
+        client::{FetchOptions, ScannerClient},
+        settings::{InputSource, ScanSettings},
+        virtual_scanner::{VirtualPage, VirtualScanner},
+    };
+
+    fn context() -> FilenameContext {
+        FilenameContext {
+            page: 7,
+            started: Local.with_ymd_and_hms(2024, 1, 31, 14, 25, 1).unwrap(),
+            job_uuid: Some(String::from("b30a11f0")),
+            scanner: Some(String::from("HP Envy/Photo")),
+            extension: String::from("jpg"),
+        }
+    }
+
+    fn render(template: &str) -> PathBuf {
+        template
+            .parse::<FilenameTemplate>()
+            .unwrap()
+            .render(&context())
+            .unwrap()
+    }
+
+    fn test_directory(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("airscan-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn render_placeholders() {
+        assert_eq!(
+            render("out/{date}/letter-{page:03}.{ext}"),
+            Path::new("out/2024-01-31/letter-007.jpg")
+        );
+        assert_eq!(
+            render("{scanner}_{job_uuid}_{time}_{page}.pdf"),
+            Path::new("HP Envy_Photo_b30a11f0_142501_7.pdf")
+        );
+        assert_eq!(render("{{literal}}.{ext}"), Path::new("{literal}.jpg"));
+    }
+
+    #[test]
+    fn reject_invalid_templates() {
+        for template in [
+            "{unknown}",
+            "{page",
+            "scan}",
+            "{page:x}",
+            "{page:999999999}",
+            "{date:3}",
+            "{stem}",
+        ] {
+            assert!(
+                matches!(
+                    template.parse::<FilenameTemplate>(),
+                    Err(AirscanError::InvalidValue(_))
+                ),
+                "{}",
+                template
+            );
+        }
+        let empty: FilenameTemplate = "out/".parse().unwrap();
+        assert!(empty.render(&context()).is_err());
+    }
+
+    #[test]
+    fn number_pages_without_page_placeholder() {
+        let render_page = |template: &str| {
+            template
+                .parse::<FilenameTemplate>()
+                .unwrap()
+                .render_page(&context())
+                .unwrap()
+        };
+
+        assert_eq!(
+            render_page("./out/scan.v2.jpg"),
+            Path::new("./out/scan.v2-7.jpg")
+        );
+        assert_eq!(render_page("scan"), Path::new("scan-7"));
+        assert_eq!(render_page(".hidden"), Path::new(".hidden-7"));
+        assert_eq!(render_page("p{page}.{ext}"), Path::new("p7.jpg"));
+    }
+
+    #[test]
+    fn job_context_from_location() {
+        let location = Url::parse("http://192.168.2.38/eSCL/ScanJobs/b30a11f0/").unwrap();
+
+        let context = FilenameContext::for_job(&location);
+
+        assert_eq!(context.job_uuid.as_deref(), Some("b30a11f0"));
+        assert_eq!(context.scanner.as_deref(), Some("192.168.2.38"));
+    }
+
+    #[tokio::test]
+    async fn avoid_collisions() {
+        let directory = test_directory("collisions");
+        let path = directory.join("nested/scan.pdf");
+
+        let (_, first) = create_unique(&path).await.unwrap();
+        let (_, second) = create_unique(&path).await.unwrap();
+
+        assert_eq!(first, path);
+        assert_eq!(second, directory.join("nested/scan_1.pdf"));
+        assert_eq!(
+            unique_path(&path).unwrap(),
+            directory.join("nested/scan_2.pdf")
+        );
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn fetch_pages_to_template() {
+        let scanner = VirtualScanner::builder()
+            .page(VirtualPage::new(b"page".to_vec(), "image/jpeg"))
+            .adf_sheets(2)
+            .start()
+            .await
+            .unwrap();
+        let client = ScannerClient::new(&scanner.url()).unwrap();
+        let settings = ScanSettings::builder()
+            .input_source(InputSource::Feeder)
+            .document_format("image/jpeg")
+            .build();
+        let directory = test_directory("template");
+        let template = format!("{}/{{job_uuid}}/{{page:02}}.{{ext}}", directory.display())
+            .parse()
+            .unwrap();
+
+        let location = client.submit_job(&settings).await.unwrap();
+        client
+            .fetch_to_files(&location, &template, true, &FetchOptions::default())
+            .await
+            .unwrap();
+
+        let job = FilenameContext::for_job(&location).job_uuid.unwrap();
+        for page in ["01.jpg", "02.jpg"] {
+            assert_eq!(
+                std::fs::read(directory.join(&job).join(page)).unwrap(),
+                b"page"
+            );
+        }
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,138 @@
+use crate::error::{AirscanError, Result};
+
+/// Size and color layout from the frame header of a JPEG.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct JpegInfo {
+    pub width: u32,
+    pub height: u32,
+    pub components: u8,
+    /// Horizontal and vertical chroma subsampling, `(1, 1)` without chroma.
+    pub subsampling: (u8, u8),
+}
+
+/// Reads the frame header of a JPEG.
+pub(crate) fn jpeg_info(data: &[u8]) -> Result<JpegInfo> {
+    let invalid = |reason: &str| AirscanError::InvalidValue(format!("Not a JPEG page: {}", reason));
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return Err(invalid("missing start of image"));
+    }
+
+    let mut position = 2;
+    while position + 4 <= data.len() {
+        if data[position] != 0xFF {
+            return Err(invalid("corrupt marker"));
+        }
+        let marker = data[position + 1];
+        if marker == 0xFF {
+            position += 1;
+            continue;
+        }
+        // The length counts itself but not the marker.
+        let length = u16::from_be_bytes([data[position + 2], data[position + 3]]) as usize;
+        if length < 2 {
+            return Err(invalid("invalid segment length"));
+        }
+        if position + 2 + length > data.len() {
+            return Err(invalid("truncated segment"));
+        }
+        let segment = &data[position + 4..position + 2 + length];
+        // SOF0 to SOF15, except DHT (C4), JPG (C8) and DAC (CC).
+        if (0xC0..=0xCF).contains(&marker) && !matches!(marker, 0xC4 | 0xC8 | 0xCC) {
+            if segment.len() < 6 || segment.len() < 6 + 3 * segment[5] as usize {
+                return Err(invalid("truncated frame header"));
+            }
+            let components = segment[5];
+            // Sampling factors are stored as H << 4 | V for each component.
+            let sampling = |component: usize| {
+                let factors = segment[7 + 3 * component];
+                ((factors >> 4).max(1), (factors & 0x0F).max(1))
+            };
+            let subsampling = if components == 3 {
+                let (luma, chroma) = (sampling(0), sampling(1));
+                (luma.0 / chroma.0, luma.1 / chroma.1)
+            } else {
+                (1, 1)
+            };
+            return Ok(JpegInfo {
+                height: u16::from_be_bytes([segment[1], segment[2]]) as u32,
+                width: u16::from_be_bytes([segment[3], segment[4]]) as u32,
+                components,
+                subsampling,
+            });
+        }
+        if marker == 0xDA {
+            break;
+        }
+        position += 2 + length;
+    }
+    Err(invalid("no frame header"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{codecs::jpeg::JpegEncoder, ExtendedColorType};
+
+    use super::*;
+
+    fn jpeg(width: u32, height: u32, color_type: ExtendedColorType) -> Vec<u8> {
+        let pixels = vec![0; (width * height) as usize * color_type.channel_count() as usize];
+        let mut out = Cursor::new(Vec::new());
+        JpegEncoder::new(&mut out)
+            .encode(&pixels, width, height, color_type)
+            .unwrap();
+        out.into_inner()
+    }
+
+    #[test]
+    fn read_jpeg_frame_header() {
+        let gray = jpeg(600, 300, ExtendedColorType::L8);
+        let color = jpeg(17, 23, ExtendedColorType::Rgb8);
+
+        assert_eq!(
+            jpeg_info(&gray).unwrap(),
+            JpegInfo {
+                width: 600,
+                height: 300,
+                components: 1,
+                subsampling: (1, 1),
+            }
+        );
+        assert_eq!(
+            jpeg_info(&color).unwrap(),
+            JpegInfo {
+                width: 17,
+                height: 23,
+                components: 3,
+                subsampling: (1, 1),
+            }
+        );
+        // 4:2:0 frame header, luma sampled 2x2 and chroma 1x1.
+        let subsampled = [
+            0xFF, 0xD8, 0xFF, 0xC0, 0x00, 0x11, 0x08, 0x00, 0x10, 0x00, 0x20, 0x03, 0x01, 0x22,
+            0x00, 0x02, 0x11, 0x01, 0x03, 0x11, 0x01,
+        ];
+        assert_eq!(jpeg_info(&subsampled).unwrap().subsampling, (2, 2));
+        assert!(jpeg_info(b"%PDF-1.4").is_err());
+        assert!(jpeg_info(&[0xFF, 0xD8, 0xFF, 0xDA, 0x00, 0x02]).is_err());
+    }
+
+    #[test]
+    fn reject_truncated_segments() {
+        for length in [0, 1] {
+            let app0 = [0xFF, 0xD8, 0xFF, 0xE0, 0x00, length, 0xFF, 0xC0];
+            assert!(matches!(
+                jpeg_info(&app0),
+                Err(AirscanError::InvalidValue(_))
+            ));
+        }
+        let app0 = [0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x10, 0x4A, 0x46];
+        assert!(jpeg_info(&app0).is_err());
+        let sof = [0xFF, 0xD8, 0xFF, 0xC0, 0x00, 0x11, 0x08, 0x00, 0x10, 0x00];
+        assert!(matches!(
+            jpeg_info(&sof),
+            Err(AirscanError::InvalidValue(_))
+        ));
+    }
+}
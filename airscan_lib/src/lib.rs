@@ -1,192 +1,88 @@
-use std::{fs::File, io::copy, time::Duration};
-
-use anyhow::{anyhow, Result};
-use reqwest::{header::CONTENT_TYPE, Client, Url};
-use tokio::time::sleep;
-
-enum Source {
-    Platen,
-    Feeder,
-}
-
-struct ScanSettingsInput {
-    source: Source,
-    resolution: String,
-    docformat: String,
-    colormode: String,
-    extformat: Option<bool>,
-}
-fn build_scansettings_xml(
-    source: &str,
-    resolution: &str,
-    docformat: &str,
-    colormode: &str,
-    extformat: Option<bool>,
-) -> String {
-    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>");
-    xml.push_str("<scan:ScanSettings xmlns:pwg=\"http://www.pwg.org/schemas/2010/12/sm\" ");
-    xml.push_str("xmlns:scan=\"http://schemas.hp.com/imaging/escl/2011/05/03\">");
-    xml.push_str("<pwg:Version>2.0</pwg:Version>");
-    xml.push_str("<pwg:InputSource>");
-    xml.push_str(source);
-    xml.push_str("</pwg:InputSource>");
-    xml.push_str("<pwg:ScanRegions>");
-    xml.push_str("<pwg:ScanRegion>");
-    xml.push_str("<pwg:ContentRegionUnits>escl:ThreeHundredthsOfInches</pwg:ContentRegionUnits>");
-    xml.push_str("<pwg:Height>3507</pwg:Height>");
-    xml.push_str("<pwg:Width>2550</pwg:Width>");
-    xml.push_str("<pwg:XOffset>0</pwg:XOffset>");
-    xml.push_str("<pwg:YOffset>0</pwg:YOffset>");
-    xml.push_str("</pwg:ScanRegion>");
-    xml.push_str("</pwg:ScanRegions>");
-    xml.push_str("<pwg:DocumentFormat>");
-    xml.push_str(docformat);
-    xml.push_str("</pwg:DocumentFormat>");
-    if extformat.is_some() {
-        xml.push_str("<pwg:DocumentFormatExt>");
-        xml.push_str(docformat);
-        xml.push_str("</pwg:DocumentFormatExt>");
-    }
-    xml.push_str("<scan:ColorMode>");
-    xml.push_str(colormode);
-    xml.push_str("</scan:ColorMode>");
-    xml.push_str("<scan:XResolution>");
-    xml.push_str(resolution);
-    xml.push_str("</scan:XResolution>");
-    xml.push_str("<scan:YResolution>");
-    xml.push_str(resolution);
-    xml.push_str("</scan:YResolution>");
-    xml.push_str("</scan:ScanSettings>");
-    xml
+use reqwest::Url;
+
+mod auth;
+mod capabilities;
+mod client;
+mod discovery;
+mod document;
+mod error;
+mod filename;
+mod jpeg;
+#[cfg(feature = "ocr")]
+pub mod ocr;
+mod pdf;
+pub mod postprocess;
+mod region;
+mod retry;
+mod settings;
+mod sink;
+mod status;
+mod tiff_writer;
+mod tls;
+mod version;
+#[cfg(any(test, feature = "virtual-scanner"))]
+mod virtual_scanner;
+
+pub use auth::Credentials;
+pub use capabilities::{
+    get_capabilities, Adf, DiscreteResolution, DocumentFormats, InputCaps, Platen, Range,
+    ResolutionRange, ScannerCapabilities, SettingProfile, SupportedResolutions,
+};
+pub use client::{FetchOptions, ScannerClient, ScannerClientBuilder};
+pub use discovery::{discover, DiscoveredScanner, DiscoveryOptions, USCANS_SERVICE, USCAN_SERVICE};
+pub use document::{Document, Progress, ProgressCallback};
+pub use error::{AirscanError, Result};
+pub use filename::{unique_path, FilenameContext, FilenameTemplate};
+pub use pdf::{PdfAssembler, PdfSink};
+pub use region::{PaperSize, Region, Unit};
+pub use retry::{Backoff, RetryPolicy, DEFAULT_MAX_RETRY_AFTER};
+pub use settings::{
+    ColorMode, FeedDirection, InputSource, Intent, ScanRegion, ScanSettings, ScanSettingsBuilder,
+};
+pub use sink::{DirectorySink, MemoryPage, MemorySink, PageMetadata, PageSink, StdoutSink};
+pub use status::{get_status, AdfState, JobInfo, JobState, ScannerState, ScannerStatus};
+pub use tiff_writer::{TiffAssembler, TiffCompression, TiffSink};
+pub use tls::{fingerprint, KnownScanners, PinCallback, TlsVerification};
+pub use tokio_util::sync::CancellationToken;
+pub use version::EsclVersion;
+#[cfg(feature = "virtual-scanner")]
+pub use virtual_scanner::{VirtualPage, VirtualScanner, VirtualScannerBuilder};
+
+/// Submits a job with a default [`ScannerClient`], without credentials, certificate pinning
+/// or a custom retry policy. Use [`ScannerClient::submit_job`] for those.
+pub async fn post_scanrequest(url: &str, settings: &ScanSettings) -> Result<Url> {
+    ScannerClient::new(url)?.submit_job(settings).await
 }
 
-pub async fn post_scanrequest(
-    url: &str,
-    source: &str,
-    resolution: &str,
-    docformat: &str,
-    colormode: &str,
-) -> Result<Url> {
-    let post_url = format!("{}/ScanJobs", url);
-    println!("Send request to {}", post_url);
-
-    let request = build_scansettings_xml(source, resolution, docformat, colormode, None);
-    println!("{}", request);
-
-    let client = Client::new();
-    let mut count = 0;
-
-    loop {
-        match send_post(&client, &post_url, &request).await {
-            Ok(ScannerResponse::Success(url)) => return Ok(url),
-            Ok(ScannerResponse::Busy) => {
-                increase_retry_count(&mut count).await;
-            }
-            Err(e) => return Err(e),
-        };
-    }
+/// Like [`fetch_result_with_options`] with default options.
+pub async fn fetch_result(location: Url, outfile: &str, multi: bool) -> Result<()> {
+    fetch_result_with_options(location, outfile, multi, &FetchOptions::default()).await
 }
 
-enum ScannerResponse {
-    Success(Url),
-    Busy,
-}
-
-async fn send_post(client: &Client, post_url: &str, request: &str) -> Result<ScannerResponse> {
-    let response = match client
-        .post(post_url)
-        .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
-        .body(request.to_string())
-        .send()
-        .await
-    {
-        Ok(response) => response,
-        Err(e) => return Err(e.into()),
-    };
-
-    if response.status().is_success() {
-        let location_url = response
-            .headers()
-            .get("location")
-            .and_then(|header| header.to_str().ok())
-            .and_then(|location_string| Url::parse(&format!("{}/", location_string)).ok());
-
-        if let Some(url) = location_url {
-            return Ok(ScannerResponse::Success(url));
-        }
-    }
-
-    if response.status().as_u16() == 503 {
-        return Ok(ScannerResponse::Busy);
-    }
-    return Err(anyhow!(response.status()));
-}
-
-async fn increase_retry_count(count: &mut i32) {
-    println!(
-        "Scanner seems busy (HTTP 503), waiting {} of 100 seconds",
-        count
-    );
-    *count += 1;
-    sleep(Duration::from_secs(1)).await;
-    const MAX_RETRIES: i32 = 100;
-    if *count >= MAX_RETRIES {
-        panic!("Scanner is busy for too long");
-    }
-}
-
-pub async fn fetch_result(
+/// Fetches the pages of a job with a default [`ScannerClient`] for its scanner. Credentials,
+/// certificate pinning and retry policy of the client that submitted the job are not carried
+/// over, use [`ScannerClient::fetch_result`] to keep them.
+pub async fn fetch_result_with_options(
     location: Url,
     outfile: &str,
     multi: bool,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let client = Client::new();
-    println!("{}", location);
-    let mut count = 1;
-
-    loop {
-        let filename = determine_filename(multi, outfile, count);
-        sleep(Duration::from_secs(1)).await;
-
-        let response = match client.get(location.join("NextDocument")?).send().await {
-            Ok(response) => response,
-            Err(e) => {
-                panic!("HTTP request failed: {}", e);
-            }
-        };
-
-        println!("{}", response.status());
-        if response.status().is_success() {
-            let mut dest = File::create(filename)?;
-            let content = response.bytes().await?;
-            copy(&mut content.as_ref(), &mut dest)?;
-            count += 1;
-            if !multi {
-                break;
-            }
-        } else if response.status().as_u16() == 404 {
-            break;
-        } else {
-            panic!("Unexpected HTTP error: {}", response.status());
-        }
-    }
-    Ok(())
+    options: &FetchOptions,
+) -> Result<()> {
+    ScannerClient::for_job(&location)?
+        .fetch_result(&location, outfile, multi, options)
+        .await
 }
 
-fn determine_filename(multi: bool, outfile: &str, count: i32) -> String {
-    let filename = if multi {
-        let parts: Vec<&str> = outfile.split('.').collect();
-        format!("{}-{}.{}", parts[0], count, parts[1])
-    } else {
-        outfile.to_string()
-    };
-    filename
+/// Deletes the job at the location returned by [`post_scanrequest`] with a default
+/// [`ScannerClient`], see [`fetch_result_with_options`]. Use [`ScannerClient::cancel_job`]
+/// with the configured client otherwise.
+pub async fn cancel_job(location: &Url) -> Result<()> {
+    ScannerClient::for_job(location)?.cancel_job(location).await
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use reqwest::Client;
     use std::{fs, path::Path};
 
     #[test]
@@ -211,108 +107,108 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_send_post_success() {
-        let mut server = mockito::Server::new();
-
+    async fn test_fetch_result_success_single() {
+        let mut server = mockito::Server::new_async().await;
         let _m = server
-            .mock("POST", "/")
-            .with_header("content-type", "application/x-www-form-urlencoded")
-            .with_body("request")
+            .mock("GET", "/NextDocument")
             .with_status(200)
-            .with_header("location", "http://example.com")
-            .create();
-
-        let client = Client::new();
-        let result = send_post(&client, server.url().as_str(), "request")
-            .await
-            .unwrap();
-
-        match result {
-            ScannerResponse::Success(url) => assert_eq!(url.as_str(), "http://example.com/"),
-            _ => panic!("Unexpected result"),
-        }
-    }
+            .with_body("Hello, world!")
+            .create_async()
+            .await;
 
-    #[tokio::test]
-    async fn test_send_post_busy() {
-        let mut server = mockito::Server::new();
+        let url = Url::parse(server.url().as_str()).unwrap();
+        let outfile = "test.txt";
+        let multi = false;
 
-        let _m = server
-            .mock("POST", "/")
-            .with_header("content-type", "application/x-www-form-urlencoded")
-            .with_body("request")
-            .with_status(503)
-            .create();
+        fetch_result(url, outfile, multi).await.unwrap();
 
-        let client = Client::new();
-        let result = send_post(&client, server.url().as_str(), "request")
-            .await
-            .unwrap();
+        let contents = fs::read_to_string(outfile).unwrap();
+        assert!(Path::new(outfile).exists());
+        assert_eq!(contents, "Hello, world!");
 
-        match result {
-            ScannerResponse::Busy => {}
-            _ => panic!("Unexpected result"),
-        }
+        fs::remove_file(outfile).unwrap();
     }
 
     #[tokio::test]
-    async fn test_send_post_error() {
-        let mut server = mockito::Server::new();
-
+    async fn test_fetch_result_keeps_braces_in_plain_names() {
+        let mut server = mockito::Server::new_async().await;
         let _m = server
-            .mock("POST", "/")
-            .with_header("content-type", "application/x-www-form-urlencoded")
-            .with_body("request")
-            .with_status(500)
-            .create();
+            .mock("GET", "/NextDocument")
+            .with_status(200)
+            .with_body("Hello, world!")
+            .create_async()
+            .await;
 
-        let client = Client::new();
-        let result = send_post(&client, server.url().as_str(), "request").await;
+        let url = Url::parse(server.url().as_str()).unwrap();
+        fetch_result(url, "test_{draft}.txt", false).await.unwrap();
 
-        assert!(result.is_err());
+        assert_eq!(
+            fs::read_to_string("test_{draft}.txt").unwrap(),
+            "Hello, world!"
+        );
+        fs::remove_file("test_{draft}.txt").unwrap();
     }
 
     #[tokio::test]
-    async fn test_fetch_result_success_single() {
-        let mut server = mockito::Server::new();
-        let _m = server
+    async fn test_fetch_result_extension_from_requested_format() {
+        let mut server = mockito::Server::new_async().await;
+        let _m1 = server
             .mock("GET", "/NextDocument")
             .with_status(200)
-            .with_body("Hello, world!")
-            .create();
+            .with_header("content-type", "application/octet-stream")
+            .with_body("one")
+            .create_async()
+            .await;
+        let _m2 = server
+            .mock("GET", "/NextDocument")
+            .with_status(200)
+            .with_header("content-type", "image/png; charset=binary")
+            .with_body("two")
+            .create_async()
+            .await;
+        let _m3 = server
+            .mock("GET", "/NextDocument")
+            .with_status(404)
+            .create_async()
+            .await;
 
         let url = Url::parse(server.url().as_str()).unwrap();
-        let outfile = "test.txt";
-        let multi = false;
-
-        fetch_result(url, outfile, multi).await.unwrap();
-
-        let contents = fs::read_to_string(outfile).unwrap();
-        assert!(Path::new(outfile).exists());
-        assert_eq!(contents, "Hello, world!");
+        let options = FetchOptions {
+            document_format: Some(String::from("image/jpeg")),
+            ..FetchOptions::default()
+        };
+        fetch_result_with_options(url, "test_format.{ext}", true, &options)
+            .await
+            .unwrap();
 
-        fs::remove_file(outfile).unwrap();
+        assert_eq!(fs::read_to_string("test_format-1.jpg").unwrap(), "one");
+        assert_eq!(fs::read_to_string("test_format-2.png").unwrap(), "two");
+        fs::remove_file("test_format-1.jpg").unwrap();
+        fs::remove_file("test_format-2.png").unwrap();
     }
 
     #[tokio::test()]
     async fn test_fetch_result_success_multi() {
-        let mut server = mockito::Server::new();
+        let mut server = mockito::Server::new_async().await;
         let _m1 = server
             .mock("GET", "/NextDocument")
             .with_status(200)
             .with_body("Hello, world!")
-            .create();
+            .create_async()
+            .await;
 
         let _m2 = server
             .mock("GET", "/NextDocument")
             .with_status(200)
             .with_body("Goodbye, world!")
-            .create();
+            .create_async()
+            .await;
 
         let _m3 = server
             .mock("GET", "/NextDocument")
             .with_status(404)
-            .create();
+            .create_async()
+            .await;
 
         let url = Url::parse(server.url().as_str()).unwrap();
         let outfile = "test.txt";
@@ -329,16 +225,58 @@ mod tests {
         fs::remove_file("test-2.txt").unwrap();
     }
 
+    #[tokio::test]
+    async fn test_fetch_result_rotate_back_side() {
+        let front = postprocess::test_png(&[0, 255], 2);
+        let back = postprocess::test_png(&[10, 20], 2);
+        let mut server = mockito::Server::new_async().await;
+        let _m1 = server
+            .mock("GET", "/NextDocument")
+            .with_status(200)
+            .with_body(&front)
+            .create_async()
+            .await;
+        let _m2 = server
+            .mock("GET", "/NextDocument")
+            .with_status(200)
+            .with_body(&back)
+            .create_async()
+            .await;
+        let _m3 = server
+            .mock("GET", "/NextDocument")
+            .with_status(404)
+            .create_async()
+            .await;
+
+        let url = Url::parse(server.url().as_str()).unwrap();
+        let options = FetchOptions {
+            rotate_back_side: true,
+            ..FetchOptions::default()
+        };
+        fetch_result_with_options(url, "test_duplex.png", true, &options)
+            .await
+            .unwrap();
+
+        let page1 = image::open("test_duplex-1.png").unwrap().into_luma8();
+        let page2 = image::open("test_duplex-2.png").unwrap().into_luma8();
+        assert_eq!(page1.into_raw(), vec![0, 255]);
+        assert_eq!(page2.into_raw(), vec![20, 10]);
+
+        fs::remove_file("test_duplex-1.png").unwrap();
+        fs::remove_file("test_duplex-2.png").unwrap();
+    }
+
     #[tokio::test]
     async fn test_fetch_result_404() {
-        let mut server = mockito::Server::new();
+        let mut server = mockito::Server::new_async().await;
         let _m = server
             .mock("GET", "/NextDocument")
             .with_status(404)
-            .create();
+            .create_async()
+            .await;
 
         let url = Url::parse(server.url().as_str()).unwrap();
-        let outfile = "test.txt";
+        let outfile = "test_404.txt";
         let multi = false;
 
         fetch_result(url, outfile, multi).await.unwrap();
@@ -347,13 +285,92 @@ mod tests {
     }
 
     #[tokio::test]
-    #[ignore]
+    async fn test_post_scanrequest_busy_with_empty_adf() {
+        let mut server = mockito::Server::new_async().await;
+        let _post = server
+            .mock("POST", "/ScanJobs")
+            .with_status(503)
+            .create_async()
+            .await;
+        let _status = server
+            .mock("GET", "/ScannerStatus")
+            .with_status(200)
+            .with_body(include_str!("../testdata/status/epson_empty_no_jobs.xml"))
+            .create_async()
+            .await;
+
+        let settings = ScanSettings::builder()
+            .input_source(InputSource::Feeder)
+            .document_format("image/jpeg")
+            .build();
+        let result = post_scanrequest(server.url().as_str(), &settings).await;
+
+        assert!(matches!(result, Err(AirscanError::AdfEmpty)));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_result_busy_aborted_job() {
+        let mut server = mockito::Server::new_async().await;
+        let _next = server
+            .mock("GET", "/eSCL/ScanJobs/1004/NextDocument")
+            .with_status(503)
+            .create_async()
+            .await;
+        let _status = server
+            .mock("GET", "/eSCL/ScannerStatus")
+            .with_status(200)
+            .with_body(include_str!("../testdata/status/canon_adf_jam.xml"))
+            .create_async()
+            .await;
+
+        let url = Url::parse(&format!("{}/eSCL/ScanJobs/1004/", server.url())).unwrap();
+        let result = fetch_result(url, "test_aborted.txt", false).await;
+
+        assert!(matches!(
+            result,
+            Err(AirscanError::JobFailed {
+                state: JobState::Aborted,
+                ..
+            })
+        ));
+        assert!(!Path::new("test_aborted.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_result_busy_completed_job() {
+        let mut server = mockito::Server::new_async().await;
+        let _next = server
+            .mock(
+                "GET",
+                "/eSCL/ScanJobs/b30a11f0-5834-11b2-8325-3c2af490c39a/NextDocument",
+            )
+            .with_status(503)
+            .create_async()
+            .await;
+        let _status = server
+            .mock("GET", "/eSCL/ScannerStatus")
+            .with_status(200)
+            .with_body(include_str!("../testdata/status/brother_idle_jobs.xml"))
+            .create_async()
+            .await;
+
+        let url = Url::parse(&format!(
+            "{}/eSCL/ScanJobs/b30a11f0-5834-11b2-8325-3c2af490c39a/",
+            server.url()
+        ))
+        .unwrap();
+
+        fetch_result(url, "test_completed.txt", true).await.unwrap();
+    }
+
+    #[tokio::test]
     async fn test_fetch_result_unexpected_error() {
-        let mut server = mockito::Server::new();
+        let mut server = mockito::Server::new_async().await;
         let _m = server
             .mock("GET", "/NextDocument")
             .with_status(500)
-            .create();
+            .create_async()
+            .await;
 
         let url = Url::parse(server.url().as_str()).unwrap();
         let outfile = "test.txt";
@@ -361,6 +378,9 @@ mod tests {
 
         let result = fetch_result(url, outfile, multi).await;
 
-        assert!(result.is_err());
+        assert!(matches!(
+            result,
+            Err(AirscanError::Http { status, .. }) if status == 500
+        ));
     }
 }
@@ -0,0 +1,593 @@
+//! Text recognition for scanned pages, see [`OcrEngine`].
+//!
+//! Recognized pages are embedded as an invisible text layer by [`crate::PdfAssembler`]
+//! and can be written as hOCR or plain text sidecars.
+
+use std::{
+    fmt::Debug,
+    io::{Cursor, Write},
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+use xml::{
+    common::XmlVersion,
+    reader::{EventReader, XmlEvent as ReadEvent},
+    writer::{EmitterConfig, XmlEvent},
+};
+
+use crate::error::{AirscanError, Result};
+
+const XHTML_NS: &str = "http://www.w3.org/1999/xhtml";
+/// Helvetica glyphs are about half as wide as the font size on average.
+const AVERAGE_GLYPH_WIDTH: f64 = 0.5;
+
+/// Recognizes the text on a JPEG or PNG page.
+pub trait OcrEngine: Debug + Send + Sync {
+    /// `resolution` is the scan resolution of `image` in dots per inch.
+    fn recognize(&self, image: &[u8], resolution: u32) -> Result<OcrPage>;
+}
+
+/// Pixel rectangle from the top left `(x0, y0)` to the bottom right `(x1, y1)`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BoundingBox {
+    pub x0: u32,
+    pub y0: u32,
+    pub x1: u32,
+    pub y1: u32,
+}
+
+impl BoundingBox {
+    pub fn new(x0: u32, y0: u32, x1: u32, y1: u32) -> Self {
+        BoundingBox { x0, y0, x1, y1 }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.x1.saturating_sub(self.x0)
+    }
+
+    pub fn height(&self) -> u32 {
+        self.y1.saturating_sub(self.y0)
+    }
+
+    /// Reads the `bbox x0 y0 x1 y1` property of an hOCR `title`.
+    fn from_title(title: &str) -> Option<Self> {
+        let bbox = title
+            .split(';')
+            .find_map(|property| property.trim().strip_prefix("bbox "))?;
+        let values: Vec<u32> = bbox
+            .split_whitespace()
+            .map(str::parse)
+            .collect::<std::result::Result<_, _>>()
+            .ok()?;
+        match values[..] {
+            [x0, y0, x1, y1] => Some(BoundingBox::new(x0, y0, x1, y1)),
+            _ => None,
+        }
+    }
+
+    fn to_title(self) -> String {
+        format!("bbox {} {} {} {}", self.x0, self.y0, self.x1, self.y1)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct OcrWord {
+    pub text: String,
+    pub bbox: BoundingBox,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct OcrLine {
+    pub bbox: BoundingBox,
+    pub words: Vec<OcrWord>,
+}
+
+/// Text recognized on a page of `width` x `height` pixels.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct OcrPage {
+    pub width: u32,
+    pub height: u32,
+    pub lines: Vec<OcrLine>,
+}
+
+impl OcrPage {
+    /// Reads the first page of an hOCR document.
+    pub fn from_hocr(hocr: &str) -> Result<Self> {
+        let mut page = OcrPage::default();
+        // Depth of the open elements, and the depth of the current word.
+        let mut depth = 0;
+        let mut word_depth = None;
+        let mut pages = 0;
+        for event in EventReader::new(Cursor::new(hocr)) {
+            match event.map_err(|error| AirscanError::Xml(error.to_string()))? {
+                ReadEvent::StartElement { attributes, .. } => {
+                    depth += 1;
+                    let attribute = |name: &str| {
+                        attributes
+                            .iter()
+                            .find(|attribute| attribute.name.local_name == name)
+                            .map(|attribute| attribute.value.as_str())
+                    };
+                    let bbox = attribute("title").and_then(BoundingBox::from_title);
+                    let classes = attribute("class").unwrap_or_default();
+                    for class in classes.split_whitespace() {
+                        match class {
+                            "ocr_page" => {
+                                pages += 1;
+                                let bbox = bbox.unwrap_or_default();
+                                (page.width, page.height) = (bbox.width(), bbox.height());
+                            }
+                            "ocr_line" | "ocr_header" | "ocr_caption" | "ocr_textfloat"
+                                if pages == 1 =>
+                            {
+                                page.lines.push(OcrLine {
+                                    bbox: bbox.unwrap_or_default(),
+                                    words: Vec::new(),
+                                });
+                            }
+                            "ocrx_word" if pages == 1 => {
+                                let Some(line) = page.lines.last_mut() else {
+                                    return Err(AirscanError::Ocr(String::from(
+                                        "hOCR word outside of a line",
+                                    )));
+                                };
+                                line.words.push(OcrWord {
+                                    text: String::new(),
+                                    bbox: bbox.unwrap_or_default(),
+                                });
+                                word_depth = Some(depth);
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                ReadEvent::Characters(text) if word_depth.is_some() => {
+                    if let Some(word) = page.lines.last_mut().and_then(|line| line.words.last_mut())
+                    {
+                        word.text.push_str(&text);
+                    }
+                }
+                ReadEvent::EndElement { .. } => {
+                    if word_depth == Some(depth) {
+                        word_depth = None;
+                    }
+                    depth -= 1;
+                }
+                _ => {}
+            }
+        }
+        if pages == 0 {
+            return Err(AirscanError::InvalidValue(String::from(
+                "hOCR document without ocr_page",
+            )));
+        }
+        for line in &mut page.lines {
+            line.words.retain(|word| !word.text.trim().is_empty());
+        }
+        page.lines.retain(|line| !line.words.is_empty());
+        Ok(page)
+    }
+
+    /// Lines of words separated by spaces.
+    pub fn text(&self) -> String {
+        self.lines
+            .iter()
+            .map(|line| {
+                let words: Vec<&str> = line.words.iter().map(|word| word.text.trim()).collect();
+                words.join(" ") + "\n"
+            })
+            .collect()
+    }
+
+    /// PDF content stream operators drawing the words invisibly over a page of
+    /// `width` x `height` points, with `font` as the resource name of Helvetica.
+    pub(crate) fn pdf_text_layer(&self, width: f64, height: f64, font: &str) -> String {
+        if self.width == 0 || self.height == 0 {
+            return String::new();
+        }
+        let scale_x = width / self.width as f64;
+        let scale_y = height / self.height as f64;
+        // Text render mode 3 neither fills nor strokes the glyphs.
+        let mut layer = String::from("BT 3 Tr\n");
+        for word in self.lines.iter().flat_map(|line| &line.words) {
+            let text = word.text.trim();
+            let size = word.bbox.height() as f64 * scale_y;
+            if text.is_empty() || size <= 0.0 {
+                continue;
+            }
+            // Stretch the text horizontally to cover the word on the page.
+            let natural_width = size * AVERAGE_GLYPH_WIDTH * text.chars().count() as f64;
+            let scaling = 100.0 * word.bbox.width() as f64 * scale_x / natural_width;
+            layer.push_str(&format!(
+                "/{} {:.2} Tf {:.2} Tz 1 0 0 1 {:.2} {:.2} Tm ({}) Tj\n",
+                font,
+                size,
+                scaling,
+                word.bbox.x0 as f64 * scale_x,
+                height - word.bbox.y1 as f64 * scale_y,
+                pdf_string(text)
+            ));
+        }
+        layer.push_str("ET");
+        layer
+    }
+}
+
+/// Escapes text for a PDF string in WinAnsiEncoding, characters it cannot encode become `?`.
+fn pdf_string(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '(' | ')' | '\\' => format!("\\{}", c),
+            ' '..='~' => c.to_string(),
+            '\u{A0}'..='\u{FF}' => format!("\\{:03o}", c as u32),
+            _ => String::from("?"),
+        })
+        .collect()
+}
+
+/// Plain text of the pages, separated by form feeds like tesseract does.
+pub fn plain_text(pages: &[OcrPage]) -> String {
+    let pages: Vec<String> = pages.iter().map(OcrPage::text).collect();
+    pages.join("\x0C")
+}
+
+/// hOCR document with one `ocr_page` per page.
+pub fn hocr(pages: &[OcrPage]) -> Result<String> {
+    let mut writer = EmitterConfig::new()
+        .perform_indent(true)
+        .create_writer(Vec::new());
+    writer.write(XmlEvent::StartDocument {
+        version: XmlVersion::Version10,
+        encoding: Some("UTF-8"),
+        standalone: None,
+    })?;
+    writer.write(XmlEvent::start_element("html").default_ns(XHTML_NS))?;
+    writer.write(XmlEvent::start_element("head"))?;
+    writer.write(XmlEvent::start_element("title"))?;
+    writer.write(XmlEvent::end_element())?;
+    writer.write(
+        XmlEvent::start_element("meta")
+            .attr("name", "ocr-system")
+            .attr(
+                "content",
+                concat!("airscan-rust ", env!("CARGO_PKG_VERSION")),
+            ),
+    )?;
+    writer.write(XmlEvent::end_element())?;
+    writer.write(
+        XmlEvent::start_element("meta")
+            .attr("name", "ocr-capabilities")
+            .attr("content", "ocr_page ocr_line ocrx_word"),
+    )?;
+    writer.write(XmlEvent::end_element())?;
+    writer.write(XmlEvent::end_element())?;
+    writer.write(XmlEvent::start_element("body"))?;
+
+    for (number, page) in (1..).zip(pages) {
+        let title = format!(
+            "{}; ppageno {}",
+            BoundingBox::new(0, 0, page.width, page.height).to_title(),
+            number - 1
+        );
+        let id = format!("page_{}", number);
+        writer.write(
+            XmlEvent::start_element("div")
+                .attr("class", "ocr_page")
+                .attr("id", &id)
+                .attr("title", &title),
+        )?;
+        for (line_number, line) in (1..).zip(&page.lines) {
+            let (id, title) = (
+                format!("line_{}_{}", number, line_number),
+                line.bbox.to_title(),
+            );
+            writer.write(
+                XmlEvent::start_element("span")
+                    .attr("class", "ocr_line")
+                    .attr("id", &id)
+                    .attr("title", &title),
+            )?;
+            for (word_number, word) in (1..).zip(&line.words) {
+                let (id, title) = (
+                    format!("word_{}_{}_{}", number, line_number, word_number),
+                    word.bbox.to_title(),
+                );
+                writer.write(
+                    XmlEvent::start_element("span")
+                        .attr("class", "ocrx_word")
+                        .attr("id", &id)
+                        .attr("title", &title),
+                )?;
+                writer.write(XmlEvent::characters(&word.text))?;
+                writer.write(XmlEvent::end_element())?;
+            }
+            writer.write(XmlEvent::end_element())?;
+        }
+        writer.write(XmlEvent::end_element())?;
+    }
+
+    writer.write(XmlEvent::end_element())?;
+    writer.write(XmlEvent::end_element())?;
+    String::from_utf8(writer.into_inner()).map_err(|error| AirscanError::Xml(error.to_string()))
+}
+
+/// Runs a local `tesseract` binary, version 4 or later, on each page.
+#[derive(Debug, Clone)]
+pub struct TesseractEngine {
+    binary: PathBuf,
+    language: String,
+}
+
+impl Default for TesseractEngine {
+    fn default() -> Self {
+        TesseractEngine {
+            binary: PathBuf::from("tesseract"),
+            language: String::from("eng"),
+        }
+    }
+}
+
+impl TesseractEngine {
+    pub fn new() -> Self {
+        TesseractEngine::default()
+    }
+
+    /// Path of the binary, `tesseract` from `PATH` by default.
+    pub fn with_binary(mut self, binary: impl Into<PathBuf>) -> Self {
+        self.binary = binary.into();
+        self
+    }
+
+    /// Tesseract language codes, several joined by `+`, e.g. `deu+eng`. Defaults to `eng`.
+    pub fn with_language(mut self, language: impl Into<String>) -> Self {
+        self.language = language.into();
+        self
+    }
+}
+
+impl OcrEngine for TesseractEngine {
+    fn recognize(&self, image: &[u8], resolution: u32) -> Result<OcrPage> {
+        let mut child = Command::new(&self.binary)
+            .args(["stdin", "stdout", "-l", &self.language, "--dpi"])
+            .arg(resolution.to_string())
+            .arg("hocr")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|error| {
+                AirscanError::Ocr(format!("cannot run {}: {}", self.binary.display(), error))
+            })?;
+        // Tesseract reads the whole image before it writes, but pipes have limited buffers.
+        let mut stdin = child
+            .stdin
+            .take()
+            .ok_or_else(|| AirscanError::Ocr(String::from("no stdin pipe to tesseract")))?;
+        let image = image.to_vec();
+        let writer = std::thread::spawn(move || stdin.write_all(&image));
+        let output = child.wait_with_output()?;
+        let written = writer.join().map_err(|_| {
+            AirscanError::Ocr(format!("writing to {} panicked", self.binary.display()))
+        })?;
+
+        if !output.status.success() {
+            return Err(AirscanError::Ocr(format!(
+                "{} exited with {}: {}",
+                self.binary.display(),
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        written?;
+        OcrPage::from_hocr(&String::from_utf8_lossy(&output.stdout))
+    }
+}
+
+/// Recognizes the same text on every page as one line across its middle, for tests
+/// without tesseract.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StubOcrEngine {
+    text: String,
+}
+
+impl StubOcrEngine {
+    pub fn new(text: impl Into<String>) -> Self {
+        StubOcrEngine { text: text.into() }
+    }
+}
+
+impl OcrEngine for StubOcrEngine {
+    fn recognize(&self, image: &[u8], _resolution: u32) -> Result<OcrPage> {
+        let (width, height) = image::ImageReader::new(Cursor::new(image))
+            .with_guessed_format()?
+            .into_dimensions()?;
+        let words: Vec<&str> = self.text.split_whitespace().collect();
+        let (top, bottom) = (height * 9 / 20, height * 11 / 20);
+        let slot = width / words.len().max(1) as u32;
+        let words: Vec<OcrWord> = (0..)
+            .zip(&words)
+            .map(|(i, text)| OcrWord {
+                text: text.to_string(),
+                bbox: BoundingBox::new(i * slot, top, (i + 1) * slot * 9 / 10, bottom),
+            })
+            .collect();
+        let lines = if words.is_empty() {
+            Vec::new()
+        } else {
+            vec![OcrLine {
+                bbox: BoundingBox::new(0, top, width, bottom),
+                words,
+            }]
+        };
+        Ok(OcrPage {
+            width,
+            height,
+            lines,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TESSERACT_HOCR: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE html PUBLIC "-//W3C//DTD XHTML 1.0 Transitional//EN"
+    "http://www.w3.org/TR/xhtml1/DTD/xhtml1-transitional.dtd">
+<html xmlns="http://www.w3.org/1999/xhtml" xml:lang="en" lang="en">
+ <head>
+  <title></title>
+  <meta http-equiv="Content-Type" content="text/html;charset=utf-8"/>
+  <meta name='ocr-system' content='tesseract 5.3.0' />
+ </head>
+ <body>
+  <div class='ocr_page' id='page_1' title='image "stdin"; bbox 0 0 2480 3508; ppageno 0'>
+   <div class='ocr_carea' id='block_1_1' title="bbox 296 270 1190 330">
+    <p class='ocr_par' id='par_1_1' lang='eng' title="bbox 296 270 1190 330">
+     <span class='ocr_line' id='line_1_1' title="bbox 296 270 1190 330; baseline 0 -12; x_size 60">
+      <span class='ocrx_word' id='word_1_1' title='bbox 296 270 640 330; x_wconf 96'>Invoice</span>
+      <span class='ocrx_word' id='word_1_2' title='bbox 680 272 1190 328; x_wconf 91'><strong>Nr.</strong> 42&amp;1</span>
+     </span>
+     <span class='ocr_header' id='line_1_2' title="bbox 296 400 700 450">
+      <span class='ocrx_word' id='word_1_3' title='bbox 296 400 700 450; x_wconf 88'>Müller</span>
+      <span class='ocrx_word' id='word_1_4' title='bbox 700 400 700 450; x_wconf 0'> </span>
+     </span>
+    </p>
+   </div>
+  </div>
+ </body>
+</html>
+"#;
+
+    fn invoice() -> OcrPage {
+        OcrPage::from_hocr(TESSERACT_HOCR).unwrap()
+    }
+
+    #[test]
+    fn read_tesseract_hocr() {
+        let page = invoice();
+
+        assert_eq!((page.width, page.height), (2480, 3508));
+        assert_eq!(page.lines.len(), 2);
+        assert_eq!(page.lines[0].bbox, BoundingBox::new(296, 270, 1190, 330));
+        assert_eq!(
+            page.lines[0].words[1],
+            OcrWord {
+                text: String::from("Nr. 42&1"),
+                bbox: BoundingBox::new(680, 272, 1190, 328),
+            }
+        );
+        assert_eq!(page.text(), "Invoice Nr. 42&1\nMüller\n");
+        assert!(OcrPage::from_hocr("<html><body/></html>").is_err());
+    }
+
+    #[test]
+    fn reject_words_outside_of_lines() {
+        let hocr = "<div class='ocr_page' title='bbox 0 0 100 100'>\
+                    <span class='ocrx_word' title='bbox 0 0 10 10'>stray</span></div>";
+
+        assert!(matches!(
+            OcrPage::from_hocr(hocr),
+            Err(AirscanError::Ocr(_))
+        ));
+    }
+
+    #[test]
+    fn write_hocr_and_text() {
+        let pages = [invoice(), OcrPage::default(), invoice()];
+
+        let hocr = hocr(&pages).unwrap();
+
+        assert!(
+            hocr.contains(r#"class="ocr_page" id="page_3" title="bbox 0 0 2480 3508; ppageno 2""#)
+        );
+        assert!(hocr.contains(">Nr. 42&amp;1</span>"));
+        assert_eq!(OcrPage::from_hocr(&hocr).unwrap(), pages[0]);
+        assert_eq!(
+            plain_text(&pages),
+            "Invoice Nr. 42&1\nMüller\n\x0C\x0CInvoice Nr. 42&1\nMüller\n"
+        );
+    }
+
+    #[test]
+    fn text_layer_covers_words() {
+        let page = OcrPage {
+            width: 600,
+            height: 300,
+            lines: vec![OcrLine {
+                bbox: BoundingBox::new(100, 100, 300, 150),
+                words: vec![OcrWord {
+                    text: String::from("f(ü)"),
+                    bbox: BoundingBox::new(100, 100, 300, 150),
+                }],
+            }],
+        };
+
+        // 600x300 pixels at 300 dpi.
+        let layer = page.pdf_text_layer(144.0, 72.0, "F0");
+
+        assert_eq!(
+            layer,
+            "BT 3 Tr\n/F0 12.00 Tf 200.00 Tz 1 0 0 1 24.00 36.00 Tm (f\\(\\374\\)) Tj\nET"
+        );
+        assert_eq!(pdf_string("€"), "?");
+    }
+
+    #[test]
+    fn stub_engine_spreads_words() {
+        let png = crate::postprocess::test_png(&[255; 200 * 40], 200);
+
+        let page = StubOcrEngine::new("two words")
+            .recognize(&png, 300)
+            .unwrap();
+
+        assert_eq!((page.width, page.height), (200, 40));
+        assert_eq!(page.text(), "two words\n");
+        assert_eq!(
+            page.lines[0].words[1].bbox,
+            BoundingBox::new(100, 18, 180, 22)
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn run_tesseract_binary() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("airscan-tesseract-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let hocr = dir.join("page.hocr");
+        std::fs::write(&hocr, TESSERACT_HOCR).unwrap();
+        // Stands in for tesseract, recording its arguments and input.
+        let binary = dir.join("tesseract");
+        std::fs::write(
+            &binary,
+            format!(
+                "#!/bin/sh\necho \"$@\" > {0}/args\ncat > {0}/input\ncat {1}\n",
+                dir.display(),
+                hocr.display()
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&binary, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let engine = TesseractEngine::new()
+            .with_binary(&binary)
+            .with_language("deu+eng");
+        let page = engine.recognize(b"image data", 300).unwrap();
+
+        assert_eq!(page, invoice());
+        assert_eq!(
+            std::fs::read_to_string(dir.join("args")).unwrap(),
+            "stdin stdout -l deu+eng --dpi 300 hocr\n"
+        );
+        assert_eq!(std::fs::read(dir.join("input")).unwrap(), b"image data");
+        let missing = TesseractEngine::new().with_binary(dir.join("missing"));
+        assert!(matches!(
+            missing.recognize(b"", 300),
+            Err(AirscanError::Ocr(_))
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,494 @@
+#[cfg(feature = "ocr")]
+use std::sync::Arc;
+use std::{io::Write, path::PathBuf};
+
+use bytes::Bytes;
+use reqwest::Url;
+
+#[cfg(feature = "ocr")]
+use crate::{
+    error::AirscanError,
+    ocr::{self, OcrEngine, OcrPage},
+};
+use crate::{
+    error::Result,
+    jpeg::{jpeg_info, JpegInfo},
+    sink::{PageMetadata, PageSink},
+};
+
+const POINTS_PER_INCH: f64 = 72.0;
+
+/// Builds a PDF with one page per JPEG, embedding the JPEG data as is.
+/// Page sizes follow from the pixel size and the scan resolution. With the `ocr` feature,
+/// pages can carry an invisible text layer to make the PDF searchable.
+#[derive(Debug, Clone)]
+pub struct PdfAssembler {
+    x_resolution: u32,
+    y_resolution: u32,
+    pages: Vec<JpegPage>,
+}
+
+#[derive(Debug, Clone)]
+struct JpegPage {
+    data: Bytes,
+    info: JpegInfo,
+    #[cfg(feature = "ocr")]
+    text: Option<OcrPage>,
+}
+
+impl PdfAssembler {
+    /// Resolution of the scanned pages in dots per inch.
+    pub fn new(x_resolution: u32, y_resolution: u32) -> Self {
+        PdfAssembler {
+            x_resolution: x_resolution.max(1),
+            y_resolution: y_resolution.max(1),
+            pages: Vec::new(),
+        }
+    }
+
+    pub fn add_jpeg(&mut self, jpeg: impl Into<Bytes>) -> Result<()> {
+        let data = jpeg.into();
+        let info = jpeg_info(&data)?;
+        self.pages.push(JpegPage {
+            data,
+            info,
+            #[cfg(feature = "ocr")]
+            text: None,
+        });
+        Ok(())
+    }
+
+    /// Adds a page with the text recognized on it as invisible text layer.
+    #[cfg(feature = "ocr")]
+    pub fn add_jpeg_with_text(&mut self, jpeg: impl Into<Bytes>, text: OcrPage) -> Result<()> {
+        self.add_jpeg(jpeg)?;
+        if let Some(page) = self.pages.last_mut() {
+            page.text = Some(text);
+        }
+        Ok(())
+    }
+
+    pub fn page_count(&self) -> usize {
+        self.pages.len()
+    }
+
+    pub fn to_pdf(&self) -> Vec<u8> {
+        let mut pdf = PdfWriter::default();
+        // Objects 1 and 2 are the catalog and the page tree, each page uses three more.
+        let page_ids: Vec<usize> = (0..self.pages.len()).map(|i| 4 + 3 * i).collect();
+        // Text layers share one font after the pages.
+        let font_id = 4 + 3 * self.pages.len();
+        if self.pages.iter().any(JpegPage::has_text) {
+            pdf.object(
+                font_id,
+                b"<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica \
+                  /Encoding /WinAnsiEncoding >>",
+            );
+        }
+
+        pdf.object(1, b"<< /Type /Catalog /Pages 2 0 R >>");
+        let kids: Vec<String> = page_ids.iter().map(|id| format!("{} 0 R", id)).collect();
+        pdf.object(
+            2,
+            format!(
+                "<< /Type /Pages /Kids [{}] /Count {} >>",
+                kids.join(" "),
+                page_ids.len()
+            )
+            .as_bytes(),
+        );
+        pdf.object(
+            3,
+            concat!(
+                "<< /Producer (airscan-rust ",
+                env!("CARGO_PKG_VERSION"),
+                ") >>"
+            )
+            .as_bytes(),
+        );
+
+        for (page, id) in self.pages.iter().zip(page_ids) {
+            let width = points(page.info.width, self.x_resolution);
+            let height = points(page.info.height, self.y_resolution);
+            let (contents_id, image_id) = (id + 1, id + 2);
+            let (fonts, text) = page.text_layer(width, height, font_id);
+            let contents = format!("q {:.2} 0 0 {:.2} 0 0 cm /Im0 Do Q{}", width, height, text);
+
+            pdf.object(
+                id,
+                format!(
+                    "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {:.2} {:.2}] \
+                     /Resources << /XObject << /Im0 {} 0 R >>{} >> /Contents {} 0 R >>",
+                    width, height, image_id, fonts, contents_id
+                )
+                .as_bytes(),
+            );
+            pdf.stream(contents_id, "", contents.as_bytes());
+            let (color_space, decode) = match page.info.components {
+                1 => ("/DeviceGray", ""),
+                // Adobe CMYK JPEGs are stored inverted.
+                4 => ("/DeviceCMYK", " /Decode [1 0 1 0 1 0 1 0]"),
+                _ => ("/DeviceRGB", ""),
+            };
+            pdf.stream(
+                image_id,
+                &format!(
+                    "/Type /XObject /Subtype /Image /Width {} /Height {} /ColorSpace {} \
+                     /BitsPerComponent 8 /Filter /DCTDecode{}",
+                    page.info.width, page.info.height, color_space, decode
+                ),
+                &page.data,
+            );
+        }
+
+        pdf.finish(1, 3)
+    }
+}
+
+impl JpegPage {
+    #[cfg(feature = "ocr")]
+    fn has_text(&self) -> bool {
+        self.text.is_some()
+    }
+
+    #[cfg(not(feature = "ocr"))]
+    fn has_text(&self) -> bool {
+        false
+    }
+
+    /// Font resources and content stream of the text layer on a page of `width` x `height`
+    /// points, empty without text.
+    #[cfg(feature = "ocr")]
+    fn text_layer(&self, width: f64, height: f64, font_id: usize) -> (String, String) {
+        match &self.text {
+            Some(text) => (
+                format!(" /Font << /F0 {} 0 R >>", font_id),
+                format!("\n{}", text.pdf_text_layer(width, height, "F0")),
+            ),
+            None => Default::default(),
+        }
+    }
+
+    #[cfg(not(feature = "ocr"))]
+    fn text_layer(&self, _width: f64, _height: f64, _font_id: usize) -> (String, String) {
+        Default::default()
+    }
+}
+
+/// Size in PDF points of `pixels` scanned at `resolution` dpi.
+fn points(pixels: u32, resolution: u32) -> f64 {
+    pixels as f64 * POINTS_PER_INCH / resolution as f64
+}
+
+#[derive(Debug, Default)]
+struct PdfWriter {
+    out: Vec<u8>,
+    offsets: Vec<(usize, usize)>,
+}
+
+impl PdfWriter {
+    fn header(&mut self) {
+        if self.out.is_empty() {
+            // The binary comment marks the file as binary for transfer programs.
+            self.out.extend_from_slice(b"%PDF-1.4\n%\xE2\xE3\xCF\xD3\n");
+        }
+    }
+
+    fn object(&mut self, id: usize, body: &[u8]) {
+        self.header();
+        self.offsets.push((id, self.out.len()));
+        let _ = writeln!(self.out, "{} 0 obj", id);
+        self.out.extend_from_slice(body);
+        self.out.extend_from_slice(b"\nendobj\n");
+    }
+
+    fn stream(&mut self, id: usize, dictionary: &str, data: &[u8]) {
+        let mut entries = vec![format!("/Length {}", data.len())];
+        if !dictionary.is_empty() {
+            entries.insert(0, dictionary.to_string());
+        }
+        let mut body = format!("<< {} >>\nstream\n", entries.join(" ")).into_bytes();
+        body.extend_from_slice(data);
+        body.extend_from_slice(b"\nendstream");
+        self.object(id, &body);
+    }
+
+    fn finish(mut self, root: usize, info: usize) -> Vec<u8> {
+        self.header();
+        self.offsets.sort();
+        let size = self.offsets.last().map_or(0, |(id, _)| id + 1);
+        let xref = self.out.len();
+        let _ = write!(self.out, "xref\n0 {}\n0000000000 65535 f \n", size);
+        let mut offsets = self.offsets.iter().peekable();
+        for id in 1..size {
+            match offsets.next_if(|(object, _)| *object == id) {
+                Some((_, offset)) => {
+                    let _ = writeln!(self.out, "{:010} 00000 n ", offset);
+                }
+                None => self.out.extend_from_slice(b"0000000000 65535 f \n"),
+            }
+        }
+        let _ = write!(
+            self.out,
+            "trailer\n<< /Size {} /Root {} 0 R /Info {} 0 R >>\nstartxref\n{}\n%%EOF\n",
+            size, root, info, xref
+        );
+        self.out
+    }
+}
+
+/// Collects the JPEG pages of a job and writes them as one PDF to `path` when the job ends.
+#[derive(Debug, Clone)]
+pub struct PdfSink {
+    path: PathBuf,
+    assembler: PdfAssembler,
+    #[cfg(feature = "ocr")]
+    ocr: OcrStage,
+}
+
+/// Text recognition of a [`PdfSink`].
+#[cfg(feature = "ocr")]
+#[derive(Debug, Clone, Default)]
+struct OcrStage {
+    engine: Option<Arc<dyn OcrEngine>>,
+    text_sidecar: Option<PathBuf>,
+    hocr_sidecar: Option<PathBuf>,
+}
+
+impl PdfSink {
+    pub fn new(path: impl Into<PathBuf>, x_resolution: u32, y_resolution: u32) -> Self {
+        PdfSink {
+            path: path.into(),
+            assembler: PdfAssembler::new(x_resolution, y_resolution),
+            #[cfg(feature = "ocr")]
+            ocr: OcrStage::default(),
+        }
+    }
+
+    /// Runs `engine` on each page and adds the text as invisible layer.
+    #[cfg(feature = "ocr")]
+    pub fn with_ocr(mut self, engine: impl OcrEngine + 'static) -> Self {
+        self.ocr.engine = Some(Arc::new(engine));
+        self
+    }
+
+    /// Also writes the recognized text to `path`, pages separated by form feeds.
+    #[cfg(feature = "ocr")]
+    pub fn with_text_sidecar(mut self, path: impl Into<PathBuf>) -> Self {
+        self.ocr.text_sidecar = Some(path.into());
+        self
+    }
+
+    /// Also writes the recognized text with its positions as hOCR to `path`.
+    #[cfg(feature = "ocr")]
+    pub fn with_hocr_sidecar(mut self, path: impl Into<PathBuf>) -> Self {
+        self.ocr.hocr_sidecar = Some(path.into());
+        self
+    }
+
+    #[cfg(feature = "ocr")]
+    async fn add_page(&mut self, content: Bytes) -> Result<()> {
+        let Some(engine) = self.ocr.engine.clone() else {
+            return self.assembler.add_jpeg(content);
+        };
+        jpeg_info(&content)?;
+        let resolution = self.assembler.x_resolution;
+        let image = content.clone();
+        // Recognition takes seconds per page, off the async runtime.
+        let text = tokio::task::spawn_blocking(move || engine.recognize(&image, resolution))
+            .await
+            .map_err(|error| AirscanError::Ocr(error.to_string()))??;
+        self.assembler.add_jpeg_with_text(content, text)
+    }
+
+    #[cfg(not(feature = "ocr"))]
+    async fn add_page(&mut self, content: Bytes) -> Result<()> {
+        self.assembler.add_jpeg(content)
+    }
+
+    #[cfg(feature = "ocr")]
+    async fn write_sidecars(&self) -> Result<()> {
+        let pages: Vec<OcrPage> = self
+            .assembler
+            .pages
+            .iter()
+            .map(|page| page.text.clone().unwrap_or_default())
+            .collect();
+        if let Some(path) = &self.ocr.text_sidecar {
+            tokio::fs::write(path, ocr::plain_text(&pages)).await?;
+        }
+        if let Some(path) = &self.ocr.hocr_sidecar {
+            tokio::fs::write(path, ocr::hocr(&pages)?).await?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "ocr"))]
+    async fn write_sidecars(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl PageSink for PdfSink {
+    async fn begin_job(&mut self, _location: &Url) -> Result<()> {
+        self.assembler.pages.clear();
+        Ok(())
+    }
+
+    async fn page(&mut self, content: Bytes, _metadata: &PageMetadata) -> Result<()> {
+        self.add_page(content).await
+    }
+
+    async fn end_job(&mut self) -> Result<()> {
+        tokio::fs::write(&self.path, self.assembler.to_pdf()).await?;
+        self.write_sidecars().await?;
+        self.assembler.pages.clear();
+        Ok(())
+    }
+
+    async fn abort(&mut self) -> Result<()> {
+        self.assembler.pages.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{codecs::jpeg::JpegEncoder, DynamicImage, ExtendedColorType, GrayImage, RgbImage};
+
+    use super::*;
+
+    fn jpeg(image: DynamicImage) -> Vec<u8> {
+        let color_type = match image {
+            DynamicImage::ImageLuma8(_) => ExtendedColorType::L8,
+            _ => ExtendedColorType::Rgb8,
+        };
+        let mut out = Cursor::new(Vec::new());
+        JpegEncoder::new(&mut out)
+            .encode(image.as_bytes(), image.width(), image.height(), color_type)
+            .unwrap();
+        out.into_inner()
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack
+            .windows(needle.len())
+            .any(|window| window == needle)
+    }
+
+    #[test]
+    fn assemble_pages() {
+        let first = jpeg(DynamicImage::ImageLuma8(GrayImage::new(600, 300)));
+        let second = jpeg(DynamicImage::ImageRgb8(RgbImage::new(2550, 3300)));
+        let mut assembler = PdfAssembler::new(300, 300);
+        assembler.add_jpeg(first.clone()).unwrap();
+        assembler.add_jpeg(second.clone()).unwrap();
+
+        let pdf = assembler.to_pdf();
+
+        assert!(pdf.starts_with(b"%PDF-1.4\n"));
+        assert!(pdf.ends_with(b"%%EOF\n"));
+        assert!(contains(&pdf, b"/Count 2"));
+        assert!(contains(&pdf, b"/MediaBox [0 0 144.00 72.00]"));
+        assert!(contains(&pdf, b"/MediaBox [0 0 612.00 792.00]"));
+        assert!(contains(&pdf, b"/ColorSpace /DeviceGray"));
+        assert!(contains(&pdf, b"/ColorSpace /DeviceRGB"));
+        assert!(contains(&pdf, &first));
+        assert!(contains(&pdf, &second));
+    }
+
+    #[test]
+    fn xref_points_to_objects() {
+        let mut assembler = PdfAssembler::new(200, 100);
+        assembler
+            .add_jpeg(jpeg(DynamicImage::ImageLuma8(GrayImage::new(8, 8))))
+            .unwrap();
+        let pdf = assembler.to_pdf();
+        let text = String::from_utf8_lossy(&pdf);
+
+        let startxref: usize = text
+            .rsplit("startxref\n")
+            .next()
+            .and_then(|tail| tail.lines().next())
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(pdf[startxref..].starts_with(b"xref\n0 7\n"));
+        let xref = std::str::from_utf8(&pdf[startxref..]).unwrap();
+        let entries = xref.lines().skip(3).take(6);
+        for (id, entry) in (1..).zip(entries) {
+            let offset: usize = entry[..10].parse().unwrap();
+            assert!(pdf[offset..].starts_with(format!("{} 0 obj\n", id).as_bytes()));
+        }
+        assert!(contains(&pdf, b"/MediaBox [0 0 2.88 5.76]"));
+    }
+
+    #[tokio::test]
+    async fn pdf_sink_writes_file() {
+        let path = std::env::temp_dir().join(format!("airscan-sink-{}.pdf", std::process::id()));
+        let mut sink = PdfSink::new(&path, 300, 300);
+        let location = Url::parse("http://127.0.0.1/eSCL/ScanJobs/1/").unwrap();
+        let metadata = PageMetadata {
+            number: 1,
+            content_type: Some(String::from("image/jpeg")),
+        };
+
+        sink.begin_job(&location).await.unwrap();
+        let page = jpeg(DynamicImage::ImageLuma8(GrayImage::new(30, 30)));
+        sink.page(Bytes::from(page), &metadata).await.unwrap();
+        assert!(sink
+            .page(Bytes::from_static(b"%PDF-1.4"), &metadata)
+            .await
+            .is_err());
+        sink.end_job().await.unwrap();
+
+        let pdf = std::fs::read(&path).unwrap();
+        assert!(contains(&pdf, b"/Count 1"));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(feature = "ocr")]
+    #[tokio::test]
+    async fn pdf_sink_adds_text_layer() {
+        let dir = std::env::temp_dir().join(format!("airscan-ocr-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut sink = PdfSink::new(dir.join("scan.pdf"), 100, 100)
+            .with_ocr(crate::ocr::StubOcrEngine::new("Hello (world)"))
+            .with_text_sidecar(dir.join("scan.txt"))
+            .with_hocr_sidecar(dir.join("scan.hocr"));
+        let location = Url::parse("http://127.0.0.1/eSCL/ScanJobs/1/").unwrap();
+        let metadata = PageMetadata {
+            number: 1,
+            content_type: Some(String::from("image/jpeg")),
+        };
+
+        sink.begin_job(&location).await.unwrap();
+        for _ in 0..2 {
+            let page = jpeg(DynamicImage::ImageLuma8(GrayImage::new(200, 100)));
+            sink.page(Bytes::from(page), &metadata).await.unwrap();
+        }
+        sink.end_job().await.unwrap();
+
+        let pdf = std::fs::read(dir.join("scan.pdf")).unwrap();
+        assert!(contains(
+            &pdf,
+            b"10 0 obj\n<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica"
+        ));
+        assert!(contains(
+            &pdf,
+            b"/XObject << /Im0 6 0 R >> /Font << /F0 10 0 R >>"
+        ));
+        assert!(contains(&pdf, b"BT 3 Tr\n/F0 7.20 Tf"));
+        assert!(contains(&pdf, b"(\\(world\\)) Tj"));
+        assert!(contains(&pdf, b"trailer\n<< /Size 11 "));
+        assert_eq!(
+            std::fs::read_to_string(dir.join("scan.txt")).unwrap(),
+            "Hello (world)\n\x0CHello (world)\n"
+        );
+        let hocr = std::fs::read_to_string(dir.join("scan.hocr")).unwrap();
+        assert!(hocr.contains(r#"id="word_2_1_2" title="bbox 100 45 180 55">(world)</span>"#));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
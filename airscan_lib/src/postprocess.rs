@@ -0,0 +1,421 @@
+use std::io::Cursor;
+
+use image::{codecs::jpeg::JpegEncoder, DynamicImage, GrayImage, ImageBuffer, ImageFormat, Pixel};
+
+use crate::error::Result;
+
+const JPEG_QUALITY: u8 = 95;
+/// Gray values below count as ink.
+const INK_LEVEL: u8 = 160;
+/// Share of each edge left out of the ink coverage, where scanners leave shadows.
+const MARGIN_PERCENT: u32 = 3;
+
+/// Gray values above count as paper when looking for the page edges.
+const PAPER_LEVEL: u8 = 128;
+/// Scan lines sampled along each side of the page when looking for its edge.
+const EDGE_SAMPLES: u32 = 200;
+/// Edges found on fewer scan lines are ignored.
+const MIN_EDGE_POINTS: usize = 10;
+/// Larger angles are not taken for skew.
+const MAX_SKEW_DEGREES: f64 = 10.0;
+/// Smaller angles are not worth resampling the page.
+const MIN_SKEW_DEGREES: f64 = 0.05;
+
+/// Pages with less ink coverage are blank by default, see [`ink_coverage`].
+pub const DEFAULT_BLANK_THRESHOLD: f64 = 0.001;
+
+/// Returns true for page content that can be decoded as JPEG or PNG.
+pub fn is_image(content: &[u8]) -> bool {
+    matches!(
+        image::guess_format(content),
+        Ok(ImageFormat::Jpeg | ImageFormat::Png)
+    )
+}
+
+/// Processing steps for a JPEG or PNG page, applied in one decode and encode
+/// in the order rotation, deskew, crop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PageTransform {
+    pub rotate_180: bool,
+    /// Straighten the page along its edges, see [`deskew`].
+    pub deskew: bool,
+    /// Cut off the scan area around the page, see [`crop_to_page`].
+    pub crop: bool,
+}
+
+impl PageTransform {
+    pub fn is_identity(&self) -> bool {
+        !(self.rotate_180 || self.deskew || self.crop)
+    }
+
+    /// Applies the steps to a page, keeping its format.
+    pub fn apply(&self, content: &[u8]) -> Result<Vec<u8>> {
+        let format = image::guess_format(content)?;
+        let mut image = image::load_from_memory_with_format(content, format)?;
+        if self.rotate_180 {
+            image = image.rotate180();
+        }
+        if self.deskew {
+            image = deskew(&image);
+        }
+        if self.crop {
+            image = crop_to_page(&image);
+        }
+        encode(&image, format)
+    }
+}
+
+/// Rotates a JPEG or PNG page by 180°, keeping its format.
+pub fn rotate_180(content: &[u8]) -> Result<Vec<u8>> {
+    PageTransform {
+        rotate_180: true,
+        ..PageTransform::default()
+    }
+    .apply(content)
+}
+
+/// Angle in degrees by which the page is rotated clockwise within the scan, estimated from
+/// its edges against a darker background. `None` if no edge stands out.
+pub fn detect_skew(image: &GrayImage) -> Option<f64> {
+    let (width, height) = image.dimensions();
+    let is_paper = |x: u32, y: u32| image.get_pixel(x, y).0[0] > PAPER_LEVEL;
+    let columns: Vec<u32> = (0..EDGE_SAMPLES)
+        .map(|i| i * width / EDGE_SAMPLES)
+        .collect();
+    let rows: Vec<u32> = (0..EDGE_SAMPLES)
+        .map(|i| i * height / EDGE_SAMPLES)
+        .collect();
+    // Points on an edge as (position along the edge, distance from the border). Scan lines
+    // where the page touches the border of the scan do not show the edge.
+    let edge = |lines: &[u32], find: &dyn Fn(u32) -> Option<u32>, limit: u32| -> Vec<(f64, f64)> {
+        lines
+            .iter()
+            .filter_map(|&line| {
+                let found = find(line)?;
+                (found > 0 && found < limit).then_some((line as f64, found as f64))
+            })
+            .collect()
+    };
+
+    let top = edge(
+        &columns,
+        &|x| (0..height / 3).find(|&y| is_paper(x, y)),
+        height,
+    );
+    let bottom = edge(
+        &columns,
+        &|x| {
+            (height * 2 / 3..height)
+                .rev()
+                .find(|&y| is_paper(x, y))
+                .map(|y| height - 1 - y)
+        },
+        height,
+    );
+    let left = edge(&rows, &|y| (0..width / 3).find(|&x| is_paper(x, y)), width);
+    let right = edge(
+        &rows,
+        &|y| {
+            (width * 2 / 3..width)
+                .rev()
+                .find(|&x| is_paper(x, y))
+                .map(|x| width - 1 - x)
+        },
+        width,
+    );
+
+    // A clockwise rotation moves the top edge down to the right and the left edge left
+    // towards the bottom, and the opposite edges the other way.
+    let mut angles: Vec<f64> = [(top, 1.0), (bottom, -1.0), (left, -1.0), (right, 1.0)]
+        .into_iter()
+        .filter_map(|(points, sign)| edge_slope(&points).map(|slope| sign * slope.atan()))
+        .map(f64::to_degrees)
+        .filter(|angle| angle.abs() <= MAX_SKEW_DEGREES)
+        .collect();
+    if angles.is_empty() {
+        return None;
+    }
+    angles.sort_by(f64::total_cmp);
+    Some(angles[angles.len() / 2])
+}
+
+/// Slope of a straight edge through most of `points`, `None` if they do not form one.
+fn edge_slope(points: &[(f64, f64)]) -> Option<f64> {
+    if points.len() < MIN_EDGE_POINTS {
+        return None;
+    }
+    // Median of the slopes between points half the edge apart, robust against outliers.
+    let half = points.len() / 2;
+    let mut slopes: Vec<f64> = (0..half)
+        .map(|i| {
+            let (a, b) = (points[i], points[i + half]);
+            (b.1 - a.1) / (b.0 - a.0)
+        })
+        .collect();
+    slopes.sort_by(f64::total_cmp);
+    let slope = slopes[slopes.len() / 2];
+
+    let mut offsets: Vec<f64> = points.iter().map(|(x, y)| y - slope * x).collect();
+    offsets.sort_by(f64::total_cmp);
+    let offset = offsets[offsets.len() / 2];
+    let on_edge = points
+        .iter()
+        .filter(|(x, y)| (y - slope * x - offset).abs() <= 2.0)
+        .count();
+    (on_edge * 2 >= points.len()).then_some(slope)
+}
+
+/// Rotates the page back by its [`detect_skew`] angle. Uncovered corners get the color
+/// of the top left corner of the scan, usually the background.
+pub fn deskew(image: &DynamicImage) -> DynamicImage {
+    let angle = match detect_skew(&image.to_luma8()) {
+        Some(angle) if angle.abs() >= MIN_SKEW_DEGREES => angle,
+        _ => return image.clone(),
+    };
+    match image {
+        DynamicImage::ImageLuma8(gray) => {
+            DynamicImage::ImageLuma8(rotate(gray, -angle, *gray.get_pixel(0, 0)))
+        }
+        image => {
+            let rgb = image.to_rgb8();
+            DynamicImage::ImageRgb8(rotate(&rgb, -angle, *rgb.get_pixel(0, 0)))
+        }
+    }
+}
+
+/// Rotates clockwise by `degrees` around the center, with bilinear interpolation.
+fn rotate<P>(image: &ImageBuffer<P, Vec<u8>>, degrees: f64, fill: P) -> ImageBuffer<P, Vec<u8>>
+where
+    P: Pixel<Subpixel = u8>,
+{
+    let (width, height) = image.dimensions();
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    let (center_x, center_y) = ((width as f64 - 1.0) / 2.0, (height as f64 - 1.0) / 2.0);
+
+    ImageBuffer::from_fn(width, height, |x, y| {
+        let (dx, dy) = (x as f64 - center_x, y as f64 - center_y);
+        let source_x = center_x + dx * cos + dy * sin;
+        let source_y = center_y - dx * sin + dy * cos;
+        if source_x < 0.0
+            || source_y < 0.0
+            || source_x > (width - 1) as f64
+            || source_y > (height - 1) as f64
+        {
+            return fill;
+        }
+
+        let (x0, y0) = (source_x.floor() as u32, source_y.floor() as u32);
+        let (x1, y1) = ((x0 + 1).min(width - 1), (y0 + 1).min(height - 1));
+        let (fx, fy) = (source_x - x0 as f64, source_y - y0 as f64);
+        let corners = [
+            (image.get_pixel(x0, y0), (1.0 - fx) * (1.0 - fy)),
+            (image.get_pixel(x1, y0), fx * (1.0 - fy)),
+            (image.get_pixel(x0, y1), (1.0 - fx) * fy),
+            (image.get_pixel(x1, y1), fx * fy),
+        ];
+        let mut pixel = *corners[0].0;
+        for (channel, value) in pixel.channels_mut().iter_mut().enumerate() {
+            let sum: f64 = corners
+                .iter()
+                .map(|(corner, weight)| corner.channels()[channel] as f64 * weight)
+                .sum();
+            *value = sum.round() as u8;
+        }
+        pixel
+    })
+}
+
+/// Cuts the page out of a scan with a darker background. Rows and columns of the page
+/// are those with at least half as much paper as the fullest one.
+pub fn crop_to_page(image: &DynamicImage) -> DynamicImage {
+    let gray = image.to_luma8();
+    let (width, height) = gray.dimensions();
+    let is_paper = |x: u32, y: u32| gray.get_pixel(x, y).0[0] > PAPER_LEVEL;
+    let rows: Vec<u32> = (0..height)
+        .map(|y| (0..width).filter(|&x| is_paper(x, y)).count() as u32)
+        .collect();
+    let columns: Vec<u32> = (0..width)
+        .map(|x| (0..height).filter(|&y| is_paper(x, y)).count() as u32)
+        .collect();
+
+    let (Some((top, bottom)), Some((left, right))) = (page_span(&rows), page_span(&columns)) else {
+        return image.clone();
+    };
+    image.crop_imm(left, top, right - left + 1, bottom - top + 1)
+}
+
+/// First and last index with at least half the maximum count.
+fn page_span(counts: &[u32]) -> Option<(u32, u32)> {
+    let max = counts.iter().copied().max().filter(|&max| max > 0)?;
+    let first = counts.iter().position(|&count| count * 2 >= max)?;
+    let last = counts.iter().rposition(|&count| count * 2 >= max)?;
+    Some((first as u32, last as u32))
+}
+
+/// Share of dark pixels on a JPEG or PNG page, from 0.0 for an empty page to 1.0.
+pub fn ink_coverage(content: &[u8]) -> Result<f64> {
+    let image = image::load_from_memory(content)?.into_luma8();
+    let (width, height) = image.dimensions();
+    let (margin_x, margin_y) = (width * MARGIN_PERCENT / 100, height * MARGIN_PERCENT / 100);
+    let area = (width - 2 * margin_x) as u64 * (height - 2 * margin_y) as u64;
+    if area == 0 {
+        return Ok(0.0);
+    }
+
+    let ink = (margin_y..height - margin_y)
+        .flat_map(|y| (margin_x..width - margin_x).map(move |x| (x, y)))
+        .filter(|&(x, y)| image.get_pixel(x, y).0[0] < INK_LEVEL)
+        .count();
+    Ok(ink as f64 / area as f64)
+}
+
+/// Returns true if a JPEG or PNG page has less ink coverage than `threshold`.
+pub fn is_blank(content: &[u8], threshold: f64) -> Result<bool> {
+    Ok(ink_coverage(content)? < threshold)
+}
+
+pub(crate) fn encode(image: &DynamicImage, format: ImageFormat) -> Result<Vec<u8>> {
+    let mut out = Cursor::new(Vec::new());
+    match format {
+        ImageFormat::Jpeg => {
+            JpegEncoder::new_with_quality(&mut out, JPEG_QUALITY).encode_image(image)?
+        }
+        _ => image.write_to(&mut out, format)?,
+    }
+    Ok(out.into_inner())
+}
+
+#[cfg(test)]
+pub(crate) fn test_png(pixels: &[u8], width: u32) -> Vec<u8> {
+    let height = pixels.len() as u32 / width;
+    let image = image::GrayImage::from_raw(width, height, pixels.to_vec()).unwrap();
+    encode(&DynamicImage::ImageLuma8(image), ImageFormat::Png).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotate_png() {
+        let png = test_png(&[0, 50, 100, 150, 200, 250], 3);
+
+        let rotated = image::load_from_memory(&rotate_180(&png).unwrap())
+            .unwrap()
+            .into_luma8();
+
+        assert_eq!(rotated.dimensions(), (3, 2));
+        assert_eq!(rotated.into_raw(), vec![250, 200, 150, 100, 50, 0]);
+    }
+
+    #[test]
+    fn rotate_jpeg_keeps_format() {
+        let image = DynamicImage::ImageLuma8(image::GrayImage::from_fn(16, 8, |x, _| {
+            image::Luma([if x < 8 { 0 } else { 255 }])
+        }));
+        let jpeg = encode(&image, ImageFormat::Jpeg).unwrap();
+
+        let rotated = rotate_180(&jpeg).unwrap();
+
+        assert_eq!(image::guess_format(&rotated).unwrap(), ImageFormat::Jpeg);
+        let rotated = image::load_from_memory(&rotated).unwrap().into_luma8();
+        assert!(rotated.get_pixel(1, 4).0[0] > 200);
+        assert!(rotated.get_pixel(14, 4).0[0] < 50);
+    }
+
+    #[test]
+    fn ink_coverage_ignores_margins() {
+        // A dark border, as left by the scanner lid, around a white page.
+        let mut page = image::GrayImage::from_pixel(100, 100, image::Luma([250]));
+        for i in 0..100 {
+            for j in 0..2 {
+                page.put_pixel(i, j, image::Luma([0]));
+                page.put_pixel(j, i, image::Luma([0]));
+            }
+        }
+        let blank = test_png(page.as_raw(), 100);
+        for x in 10..30 {
+            page.put_pixel(x, 50, image::Luma([20]));
+        }
+        let line = test_png(page.as_raw(), 100);
+
+        assert_eq!(ink_coverage(&blank).unwrap(), 0.0);
+        assert!(is_blank(&blank, DEFAULT_BLANK_THRESHOLD).unwrap());
+        assert!((ink_coverage(&line).unwrap() - 20.0 / (94.0 * 94.0)).abs() < 1e-9);
+        assert!(!is_blank(&line, DEFAULT_BLANK_THRESHOLD).unwrap());
+    }
+
+    /// Light page of `width` x `height` with a line of text, rotated clockwise by `degrees`
+    /// on a dark background.
+    fn skewed_scan(degrees: f64, width: f64, height: f64) -> GrayImage {
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        GrayImage::from_fn(300, 360, |x, y| {
+            let (dx, dy) = (x as f64 - 150.0, y as f64 - 180.0);
+            // Back into the coordinates of the unrotated page.
+            let (px, py) = (dx * cos + dy * sin, -dx * sin + dy * cos);
+            if px.abs() > width / 2.0 || py.abs() > height / 2.0 {
+                image::Luma([25])
+            } else if py.abs() < 2.0 && px.abs() < width / 4.0 {
+                image::Luma([10])
+            } else {
+                image::Luma([235])
+            }
+        })
+    }
+
+    #[test]
+    fn detect_skew_from_edges() {
+        for degrees in [-4.0, -1.0, 0.0, 2.5, 6.0] {
+            let angle = detect_skew(&skewed_scan(degrees, 200.0, 280.0)).unwrap();
+            assert!((angle - degrees).abs() < 0.3, "{} vs {}", angle, degrees);
+        }
+        let unbordered = GrayImage::from_pixel(100, 100, image::Luma([240]));
+        assert_eq!(detect_skew(&unbordered), None);
+    }
+
+    #[test]
+    fn deskew_and_crop_page() {
+        let scan = DynamicImage::ImageLuma8(skewed_scan(3.0, 200.0, 280.0));
+        let png = encode(&scan, ImageFormat::Png).unwrap();
+        let transform = PageTransform {
+            deskew: true,
+            crop: true,
+            ..PageTransform::default()
+        };
+
+        let page = image::load_from_memory(&transform.apply(&png).unwrap())
+            .unwrap()
+            .into_luma8();
+
+        let (width, height) = page.dimensions();
+        assert!(
+            width.abs_diff(200) <= 4 && height.abs_diff(280) <= 4,
+            "{width}x{height}"
+        );
+        // Apart from the line of text, at most a seam of background remains along the edges.
+        let dark = page
+            .enumerate_pixels()
+            .filter(|(_, y, pixel)| y.abs_diff(height / 2) > 6 && pixel.0[0] < PAPER_LEVEL)
+            .count();
+        assert!(dark < (width + height) as usize, "{} dark pixels", dark);
+        let line: Vec<u32> = (0..width)
+            .filter(|&x| page.get_pixel(x, height / 2).0[0] < PAPER_LEVEL)
+            .collect();
+        assert!(line.len() >= 95, "{:?}", line);
+    }
+
+    #[test]
+    fn crop_without_border_keeps_page() {
+        let page = DynamicImage::ImageLuma8(GrayImage::from_pixel(40, 60, image::Luma([240])));
+
+        assert_eq!(crop_to_page(&page).into_luma8().dimensions(), (40, 60));
+        assert_eq!(deskew(&page), page);
+    }
+
+    #[test]
+    fn pdf_is_not_an_image() {
+        assert!(!is_image(b"%PDF-1.4\n"));
+        assert!(is_image(&test_png(&[0], 1)));
+        assert!(rotate_180(b"%PDF-1.4\n").is_err());
+    }
+}
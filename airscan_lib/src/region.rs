@@ -0,0 +1,358 @@
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    capabilities::InputCaps,
+    error::{AirscanError, Result},
+    settings::ScanRegion,
+};
+
+const MM_PER_INCH: f64 = 25.4;
+const UNITS_PER_INCH: f64 = 300.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Unit {
+    Millimeters,
+    Inches,
+}
+
+impl Unit {
+    fn to_three_hundredths(self, value: f64) -> u32 {
+        let inches = match self {
+            Unit::Millimeters => value / MM_PER_INCH,
+            Unit::Inches => value,
+        };
+        (inches * UNITS_PER_INCH).round().max(0.0) as u32
+    }
+}
+
+/// Scan area given in millimetres or inches, measured from the top left corner.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Region {
+    pub width: f64,
+    pub height: f64,
+    pub x_offset: f64,
+    pub y_offset: f64,
+    pub unit: Unit,
+}
+
+impl Region {
+    pub fn mm(width: f64, height: f64) -> Self {
+        Region {
+            width,
+            height,
+            x_offset: 0.0,
+            y_offset: 0.0,
+            unit: Unit::Millimeters,
+        }
+    }
+
+    pub fn inches(width: f64, height: f64) -> Self {
+        Region {
+            unit: Unit::Inches,
+            ..Region::mm(width, height)
+        }
+    }
+
+    /// Offset in the same unit as the size.
+    pub fn with_offset(mut self, x_offset: f64, y_offset: f64) -> Self {
+        self.x_offset = x_offset;
+        self.y_offset = y_offset;
+        self
+    }
+}
+
+impl From<Region> for ScanRegion {
+    fn from(region: Region) -> Self {
+        ScanRegion {
+            width: region.unit.to_three_hundredths(region.width),
+            height: region.unit.to_three_hundredths(region.height),
+            x_offset: region.unit.to_three_hundredths(region.x_offset),
+            y_offset: region.unit.to_three_hundredths(region.y_offset),
+        }
+    }
+}
+
+/// Parses `WIDTHxHEIGHT[+X+Y][mm|in]`, e.g. `80x200`, `3.5x2in` or `100x50+10+20mm`.
+/// Values without unit are millimetres.
+impl FromStr for Region {
+    type Err = AirscanError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || {
+            AirscanError::InvalidValue(format!(
+                "Invalid region {:?}, expected WIDTHxHEIGHT[+X+Y][mm|in]",
+                s
+            ))
+        };
+        let value = s.trim().to_ascii_lowercase();
+        let (value, unit) = if let Some(value) = value.strip_suffix("mm") {
+            (value.to_string(), Unit::Millimeters)
+        } else if let Some(value) = value.strip_suffix("in") {
+            (value.to_string(), Unit::Inches)
+        } else {
+            (value, Unit::Millimeters)
+        };
+
+        let mut parts = value.split('+');
+        let (width, height) = parts
+            .next()
+            .and_then(|size| size.split_once('x'))
+            .ok_or_else(invalid)?;
+        let offsets: Vec<&str> = parts.collect();
+        let (x_offset, y_offset) = match offsets.as_slice() {
+            [] => ("0", "0"),
+            [x, y] => (*x, *y),
+            _ => return Err(invalid()),
+        };
+
+        let parse = |value: &str| {
+            value
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|value| value.is_finite() && *value >= 0.0)
+                .ok_or_else(invalid)
+        };
+        let region = Region {
+            width: parse(width)?,
+            height: parse(height)?,
+            x_offset: parse(x_offset)?,
+            y_offset: parse(y_offset)?,
+            unit,
+        };
+        if region.width == 0.0 || region.height == 0.0 {
+            return Err(invalid());
+        }
+        Ok(region)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PaperSize {
+    A3,
+    A4,
+    A5,
+    A6,
+    B5,
+    Letter,
+    Legal,
+    BusinessCard,
+    Photo3_5x5,
+    Photo4x6,
+    Photo5x7,
+    Photo8x10,
+}
+
+impl PaperSize {
+    pub const ALL: [PaperSize; 12] = [
+        PaperSize::A3,
+        PaperSize::A4,
+        PaperSize::A5,
+        PaperSize::A6,
+        PaperSize::B5,
+        PaperSize::Letter,
+        PaperSize::Legal,
+        PaperSize::BusinessCard,
+        PaperSize::Photo3_5x5,
+        PaperSize::Photo4x6,
+        PaperSize::Photo5x7,
+        PaperSize::Photo8x10,
+    ];
+
+    /// Portrait size of the paper.
+    pub fn region(&self) -> Region {
+        match self {
+            PaperSize::A3 => Region::mm(297.0, 420.0),
+            PaperSize::A4 => Region::mm(210.0, 297.0),
+            PaperSize::A5 => Region::mm(148.0, 210.0),
+            PaperSize::A6 => Region::mm(105.0, 148.0),
+            PaperSize::B5 => Region::mm(176.0, 250.0),
+            PaperSize::Letter => Region::inches(8.5, 11.0),
+            PaperSize::Legal => Region::inches(8.5, 14.0),
+            PaperSize::BusinessCard => Region::mm(55.0, 85.0),
+            PaperSize::Photo3_5x5 => Region::inches(3.5, 5.0),
+            PaperSize::Photo4x6 => Region::inches(4.0, 6.0),
+            PaperSize::Photo5x7 => Region::inches(5.0, 7.0),
+            PaperSize::Photo8x10 => Region::inches(8.0, 10.0),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            PaperSize::A3 => "a3",
+            PaperSize::A4 => "a4",
+            PaperSize::A5 => "a5",
+            PaperSize::A6 => "a6",
+            PaperSize::B5 => "b5",
+            PaperSize::Letter => "letter",
+            PaperSize::Legal => "legal",
+            PaperSize::BusinessCard => "business-card",
+            PaperSize::Photo3_5x5 => "3.5x5",
+            PaperSize::Photo4x6 => "4x6",
+            PaperSize::Photo5x7 => "5x7",
+            PaperSize::Photo8x10 => "8x10",
+        }
+    }
+}
+
+impl From<PaperSize> for ScanRegion {
+    fn from(paper: PaperSize) -> Self {
+        paper.region().into()
+    }
+}
+
+impl fmt::Display for PaperSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for PaperSize {
+    type Err = AirscanError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let name = s.trim().to_ascii_lowercase();
+        PaperSize::ALL
+            .into_iter()
+            .find(|paper| paper.name() == name)
+            .ok_or_else(|| {
+                let names: Vec<&str> = PaperSize::ALL.iter().map(PaperSize::name).collect();
+                AirscanError::InvalidValue(format!(
+                    "Unknown paper size {:?}, expected one of {}",
+                    s,
+                    names.join(", ")
+                ))
+            })
+    }
+}
+
+impl ScanRegion {
+    /// Shrinks or grows the region to the scan area of `caps`, moving the offset
+    /// if the region would extend past the maximum width or height.
+    pub fn clamp_to(self, caps: &InputCaps) -> ScanRegion {
+        let width = self
+            .width
+            .clamp(caps.min_width, caps.max_width.max(caps.min_width));
+        let height = self
+            .height
+            .clamp(caps.min_height, caps.max_height.max(caps.min_height));
+        ScanRegion {
+            width,
+            height,
+            x_offset: self.x_offset.min(caps.max_width.saturating_sub(width)),
+            y_offset: self.y_offset.min(caps.max_height.saturating_sub(height)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capabilities::ScannerCapabilities;
+
+    fn brother_platen() -> InputCaps {
+        ScannerCapabilities::from_xml(include_str!(
+            "../testdata/capabilities/brother_mfc_l2710dw.xml"
+        ))
+        .unwrap()
+        .platen_caps()
+        .unwrap()
+        .clone()
+    }
+
+    #[test]
+    fn paper_sizes_in_three_hundredths() {
+        assert_eq!(
+            ScanRegion::from(PaperSize::A4),
+            ScanRegion {
+                width: 2480,
+                height: 3508,
+                x_offset: 0,
+                y_offset: 0
+            }
+        );
+        let letter = ScanRegion::from(PaperSize::Letter);
+        assert_eq!((letter.width, letter.height), (2550, 3300));
+        let a5 = ScanRegion::from(PaperSize::A5);
+        assert_eq!((a5.width, a5.height), (1748, 2480));
+        let photo = ScanRegion::from(PaperSize::Photo4x6);
+        assert_eq!((photo.width, photo.height), (1200, 1800));
+    }
+
+    #[test]
+    fn region_with_offset() {
+        let region = ScanRegion::from(Region::inches(2.0, 3.5).with_offset(0.5, 1.0));
+
+        assert_eq!(
+            region,
+            ScanRegion {
+                width: 600,
+                height: 1050,
+                x_offset: 150,
+                y_offset: 300
+            }
+        );
+    }
+
+    #[test]
+    fn parse_regions() {
+        assert_eq!("80x200".parse::<Region>().unwrap(), Region::mm(80.0, 200.0));
+        assert_eq!(
+            "3.5x2in".parse::<Region>().unwrap(),
+            Region::inches(3.5, 2.0)
+        );
+        assert_eq!(
+            "100x50+10+20mm".parse::<Region>().unwrap(),
+            Region::mm(100.0, 50.0).with_offset(10.0, 20.0)
+        );
+        assert!("100".parse::<Region>().is_err());
+        assert!("100x50+10".parse::<Region>().is_err());
+        assert!("0x50".parse::<Region>().is_err());
+        assert!("-10x50".parse::<Region>().is_err());
+        assert!("axb".parse::<Region>().is_err());
+    }
+
+    #[test]
+    fn parse_paper_sizes() {
+        assert_eq!("A4".parse::<PaperSize>().unwrap(), PaperSize::A4);
+        assert_eq!("letter".parse::<PaperSize>().unwrap(), PaperSize::Letter);
+        assert_eq!("4x6".parse::<PaperSize>().unwrap(), PaperSize::Photo4x6);
+        for paper in PaperSize::ALL {
+            assert_eq!(paper.to_string().parse::<PaperSize>().unwrap(), paper);
+        }
+        assert!("a2".parse::<PaperSize>().is_err());
+    }
+
+    #[test]
+    fn paper_sizes_are_portrait() {
+        for paper in PaperSize::ALL {
+            let region = paper.region();
+            assert!(region.width <= region.height, "{}", paper);
+        }
+    }
+
+    #[test]
+    fn clamp_to_scan_area() {
+        let caps = brother_platen();
+
+        let a3 = ScanRegion::from(PaperSize::A3).clamp_to(&caps);
+        assert_eq!((a3.width, a3.height), (2550, 3508));
+
+        let tiny = ScanRegion::from(Region::mm(1.0, 1.0)).clamp_to(&caps);
+        assert_eq!((tiny.width, tiny.height), (16, 16));
+
+        let shifted =
+            ScanRegion::from(Region::inches(8.0, 5.0).with_offset(1.0, 10.0)).clamp_to(&caps);
+        assert_eq!(
+            shifted,
+            ScanRegion {
+                width: 2400,
+                height: 1500,
+                x_offset: 150,
+                y_offset: 2008
+            }
+        );
+    }
+}
@@ -0,0 +1,303 @@
+use std::time::{Duration, Instant, SystemTime};
+
+use rand::Rng;
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backoff {
+    Fixed(Duration),
+    /// `initial * multiplier^attempt`, capped at `max`. `jitter` is the fraction
+    /// of the delay that is randomized, e.g. 0.5 waits between 50% and 100%.
+    Exponential {
+        initial: Duration,
+        max: Duration,
+        multiplier: f64,
+        jitter: f64,
+    },
+}
+
+/// Default for [`RetryPolicy::max_retry_after`].
+pub const DEFAULT_MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/// How long to keep retrying when the scanner is busy (HTTP 503) or unreachable.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    pub backoff: Backoff,
+    pub max_attempts: Option<u32>,
+    pub max_elapsed: Option<Duration>,
+    /// Wait as long as the scanner asks for in a `Retry-After` header.
+    pub honor_retry_after: bool,
+    /// Longest `Retry-After` that is waited for, longer ones are shortened to it.
+    pub max_retry_after: Duration,
+    /// Also retry requests that failed to connect.
+    pub retry_connect_errors: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy::fixed(Duration::from_secs(1)).with_max_attempts(100)
+    }
+}
+
+impl RetryPolicy {
+    pub fn fixed(delay: Duration) -> Self {
+        RetryPolicy {
+            backoff: Backoff::Fixed(delay),
+            max_attempts: None,
+            max_elapsed: None,
+            honor_retry_after: true,
+            max_retry_after: DEFAULT_MAX_RETRY_AFTER,
+            retry_connect_errors: true,
+        }
+    }
+
+    pub fn exponential(initial: Duration, max: Duration) -> Self {
+        RetryPolicy {
+            backoff: Backoff::Exponential {
+                initial,
+                max,
+                multiplier: 2.0,
+                jitter: 0.5,
+            },
+            ..RetryPolicy::fixed(initial)
+        }
+    }
+
+    /// Never retries, every busy response is returned as an error.
+    pub fn none() -> Self {
+        RetryPolicy::fixed(Duration::ZERO).with_max_attempts(0)
+    }
+
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = Some(max_attempts);
+        self
+    }
+
+    pub fn with_max_elapsed(mut self, max_elapsed: Duration) -> Self {
+        self.max_elapsed = Some(max_elapsed);
+        self
+    }
+
+    pub fn honor_retry_after(mut self, honor: bool) -> Self {
+        self.honor_retry_after = honor;
+        self
+    }
+
+    pub fn with_max_retry_after(mut self, max_retry_after: Duration) -> Self {
+        self.max_retry_after = max_retry_after;
+        self
+    }
+
+    pub fn retry_connect_errors(mut self, retry: bool) -> Self {
+        self.retry_connect_errors = retry;
+        self
+    }
+
+    /// Delay before retry number `attempt`, starting at 0.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        match self.backoff {
+            Backoff::Fixed(delay) => delay,
+            Backoff::Exponential {
+                initial,
+                max,
+                multiplier,
+                jitter,
+            } => {
+                let factor = multiplier.max(1.0).powi(attempt.min(64) as i32);
+                let delay = initial.as_secs_f64() * factor;
+                let delay = delay.min(max.as_secs_f64());
+                let jitter = jitter.clamp(0.0, 1.0);
+                let delay = if jitter > 0.0 {
+                    delay * rand::rng().random_range((1.0 - jitter)..=1.0)
+                } else {
+                    delay
+                };
+                // Caps near `Duration::MAX` round up past it as `f64`.
+                Duration::try_from_secs_f64(delay).map_or(max, |delay| delay.min(max))
+            }
+        }
+    }
+}
+
+/// Retry state of a single operation.
+#[derive(Debug)]
+pub(crate) struct Retry {
+    policy: RetryPolicy,
+    attempts: u32,
+    started: Instant,
+}
+
+impl Retry {
+    pub(crate) fn new(policy: RetryPolicy) -> Self {
+        Retry {
+            policy,
+            attempts: 0,
+            started: Instant::now(),
+        }
+    }
+
+    pub(crate) fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    pub(crate) fn retries_connect_errors(&self) -> bool {
+        self.policy.retry_connect_errors
+    }
+
+    /// Delay before the next attempt, or `None` when the policy is exhausted.
+    pub(crate) fn next_delay(&mut self, retry_after: Option<Duration>) -> Option<Duration> {
+        if let Some(max_attempts) = self.policy.max_attempts {
+            if self.attempts >= max_attempts {
+                return None;
+            }
+        }
+        let mut delay = self.policy.delay(self.attempts);
+        if let Some(retry_after) = retry_after.filter(|_| self.policy.honor_retry_after) {
+            delay = retry_after.min(self.policy.max_retry_after);
+        }
+        if let Some(max_elapsed) = self.policy.max_elapsed {
+            // Overflowing is well past any limit.
+            match self.started.elapsed().checked_add(delay) {
+                Some(elapsed) if elapsed <= max_elapsed => {}
+                _ => return None,
+            }
+        }
+        self.attempts += 1;
+        Some(delay)
+    }
+}
+
+/// Parses a `Retry-After` header given in seconds or as HTTP date.
+pub(crate) fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(
+        date.duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers_with_retry_after(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn fixed_delay() {
+        let policy = RetryPolicy::fixed(Duration::from_millis(250));
+
+        assert_eq!(policy.delay(0), Duration::from_millis(250));
+        assert_eq!(policy.delay(10), Duration::from_millis(250));
+    }
+
+    #[test]
+    fn exponential_delay_is_capped() {
+        let mut policy =
+            RetryPolicy::exponential(Duration::from_millis(100), Duration::from_secs(1));
+        if let Backoff::Exponential { jitter, .. } = &mut policy.backoff {
+            *jitter = 0.0;
+        }
+
+        assert_eq!(policy.delay(0), Duration::from_millis(100));
+        assert_eq!(policy.delay(1), Duration::from_millis(200));
+        assert_eq!(policy.delay(3), Duration::from_millis(800));
+        assert_eq!(policy.delay(4), Duration::from_secs(1));
+        assert_eq!(policy.delay(1000), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn exponential_delay_without_cap() {
+        let mut policy = RetryPolicy::exponential(Duration::from_secs(1), Duration::MAX);
+        assert!(policy.delay(70) >= Duration::from_secs(1 << 40));
+
+        if let Backoff::Exponential { jitter, .. } = &mut policy.backoff {
+            *jitter = 0.0;
+        }
+        assert_eq!(policy.delay(70), Duration::MAX);
+    }
+
+    #[test]
+    fn exponential_jitter_stays_in_range() {
+        let policy = RetryPolicy::exponential(Duration::from_millis(400), Duration::from_secs(10));
+
+        for _ in 0..100 {
+            let delay = policy.delay(1);
+            assert!(delay >= Duration::from_millis(400) && delay <= Duration::from_millis(800));
+        }
+    }
+
+    #[test]
+    fn max_attempts() {
+        let mut retry = Retry::new(RetryPolicy::fixed(Duration::ZERO).with_max_attempts(2));
+
+        assert!(retry.next_delay(None).is_some());
+        assert!(retry.next_delay(None).is_some());
+        assert!(retry.next_delay(None).is_none());
+        assert_eq!(retry.attempts(), 2);
+        assert!(Retry::new(RetryPolicy::none()).next_delay(None).is_none());
+    }
+
+    #[test]
+    fn max_elapsed() {
+        let mut retry = Retry::new(
+            RetryPolicy::fixed(Duration::from_secs(5)).with_max_elapsed(Duration::from_secs(12)),
+        );
+
+        assert!(retry.next_delay(None).is_some());
+        assert!(retry.next_delay(Some(Duration::from_secs(30))).is_none());
+    }
+
+    #[test]
+    fn retry_after_overrides_backoff() {
+        let mut retry = Retry::new(RetryPolicy::fixed(Duration::from_secs(1)));
+        assert_eq!(
+            retry.next_delay(Some(Duration::from_secs(7))),
+            Some(Duration::from_secs(7))
+        );
+
+        let mut retry =
+            Retry::new(RetryPolicy::fixed(Duration::from_secs(1)).honor_retry_after(false));
+        assert_eq!(
+            retry.next_delay(Some(Duration::from_secs(7))),
+            Some(Duration::from_secs(1))
+        );
+    }
+
+    #[test]
+    fn huge_retry_after() {
+        let mut retry = Retry::new(RetryPolicy::fixed(Duration::from_secs(1)));
+        assert_eq!(
+            retry.next_delay(Some(Duration::from_secs(u64::MAX))),
+            Some(DEFAULT_MAX_RETRY_AFTER)
+        );
+
+        let policy = RetryPolicy::fixed(Duration::from_secs(1))
+            .with_max_retry_after(Duration::MAX)
+            .with_max_elapsed(Duration::from_secs(600));
+        assert_eq!(Retry::new(policy).next_delay(Some(Duration::MAX)), None);
+    }
+
+    #[test]
+    fn parse_retry_after_header() {
+        assert_eq!(
+            retry_after(&headers_with_retry_after("5")),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(
+            retry_after(&headers_with_retry_after("Wed, 21 Oct 2015 07:28:00 GMT")),
+            Some(Duration::ZERO)
+        );
+        let future = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(120));
+        let delay = retry_after(&headers_with_retry_after(&future)).unwrap();
+        assert!(delay > Duration::from_secs(100) && delay <= Duration::from_secs(120));
+        assert_eq!(retry_after(&headers_with_retry_after("soon")), None);
+    }
+}
@@ -0,0 +1,1018 @@
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+use xml::{
+    common::XmlVersion,
+    writer::{EmitterConfig, EventWriter, XmlEvent},
+};
+
+use crate::{
+    capabilities::{InputCaps, ScannerCapabilities},
+    error::{AirscanError, Result},
+    version::EsclVersion,
+};
+
+pub(crate) const PWG_NS: &str = "http://www.pwg.org/schemas/2010/12/sm";
+pub(crate) const SCAN_NS: &str = "http://schemas.hp.com/imaging/escl/2011/05/03";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum InputSource {
+    Platen,
+    Feeder,
+    Camera,
+}
+
+impl InputSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            InputSource::Platen => "Platen",
+            InputSource::Feeder => "Feeder",
+            InputSource::Camera => "Camera",
+        }
+    }
+}
+
+impl fmt::Display for InputSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for InputSource {
+    type Err = AirscanError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "platen" | "flatbed" => Ok(InputSource::Platen),
+            "feeder" | "adf" => Ok(InputSource::Feeder),
+            "camera" => Ok(InputSource::Camera),
+            _ => Err(AirscanError::InvalidValue(format!(
+                "Unknown input source: {}",
+                s
+            ))),
+        }
+    }
+}
+
+/// Color mode and bit depth of the scanned pages (`scan:ColorMode`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub enum ColorMode {
+    /// 1 bit black and white, for text documents.
+    BlackAndWhite1,
+    Grayscale8,
+    Grayscale16,
+    #[default]
+    RGB24,
+    RGB48,
+}
+
+impl ColorMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ColorMode::BlackAndWhite1 => "BlackAndWhite1",
+            ColorMode::Grayscale8 => "Grayscale8",
+            ColorMode::Grayscale16 => "Grayscale16",
+            ColorMode::RGB24 => "RGB24",
+            ColorMode::RGB48 => "RGB48",
+        }
+    }
+}
+
+impl fmt::Display for ColorMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for ColorMode {
+    type Err = AirscanError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "blackandwhite1" | "bw" | "lineart" => Ok(ColorMode::BlackAndWhite1),
+            "grayscale8" | "gray" | "grey" | "grayscale" => Ok(ColorMode::Grayscale8),
+            "grayscale16" => Ok(ColorMode::Grayscale16),
+            "rgb24" | "color" | "colour" => Ok(ColorMode::RGB24),
+            "rgb48" => Ok(ColorMode::RGB48),
+            _ => Err(AirscanError::InvalidValue(format!(
+                "Unknown color mode: {}",
+                s
+            ))),
+        }
+    }
+}
+
+/// Kind of document, from which the scanner picks its image processing and compression
+/// (`scan:Intent`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Intent {
+    Document,
+    TextAndGraphic,
+    Photo,
+    /// Fast low quality scan, e.g. to show a thumbnail before the actual scan.
+    Preview,
+    Object,
+    BusinessCard,
+}
+
+impl Intent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Intent::Document => "Document",
+            Intent::TextAndGraphic => "TextAndGraphic",
+            Intent::Photo => "Photo",
+            Intent::Preview => "Preview",
+            Intent::Object => "Object",
+            Intent::BusinessCard => "BusinessCard",
+        }
+    }
+}
+
+impl fmt::Display for Intent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Intent {
+    type Err = AirscanError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "document" | "text" => Ok(Intent::Document),
+            "textandgraphic" | "mixed" => Ok(Intent::TextAndGraphic),
+            "photo" => Ok(Intent::Photo),
+            "preview" => Ok(Intent::Preview),
+            "object" => Ok(Intent::Object),
+            "businesscard" | "business-card" => Ok(Intent::BusinessCard),
+            _ => Err(AirscanError::InvalidValue(format!("Unknown intent: {}", s))),
+        }
+    }
+}
+
+/// Orientation in which sheets go through the feeder (`scan:FeedDirection`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FeedDirection {
+    LongEdgeFeed,
+    ShortEdgeFeed,
+}
+
+impl FeedDirection {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FeedDirection::LongEdgeFeed => "LongEdgeFeed",
+            FeedDirection::ShortEdgeFeed => "ShortEdgeFeed",
+        }
+    }
+}
+
+/// Scan area in 1/300 inch (`escl:ThreeHundredthsOfInches`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScanRegion {
+    pub width: u32,
+    pub height: u32,
+    pub x_offset: u32,
+    pub y_offset: u32,
+}
+
+/// Settings of a scan job, posted as `ScanSettings` XML to `{url}/ScanJobs`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScanSettings {
+    /// eSCL version to speak, the version of the scanner if `None`.
+    /// See [`ScanSettings::negotiate_version`].
+    pub version: Option<EsclVersion>,
+    pub input_source: InputSource,
+    pub scan_regions: Vec<ScanRegion>,
+    pub document_format: String,
+    pub document_format_ext: Option<String>,
+    pub color_mode: ColorMode,
+    pub x_resolution: u32,
+    pub y_resolution: u32,
+    pub duplex: Option<bool>,
+    pub feed_direction: Option<FeedDirection>,
+    pub intent: Option<Intent>,
+    /// Let the scanner mark blank pages (`scan:BlankPageDetection`).
+    pub blank_page_detection: Option<bool>,
+    /// Let the scanner drop blank pages (`scan:BlankPageDetectionAndRemoval`).
+    pub blank_page_removal: Option<bool>,
+    /// Image adjustments in the units of the scanner, see the `*_support` ranges of
+    /// [`ScannerCapabilities`].
+    pub brightness: Option<i32>,
+    pub contrast: Option<i32>,
+    pub gamma: Option<i32>,
+    pub highlight: Option<i32>,
+    pub shadow: Option<i32>,
+    pub sharpen: Option<i32>,
+    /// Black and white threshold for `BlackAndWhite1` scans.
+    pub threshold: Option<i32>,
+}
+
+impl Default for ScanSettings {
+    fn default() -> Self {
+        ScanSettings {
+            version: None,
+            input_source: InputSource::Platen,
+            scan_regions: Vec::new(),
+            document_format: String::from("application/pdf"),
+            document_format_ext: None,
+            color_mode: ColorMode::default(),
+            x_resolution: 300,
+            y_resolution: 300,
+            duplex: None,
+            feed_direction: None,
+            intent: None,
+            blank_page_detection: None,
+            blank_page_removal: None,
+            brightness: None,
+            contrast: None,
+            gamma: None,
+            highlight: None,
+            shadow: None,
+            sharpen: None,
+            threshold: None,
+        }
+    }
+}
+
+impl ScanSettings {
+    pub fn builder() -> ScanSettingsBuilder {
+        ScanSettingsBuilder::default()
+    }
+
+    /// Image adjustments by element name, in the order of the eSCL schema.
+    fn adjustments(&self) -> [(&'static str, Option<i32>); 7] {
+        [
+            ("Brightness", self.brightness),
+            ("Contrast", self.contrast),
+            ("Gamma", self.gamma),
+            ("Highlight", self.highlight),
+            ("Shadow", self.shadow),
+            ("Sharpen", self.sharpen),
+            ("Threshold", self.threshold),
+        ]
+    }
+
+    pub fn to_xml(&self) -> Result<String> {
+        let mut writer = EmitterConfig::new().create_writer(Vec::new());
+        writer.write(XmlEvent::StartDocument {
+            version: XmlVersion::Version10,
+            encoding: Some("UTF-8"),
+            standalone: None,
+        })?;
+        writer.write(
+            XmlEvent::start_element("scan:ScanSettings")
+                .ns("pwg", PWG_NS)
+                .ns("scan", SCAN_NS),
+        )?;
+        let version = self.version.unwrap_or_default();
+        write_element(&mut writer, "pwg:Version", &version.to_string())?;
+        if let Some(intent) = &self.intent {
+            write_element(&mut writer, "scan:Intent", intent.as_str())?;
+        }
+        write_element(&mut writer, "pwg:InputSource", self.input_source.as_str())?;
+        if !self.scan_regions.is_empty() {
+            writer.write(XmlEvent::start_element("pwg:ScanRegions"))?;
+            for region in &self.scan_regions {
+                writer.write(XmlEvent::start_element("pwg:ScanRegion"))?;
+                write_element(
+                    &mut writer,
+                    "pwg:ContentRegionUnits",
+                    "escl:ThreeHundredthsOfInches",
+                )?;
+                write_element(&mut writer, "pwg:Height", &region.height.to_string())?;
+                write_element(&mut writer, "pwg:Width", &region.width.to_string())?;
+                write_element(&mut writer, "pwg:XOffset", &region.x_offset.to_string())?;
+                write_element(&mut writer, "pwg:YOffset", &region.y_offset.to_string())?;
+                writer.write(XmlEvent::end_element())?;
+            }
+            writer.write(XmlEvent::end_element())?;
+        }
+        write_element(&mut writer, "pwg:DocumentFormat", &self.document_format)?;
+        if let Some(format_ext) = &self.document_format_ext {
+            write_element(&mut writer, "scan:DocumentFormatExt", format_ext)?;
+        }
+        write_element(&mut writer, "scan:ColorMode", self.color_mode.as_str())?;
+        write_element(
+            &mut writer,
+            "scan:XResolution",
+            &self.x_resolution.to_string(),
+        )?;
+        write_element(
+            &mut writer,
+            "scan:YResolution",
+            &self.y_resolution.to_string(),
+        )?;
+        if let Some(duplex) = self.duplex {
+            write_element(&mut writer, "scan:Duplex", &duplex.to_string())?;
+        }
+        if let Some(direction) = self.feed_direction {
+            write_element(&mut writer, "scan:FeedDirection", direction.as_str())?;
+        }
+        for (name, value) in self.adjustments() {
+            if let Some(value) = value {
+                write_element(&mut writer, &format!("scan:{}", name), &value.to_string())?;
+            }
+        }
+        if let Some(detection) = self.blank_page_detection {
+            write_element(
+                &mut writer,
+                "scan:BlankPageDetection",
+                &detection.to_string(),
+            )?;
+        }
+        if let Some(removal) = self.blank_page_removal {
+            write_element(
+                &mut writer,
+                "scan:BlankPageDetectionAndRemoval",
+                &removal.to_string(),
+            )?;
+        }
+        writer.write(XmlEvent::end_element())?;
+
+        String::from_utf8(writer.into_inner()).map_err(|error| AirscanError::Xml(error.to_string()))
+    }
+
+    /// Settles on the older of the requested and the scanner's eSCL version, and leaves out
+    /// elements that version does not know yet. Returns the names of the left out elements.
+    pub fn negotiate_version(&mut self, capabilities: &ScannerCapabilities) -> Vec<&'static str> {
+        let version = match (self.version, capabilities.escl_version()) {
+            (Some(requested), Some(scanner)) => requested.min(scanner),
+            (requested, scanner) => requested.or(scanner).unwrap_or_default(),
+        };
+        self.version = Some(version);
+
+        let mut left_out = Vec::new();
+        if version < EsclVersion::INTENT && self.intent.take().is_some() {
+            left_out.push("Intent");
+        }
+        if version < EsclVersion::DOCUMENT_FORMAT_EXT && self.document_format_ext.take().is_some() {
+            left_out.push("DocumentFormatExt");
+        }
+        if version < EsclVersion::FEED_DIRECTION && self.feed_direction.take().is_some() {
+            left_out.push("FeedDirection");
+        }
+        if version < EsclVersion::BLANK_PAGE_DETECTION {
+            if self.blank_page_detection.take().is_some() {
+                left_out.push("BlankPageDetection");
+            }
+            if self.blank_page_removal.take().is_some() {
+                left_out.push("BlankPageDetectionAndRemoval");
+            }
+        }
+        left_out
+    }
+
+    /// Fits all scan regions into the scan area of the selected input source.
+    pub fn clamp_regions(&mut self, capabilities: &ScannerCapabilities) {
+        let duplex = self.duplex.unwrap_or(false);
+        if let Some(caps) = capabilities.input_caps(self.input_source, duplex) {
+            for region in &mut self.scan_regions {
+                *region = region.clamp_to(caps);
+            }
+        }
+    }
+
+    /// Checks the settings against what the scanner advertises in its capabilities.
+    pub fn validate(&self, capabilities: &ScannerCapabilities) -> Result<()> {
+        let duplex = self.duplex.unwrap_or(false);
+        if duplex && self.input_source != InputSource::Feeder {
+            return Err(unsupported(
+                "Duplex is only supported with the Feeder input source".to_string(),
+            ));
+        }
+        let caps = capabilities
+            .input_caps(self.input_source, duplex)
+            .ok_or_else(|| match (self.input_source, duplex) {
+                (InputSource::Feeder, true) => {
+                    unsupported("Scanner does not support duplex scanning".to_string())
+                }
+                (source, _) => {
+                    unsupported(format!("Scanner does not support input source {}", source))
+                }
+            })?;
+
+        if !caps.supports_resolution(self.x_resolution)
+            || !caps.supports_resolution(self.y_resolution)
+        {
+            return Err(unsupported(format!(
+                "Resolution {}x{} not supported, available: {:?}",
+                self.x_resolution,
+                self.y_resolution,
+                caps.discrete_resolutions()
+            )));
+        }
+        let color_modes = caps.color_modes();
+        if !color_modes.contains(&self.color_mode.as_str()) {
+            return Err(unsupported(format!(
+                "Color mode {} not supported, available: {:?}",
+                self.color_mode, color_modes
+            )));
+        }
+        // Scanners without a list of intents take any.
+        if let Some(intent) = self.intent {
+            if !caps.supported_intents.is_empty()
+                && !caps.supported_intents.iter().any(|s| s == intent.as_str())
+            {
+                return Err(unsupported(format!(
+                    "Intent {} not supported, available: {:?}",
+                    intent, caps.supported_intents
+                )));
+            }
+        }
+        let formats = caps.document_formats();
+        for format in std::iter::once(&self.document_format).chain(&self.document_format_ext) {
+            if !formats.contains(&format.as_str()) {
+                return Err(unsupported(format!(
+                    "Document format {} not supported, available: {:?}",
+                    format, formats
+                )));
+            }
+        }
+        for region in &self.scan_regions {
+            validate_region(region, caps)?;
+        }
+        for (name, value) in self.adjustments() {
+            let Some(value) = value else {
+                continue;
+            };
+            match capabilities.adjustment_range(name) {
+                None => return Err(unsupported(format!("Scanner does not support {}", name))),
+                Some(range) if !range.contains(value) => {
+                    return Err(unsupported(format!(
+                        "{} {} not supported, available: {} to {}",
+                        name, value, range.min, range.max
+                    )))
+                }
+                Some(_) => {}
+            }
+        }
+        if self.blank_page_detection == Some(true) && !capabilities.blank_page_detection {
+            return Err(unsupported(
+                "Scanner does not support blank page detection".to_string(),
+            ));
+        }
+        if self.blank_page_removal == Some(true) && !capabilities.supports_blank_page_removal() {
+            return Err(unsupported(
+                "Scanner does not support blank page removal".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn validate_region(region: &ScanRegion, caps: &InputCaps) -> Result<()> {
+    // An overflowing edge is far outside of any scan area.
+    let right = region.x_offset.checked_add(region.width);
+    let bottom = region.y_offset.checked_add(region.height);
+    if region.width < caps.min_width
+        || region.height < caps.min_height
+        || right.is_none_or(|right| right > caps.max_width)
+        || bottom.is_none_or(|bottom| bottom > caps.max_height)
+    {
+        return Err(unsupported(format!(
+            "Scan region {:?} outside of the scan area ({}x{} to {}x{})",
+            region, caps.min_width, caps.min_height, caps.max_width, caps.max_height
+        )));
+    }
+    Ok(())
+}
+
+fn unsupported(message: String) -> AirscanError {
+    AirscanError::Unsupported(message)
+}
+
+pub(crate) fn write_element<W: std::io::Write>(
+    writer: &mut EventWriter<W>,
+    name: &str,
+    value: &str,
+) -> Result<()> {
+    writer.write(XmlEvent::start_element(name))?;
+    writer.write(XmlEvent::characters(value))?;
+    writer.write(XmlEvent::end_element())?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ScanSettingsBuilder {
+    settings: ScanSettings,
+}
+
+impl ScanSettingsBuilder {
+    pub fn version(mut self, version: EsclVersion) -> Self {
+        self.settings.version = Some(version);
+        self
+    }
+
+    pub fn input_source(mut self, input_source: InputSource) -> Self {
+        self.settings.input_source = input_source;
+        self
+    }
+
+    pub fn scan_region(mut self, region: impl Into<ScanRegion>) -> Self {
+        self.settings.scan_regions.push(region.into());
+        self
+    }
+
+    pub fn document_format(mut self, format: impl Into<String>) -> Self {
+        self.settings.document_format = format.into();
+        self
+    }
+
+    pub fn document_format_ext(mut self, format: impl Into<String>) -> Self {
+        self.settings.document_format_ext = Some(format.into());
+        self
+    }
+
+    pub fn color_mode(mut self, color_mode: ColorMode) -> Self {
+        self.settings.color_mode = color_mode;
+        self
+    }
+
+    pub fn resolution(mut self, resolution: u32) -> Self {
+        self.settings.x_resolution = resolution;
+        self.settings.y_resolution = resolution;
+        self
+    }
+
+    pub fn x_resolution(mut self, resolution: u32) -> Self {
+        self.settings.x_resolution = resolution;
+        self
+    }
+
+    pub fn y_resolution(mut self, resolution: u32) -> Self {
+        self.settings.y_resolution = resolution;
+        self
+    }
+
+    pub fn duplex(mut self, duplex: bool) -> Self {
+        self.settings.duplex = Some(duplex);
+        self
+    }
+
+    pub fn feed_direction(mut self, direction: FeedDirection) -> Self {
+        self.settings.feed_direction = Some(direction);
+        self
+    }
+
+    pub fn intent(mut self, intent: Intent) -> Self {
+        self.settings.intent = Some(intent);
+        self
+    }
+
+    pub fn blank_page_detection(mut self, detection: bool) -> Self {
+        self.settings.blank_page_detection = Some(detection);
+        self
+    }
+
+    /// Drop blank pages on the scanner, if its capabilities advertise it.
+    pub fn blank_page_removal(mut self, removal: bool) -> Self {
+        self.settings.blank_page_removal = Some(removal);
+        self
+    }
+
+    pub fn brightness(mut self, brightness: i32) -> Self {
+        self.settings.brightness = Some(brightness);
+        self
+    }
+
+    pub fn contrast(mut self, contrast: i32) -> Self {
+        self.settings.contrast = Some(contrast);
+        self
+    }
+
+    pub fn gamma(mut self, gamma: i32) -> Self {
+        self.settings.gamma = Some(gamma);
+        self
+    }
+
+    pub fn highlight(mut self, highlight: i32) -> Self {
+        self.settings.highlight = Some(highlight);
+        self
+    }
+
+    pub fn shadow(mut self, shadow: i32) -> Self {
+        self.settings.shadow = Some(shadow);
+        self
+    }
+
+    pub fn sharpen(mut self, sharpen: i32) -> Self {
+        self.settings.sharpen = Some(sharpen);
+        self
+    }
+
+    pub fn threshold(mut self, threshold: i32) -> Self {
+        self.settings.threshold = Some(threshold);
+        self
+    }
+
+    pub fn build(self) -> ScanSettings {
+        self.settings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::region::PaperSize;
+
+    fn hp() -> ScannerCapabilities {
+        ScannerCapabilities::from_xml(include_str!(
+            "../testdata/capabilities/hp_color_laserjet_m479fdw.xml"
+        ))
+        .unwrap()
+    }
+
+    fn brother() -> ScannerCapabilities {
+        ScannerCapabilities::from_xml(include_str!(
+            "../testdata/capabilities/brother_mfc_l2710dw.xml"
+        ))
+        .unwrap()
+    }
+
+    fn canon() -> ScannerCapabilities {
+        ScannerCapabilities::from_xml(include_str!(
+            "../testdata/capabilities/canon_imageclass_mf644cdw.xml"
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn default_settings_xml() {
+        let xml = ScanSettings::default().to_xml().unwrap();
+
+        assert_eq!(
+            xml,
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+             <scan:ScanSettings xmlns:pwg=\"http://www.pwg.org/schemas/2010/12/sm\" \
+             xmlns:scan=\"http://schemas.hp.com/imaging/escl/2011/05/03\">\
+             <pwg:Version>2.0</pwg:Version>\
+             <pwg:InputSource>Platen</pwg:InputSource>\
+             <pwg:DocumentFormat>application/pdf</pwg:DocumentFormat>\
+             <scan:ColorMode>RGB24</scan:ColorMode>\
+             <scan:XResolution>300</scan:XResolution>\
+             <scan:YResolution>300</scan:YResolution>\
+             </scan:ScanSettings>"
+        );
+    }
+
+    #[test]
+    fn builder_settings_xml() {
+        let xml = ScanSettings::builder()
+            .version("2.63".parse().unwrap())
+            .input_source(InputSource::Feeder)
+            .scan_region(ScanRegion {
+                width: 2480,
+                height: 3508,
+                x_offset: 10,
+                y_offset: 20,
+            })
+            .document_format("application/pdf")
+            .document_format_ext("application/pdf")
+            .color_mode(ColorMode::Grayscale8)
+            .resolution(200)
+            .duplex(true)
+            .intent(Intent::Document)
+            .build()
+            .to_xml()
+            .unwrap();
+
+        assert!(xml.contains("<pwg:Version>2.63</pwg:Version><scan:Intent>Document</scan:Intent>"));
+        assert!(xml.contains(
+            "<pwg:ScanRegions><pwg:ScanRegion>\
+             <pwg:ContentRegionUnits>escl:ThreeHundredthsOfInches</pwg:ContentRegionUnits>\
+             <pwg:Height>3508</pwg:Height><pwg:Width>2480</pwg:Width>\
+             <pwg:XOffset>10</pwg:XOffset><pwg:YOffset>20</pwg:YOffset>\
+             </pwg:ScanRegion></pwg:ScanRegions>"
+        ));
+        assert!(xml.contains("<scan:DocumentFormatExt>application/pdf</scan:DocumentFormatExt>"));
+        assert!(xml.contains("<scan:XResolution>200</scan:XResolution>"));
+        assert!(xml.contains("<scan:Duplex>true</scan:Duplex></scan:ScanSettings>"));
+    }
+
+    fn kyocera() -> ScannerCapabilities {
+        ScannerCapabilities::from_xml(include_str!(
+            "../testdata/capabilities/kyocera_ecosys_m2540dn.xml"
+        ))
+        .unwrap()
+    }
+
+    fn all_versioned_elements() -> ScanSettings {
+        ScanSettings::builder()
+            .input_source(InputSource::Feeder)
+            .document_format("application/pdf")
+            .document_format_ext("application/pdf")
+            .intent(Intent::Document)
+            .feed_direction(FeedDirection::ShortEdgeFeed)
+            .blank_page_detection(true)
+            .blank_page_removal(true)
+            .build()
+    }
+
+    #[test]
+    fn negotiate_scanner_version() {
+        let mut settings = all_versioned_elements();
+        assert!(settings.negotiate_version(&hp()).is_empty());
+
+        assert_eq!(settings.version, Some("2.63".parse().unwrap()));
+        assert_eq!(settings.feed_direction, Some(FeedDirection::ShortEdgeFeed));
+        let xml = settings.to_xml().unwrap();
+        assert!(xml.contains("<pwg:Version>2.63</pwg:Version>"));
+        assert!(xml.contains("<scan:FeedDirection>ShortEdgeFeed</scan:FeedDirection>"));
+        assert!(xml.contains("<scan:BlankPageDetection>true</scan:BlankPageDetection>"));
+
+        let mut settings = all_versioned_elements();
+        assert_eq!(
+            settings.negotiate_version(&kyocera()),
+            [
+                "DocumentFormatExt",
+                "FeedDirection",
+                "BlankPageDetection",
+                "BlankPageDetectionAndRemoval"
+            ]
+        );
+        assert_eq!(settings.version, Some(EsclVersion::DEFAULT));
+        assert_eq!(settings.intent, Some(Intent::Document));
+        assert_eq!(settings.document_format_ext, None);
+        assert_eq!(settings.feed_direction, None);
+        assert_eq!(settings.blank_page_removal, None);
+    }
+
+    #[test]
+    fn negotiate_older_version() {
+        let mut settings = ScanSettings {
+            version: Some("2.5".parse().unwrap()),
+            ..all_versioned_elements()
+        };
+        settings.negotiate_version(&hp());
+
+        assert_eq!(settings.version, Some("2.5".parse().unwrap()));
+        assert!(settings.feed_direction.is_some() && settings.document_format_ext.is_some());
+        assert_eq!(settings.blank_page_detection, None);
+
+        let mut old = hp();
+        old.version = String::from("1.4");
+        let mut settings = all_versioned_elements();
+        settings.negotiate_version(&old);
+        let xml = settings.to_xml().unwrap();
+        assert!(xml.contains("<pwg:Version>1.4</pwg:Version>"));
+        assert!(!xml.contains("Intent") && !xml.contains("DocumentFormatExt"));
+
+        // Unparseable versions fall back to what was requested.
+        old.version = String::from("unknown");
+        let mut settings = ScanSettings::default();
+        settings.negotiate_version(&old);
+        assert_eq!(settings.version, Some(EsclVersion::DEFAULT));
+    }
+
+    #[test]
+    fn blank_page_removal() {
+        let settings = ScanSettings::builder()
+            .input_source(InputSource::Feeder)
+            .document_format("image/jpeg")
+            .blank_page_removal(true)
+            .build();
+
+        assert!(settings.to_xml().unwrap().contains(
+            "<scan:BlankPageDetectionAndRemoval>true</scan:BlankPageDetectionAndRemoval>"
+        ));
+        settings.validate(&hp()).unwrap();
+        assert!(matches!(
+            settings.validate(&brother()),
+            Err(AirscanError::Unsupported(_))
+        ));
+    }
+
+    #[test]
+    fn image_adjustments() {
+        let settings = ScanSettings::builder()
+            .color_mode(ColorMode::BlackAndWhite1)
+            .brightness(1200)
+            .sharpen(3)
+            .threshold(100)
+            .blank_page_detection(true)
+            .build();
+
+        assert!(settings.to_xml().unwrap().contains(
+            "<scan:Brightness>1200</scan:Brightness>\
+             <scan:Sharpen>3</scan:Sharpen>\
+             <scan:Threshold>100</scan:Threshold>\
+             <scan:BlankPageDetection>true</scan:BlankPageDetection>"
+        ));
+        settings.validate(&hp()).unwrap();
+        let unsupported = |settings: ScanSettings, caps: &ScannerCapabilities| {
+            matches!(settings.validate(caps), Err(AirscanError::Unsupported(_)))
+        };
+        assert!(unsupported(
+            ScanSettings::builder().sharpen(5).build(),
+            &hp()
+        ));
+        assert!(unsupported(
+            ScanSettings::builder().gamma(100).build(),
+            &hp()
+        ));
+        assert!(unsupported(
+            ScanSettings::builder().threshold(128).build(),
+            &brother()
+        ));
+        ScanSettings::builder()
+            .brightness(-50)
+            .contrast(20)
+            .build()
+            .validate(&brother())
+            .unwrap();
+        assert!(unsupported(
+            ScanSettings::builder().brightness(1000).build(),
+            &brother()
+        ));
+    }
+
+    #[test]
+    fn values_are_escaped() {
+        let xml = ScanSettings::builder()
+            .document_format("image/<jpeg>&")
+            .build()
+            .to_xml()
+            .unwrap();
+
+        assert!(xml.contains("<pwg:DocumentFormat>image/&lt;jpeg&gt;&amp;</pwg:DocumentFormat>"));
+    }
+
+    #[test]
+    fn parse_input_source() {
+        assert_eq!(
+            "Feeder".parse::<InputSource>().unwrap(),
+            InputSource::Feeder
+        );
+        assert_eq!(
+            "platen".parse::<InputSource>().unwrap(),
+            InputSource::Platen
+        );
+        assert!("Tray".parse::<InputSource>().is_err());
+    }
+
+    #[test]
+    fn parse_color_mode() {
+        assert_eq!("gray".parse::<ColorMode>().unwrap(), ColorMode::Grayscale8);
+        assert_eq!(
+            "BlackAndWhite1".parse::<ColorMode>().unwrap(),
+            ColorMode::BlackAndWhite1
+        );
+        assert_eq!("rgb48".parse::<ColorMode>().unwrap(), ColorMode::RGB48);
+        assert!("CMYK32".parse::<ColorMode>().is_err());
+        for mode in [ColorMode::Grayscale16, ColorMode::RGB24] {
+            assert_eq!(mode.to_string().parse::<ColorMode>().unwrap(), mode);
+        }
+    }
+
+    #[test]
+    fn validate_color_mode_per_input_source() {
+        let black_and_white = ScanSettings::builder()
+            .color_mode(ColorMode::BlackAndWhite1)
+            .build();
+
+        black_and_white.validate(&brother()).unwrap();
+        assert!(matches!(
+            black_and_white.validate(&canon()),
+            Err(AirscanError::Unsupported(message)) if message.contains("BlackAndWhite1")
+        ));
+        assert!(black_and_white
+            .to_xml()
+            .unwrap()
+            .contains("<scan:ColorMode>BlackAndWhite1</scan:ColorMode>"));
+    }
+
+    #[test]
+    fn validate_intent() {
+        let preview = ScanSettings::builder().intent(Intent::Preview).build();
+        let feeder_preview = ScanSettings {
+            input_source: InputSource::Feeder,
+            document_format: String::from("image/jpeg"),
+            ..preview.clone()
+        };
+
+        preview.validate(&hp()).unwrap();
+        assert!(matches!(
+            feeder_preview.validate(&hp()),
+            Err(AirscanError::Unsupported(_))
+        ));
+        assert_eq!("mixed".parse::<Intent>().unwrap(), Intent::TextAndGraphic);
+        assert!("Poster".parse::<Intent>().is_err());
+    }
+
+    #[test]
+    fn validate_supported_settings() {
+        let settings = ScanSettings::builder()
+            .input_source(InputSource::Feeder)
+            .document_format("image/jpeg")
+            .color_mode(ColorMode::Grayscale8)
+            .resolution(300)
+            .duplex(true)
+            .scan_region(ScanRegion {
+                width: 2550,
+                height: 4200,
+                x_offset: 0,
+                y_offset: 0,
+            })
+            .build();
+
+        settings.validate(&hp()).unwrap();
+    }
+
+    #[test]
+    fn validate_unsupported_resolution() {
+        let settings = ScanSettings::builder()
+            .input_source(InputSource::Feeder)
+            .resolution(600)
+            .build();
+
+        assert!(settings.validate(&hp()).is_err());
+        assert!(ScanSettings::builder()
+            .resolution(600)
+            .build()
+            .validate(&hp())
+            .is_ok());
+    }
+
+    #[test]
+    fn validate_unsupported_color_mode_and_format() {
+        let caps = hp();
+
+        assert!(ScanSettings::builder()
+            .color_mode(ColorMode::RGB48)
+            .build()
+            .validate(&caps)
+            .is_err());
+        assert!(ScanSettings::builder()
+            .document_format("image/png")
+            .build()
+            .validate(&caps)
+            .is_err());
+    }
+
+    #[test]
+    fn validate_duplex_without_duplex_adf() {
+        let settings = ScanSettings::builder()
+            .input_source(InputSource::Feeder)
+            .duplex(true)
+            .build();
+
+        assert!(matches!(
+            settings.validate(&brother()),
+            Err(AirscanError::Unsupported(_))
+        ));
+        assert!(ScanSettings::builder()
+            .duplex(true)
+            .build()
+            .validate(&hp())
+            .is_err());
+    }
+
+    #[test]
+    fn clamp_regions_to_input_source() {
+        let mut settings = ScanSettings::builder()
+            .input_source(InputSource::Feeder)
+            .scan_region(PaperSize::Legal)
+            .build();
+
+        settings.clamp_regions(&brother());
+
+        assert_eq!(settings.scan_regions[0].height, 4200);
+        settings.input_source = InputSource::Platen;
+        settings.clamp_regions(&brother());
+        assert_eq!(settings.scan_regions[0].height, 3508);
+        settings.validate(&brother()).unwrap();
+    }
+
+    #[test]
+    fn validate_region_outside_scan_area() {
+        let settings = ScanSettings::builder()
+            .scan_region(ScanRegion {
+                width: 2550,
+                height: 3508,
+                x_offset: 100,
+                y_offset: 0,
+            })
+            .build();
+
+        assert!(settings.validate(&brother()).is_err());
+    }
+
+    #[test]
+    fn validate_region_with_overflowing_offset() {
+        for (x_offset, y_offset) in [(u32::MAX, 0), (0, u32::MAX - 10)] {
+            let settings = ScanSettings::builder()
+                .scan_region(ScanRegion {
+                    width: 100,
+                    height: 100,
+                    x_offset,
+                    y_offset,
+                })
+                .build();
+
+            assert!(matches!(
+                settings.validate(&brother()),
+                Err(AirscanError::Unsupported(_))
+            ));
+        }
+    }
+}
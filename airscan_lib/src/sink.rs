@@ -0,0 +1,395 @@
+use std::{
+    future::Future,
+    path::{Path, PathBuf},
+};
+
+use bytes::Bytes;
+use reqwest::Url;
+use tokio::{
+    fs,
+    io::{stdout, AsyncWriteExt},
+};
+
+use crate::error::Result;
+
+/// Describes a page handed to a [`PageSink`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PageMetadata {
+    /// Page number within the job, starting at 1.
+    pub number: u32,
+    /// `Content-Type` of the page, e.g. `image/jpeg`.
+    pub content_type: Option<String>,
+}
+
+impl PageMetadata {
+    /// File extension matching the content type, `bin` if unknown.
+    pub fn extension(&self) -> &'static str {
+        self.content_type
+            .as_deref()
+            .and_then(extension_for)
+            .unwrap_or("bin")
+    }
+}
+
+/// File extension for a MIME type, ignoring parameters such as `; charset=binary`.
+pub(crate) fn extension_for(content_type: &str) -> Option<&'static str> {
+    let essence = content_type.split(';').next().unwrap_or_default().trim();
+    match essence.to_ascii_lowercase().as_str() {
+        "application/pdf" => Some("pdf"),
+        "image/jpeg" => Some("jpg"),
+        "image/png" => Some("png"),
+        "image/tiff" => Some("tiff"),
+        "text/plain" => Some("txt"),
+        _ => None,
+    }
+}
+
+/// Receives the pages of a scan job, see [`crate::ScannerClient::fetch_to_sink`].
+///
+/// `begin_job` is called once before the first page, then `page` for every page and
+/// finally `end_job`. If fetching fails or is cancelled, `abort` is called instead of `end_job`.
+pub trait PageSink {
+    fn begin_job(&mut self, location: &Url) -> impl Future<Output = Result<()>> + Send;
+
+    fn page(
+        &mut self,
+        content: Bytes,
+        metadata: &PageMetadata,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    fn end_job(&mut self) -> impl Future<Output = Result<()>> + Send;
+
+    fn abort(&mut self) -> impl Future<Output = Result<()>> + Send;
+}
+
+/// Writes pages to `{directory}/{prefix}-{number}.{extension}`.
+/// Pages of an aborted job are removed again.
+#[derive(Debug, Clone)]
+pub struct DirectorySink {
+    directory: PathBuf,
+    prefix: String,
+    written: Vec<PathBuf>,
+}
+
+impl DirectorySink {
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        DirectorySink {
+            directory: directory.into(),
+            prefix: String::from("scan"),
+            written: Vec::new(),
+        }
+    }
+
+    /// File name prefix, defaults to `scan`.
+    pub fn prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    /// Files written for the current job.
+    pub fn written(&self) -> &[PathBuf] {
+        &self.written
+    }
+}
+
+impl PageSink for DirectorySink {
+    async fn begin_job(&mut self, _location: &Url) -> Result<()> {
+        self.written.clear();
+        fs::create_dir_all(&self.directory).await?;
+        Ok(())
+    }
+
+    async fn page(&mut self, content: Bytes, metadata: &PageMetadata) -> Result<()> {
+        let path = self.directory.join(format!(
+            "{}-{}.{}",
+            self.prefix,
+            metadata.number,
+            metadata.extension()
+        ));
+        fs::write(&path, &content).await?;
+        self.written.push(path);
+        Ok(())
+    }
+
+    async fn end_job(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn abort(&mut self) -> Result<()> {
+        for path in self.written.drain(..) {
+            fs::remove_file(path).await?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryPage {
+    pub metadata: PageMetadata,
+    pub content: Bytes,
+}
+
+/// Keeps all pages in memory. An aborted job leaves no pages.
+#[derive(Debug, Clone, Default)]
+pub struct MemorySink {
+    pub pages: Vec<MemoryPage>,
+}
+
+impl MemorySink {
+    pub fn new() -> Self {
+        MemorySink::default()
+    }
+}
+
+impl PageSink for MemorySink {
+    async fn begin_job(&mut self, _location: &Url) -> Result<()> {
+        self.pages.clear();
+        Ok(())
+    }
+
+    async fn page(&mut self, content: Bytes, metadata: &PageMetadata) -> Result<()> {
+        self.pages.push(MemoryPage {
+            metadata: metadata.clone(),
+            content,
+        });
+        Ok(())
+    }
+
+    async fn end_job(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn abort(&mut self) -> Result<()> {
+        self.pages.clear();
+        Ok(())
+    }
+}
+
+/// Writes all pages one after another to stdout, e.g. to pipe a PDF into another program.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StdoutSink;
+
+impl PageSink for StdoutSink {
+    async fn begin_job(&mut self, _location: &Url) -> Result<()> {
+        Ok(())
+    }
+
+    async fn page(&mut self, content: Bytes, _metadata: &PageMetadata) -> Result<()> {
+        let mut stdout = stdout();
+        stdout.write_all(&content).await?;
+        stdout.flush().await?;
+        Ok(())
+    }
+
+    async fn end_job(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn abort(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        client::{FetchOptions, ScannerClient},
+        error::AirscanError,
+        retry::RetryPolicy,
+        settings::{InputSource, ScanSettings},
+        virtual_scanner::{VirtualPage, VirtualScanner, VirtualScannerBuilder},
+    };
+
+    async fn submit_feeder_job(
+        builder: VirtualScannerBuilder,
+    ) -> (VirtualScanner, ScannerClient, Url) {
+        let scanner = builder.start().await.unwrap();
+        let client = ScannerClient::builder(&scanner.url())
+            .retry_policy(RetryPolicy::fixed(Duration::from_millis(10)).with_max_attempts(5))
+            .build()
+            .unwrap();
+        let settings = ScanSettings::builder()
+            .input_source(InputSource::Feeder)
+            .document_format("image/jpeg")
+            .build();
+        let location = client.submit_job(&settings).await.unwrap();
+        (scanner, client, location)
+    }
+
+    fn test_directory(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("airscan-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn extension_from_content_type() {
+        let metadata = |content_type: Option<&str>| PageMetadata {
+            number: 1,
+            content_type: content_type.map(str::to_string),
+        };
+
+        assert_eq!(metadata(Some("application/pdf")).extension(), "pdf");
+        assert_eq!(metadata(Some("image/jpeg")).extension(), "jpg");
+        assert_eq!(
+            metadata(Some("image/jpeg; charset=binary")).extension(),
+            "jpg"
+        );
+        assert_eq!(metadata(Some("Application/PDF;q=1")).extension(), "pdf");
+        assert_eq!(metadata(Some("image/x-unknown")).extension(), "bin");
+        assert_eq!(metadata(None).extension(), "bin");
+    }
+
+    #[tokio::test]
+    async fn memory_sink_collects_pages() {
+        let builder = VirtualScanner::builder()
+            .page(VirtualPage::new(b"one".to_vec(), "image/jpeg"))
+            .page(VirtualPage::new(b"two".to_vec(), "image/jpeg"))
+            .adf_sheets(2);
+        let (_scanner, client, location) = submit_feeder_job(builder).await;
+        let mut sink = MemorySink::new();
+
+        let pages = client
+            .fetch_to_sink(&location, &mut sink, &FetchOptions::default())
+            .await
+            .unwrap();
+
+        assert_eq!(pages, 2);
+        assert_eq!(sink.pages.len(), 2);
+        assert_eq!(sink.pages[0].content, Bytes::from_static(b"one"));
+        assert_eq!(sink.pages[1].content, Bytes::from_static(b"two"));
+        assert_eq!(sink.pages[1].metadata.number, 2);
+        assert_eq!(
+            sink.pages[1].metadata.content_type.as_deref(),
+            Some("image/jpeg")
+        );
+    }
+
+    #[tokio::test]
+    async fn blank_pages_are_dropped() {
+        let mut pixels = vec![255; 400];
+        let blank = crate::postprocess::test_png(&pixels, 20);
+        pixels[210..215].fill(0);
+        let text = crate::postprocess::test_png(&pixels, 20);
+        let builder = VirtualScanner::builder()
+            .page(VirtualPage::new(text.clone(), "image/png"))
+            .page(VirtualPage::new(blank, "image/png"))
+            .adf_sheets(3);
+        let (_scanner, client, location) = submit_feeder_job(builder).await;
+        let mut sink = MemorySink::new();
+        let options = FetchOptions {
+            rotate_back_side: true,
+            blank_page_threshold: Some(crate::postprocess::DEFAULT_BLANK_THRESHOLD),
+            ..FetchOptions::default()
+        };
+
+        let pages = client
+            .fetch_to_sink(&location, &mut sink, &options)
+            .await
+            .unwrap();
+
+        assert_eq!(pages, 2);
+        let numbers: Vec<u32> = sink.pages.iter().map(|page| page.metadata.number).collect();
+        assert_eq!(numbers, [1, 2]);
+        // The third scanned page is a front side and stays unrotated.
+        assert_eq!(sink.pages[1].content, Bytes::from(text));
+    }
+
+    /// Set for the child process of `stdout_sink_writes_only_pages`.
+    const STDOUT_CHILD: &str = "AIRSCAN_STDOUT_SINK_CHILD";
+    const BEGIN: &str = "--begin--";
+    const END: &str = "--end--";
+
+    #[tokio::test]
+    async fn stdout_sink_writes_only_pages() {
+        if std::env::var_os(STDOUT_CHILD).is_some() {
+            let builder = VirtualScanner::builder()
+                .page(VirtualPage::new(b"one".to_vec(), "image/jpeg"))
+                .page(VirtualPage::new(b"two".to_vec(), "image/jpeg"))
+                .adf_sheets(2)
+                .page_busy_responses(1);
+            let (_scanner, client, location) = submit_feeder_job(builder).await;
+            let options = FetchOptions {
+                blank_page_threshold: Some(crate::postprocess::DEFAULT_BLANK_THRESHOLD),
+                ..FetchOptions::default()
+            };
+            let mut stdout = stdout();
+            stdout.write_all(BEGIN.as_bytes()).await.unwrap();
+            stdout.flush().await.unwrap();
+            client
+                .fetch_to_sink(&location, &mut StdoutSink, &options)
+                .await
+                .unwrap();
+            stdout.write_all(END.as_bytes()).await.unwrap();
+            stdout.flush().await.unwrap();
+            return;
+        }
+
+        // The real stdout is only visible from another process.
+        let output = std::process::Command::new(std::env::current_exe().unwrap())
+            .args([
+                "--exact",
+                "sink::tests::stdout_sink_writes_only_pages",
+                "--nocapture",
+            ])
+            .env(STDOUT_CHILD, "1")
+            .output()
+            .unwrap();
+
+        assert!(output.status.success(), "{:?}", output);
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let (_, pages) = stdout.split_once(BEGIN).unwrap();
+        let (pages, _) = pages.split_once(END).unwrap();
+        assert_eq!(pages, "onetwo");
+    }
+
+    #[tokio::test]
+    async fn directory_sink_writes_files() {
+        let builder = VirtualScanner::builder()
+            .page(VirtualPage::new(b"page".to_vec(), "image/jpeg"))
+            .adf_sheets(2);
+        let (_scanner, client, location) = submit_feeder_job(builder).await;
+        let directory = test_directory("directory-sink");
+        let mut sink = DirectorySink::new(&directory).prefix("letter");
+
+        client
+            .fetch_to_sink(&location, &mut sink, &FetchOptions::default())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            sink.written(),
+            &[
+                directory.join("letter-1.jpg"),
+                directory.join("letter-2.jpg")
+            ]
+        );
+        assert_eq!(
+            std::fs::read(directory.join("letter-2.jpg")).unwrap(),
+            b"page"
+        );
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn failed_job_aborts_sink() {
+        let builder = VirtualScanner::builder().adf_sheets(3).jam_after(2);
+        let (_scanner, client, location) = submit_feeder_job(builder).await;
+        let directory = test_directory("aborted-sink");
+        let mut sink = DirectorySink::new(&directory);
+
+        let result = client
+            .fetch_to_sink(&location, &mut sink, &FetchOptions::default())
+            .await;
+
+        assert!(matches!(result, Err(AirscanError::JobFailed { .. })));
+        assert!(sink.written().is_empty());
+        assert_eq!(std::fs::read_dir(&directory).unwrap().count(), 0);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
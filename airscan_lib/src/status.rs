@@ -0,0 +1,292 @@
+use reqwest::Url;
+use serde::Deserialize;
+
+use crate::{capabilities::list, client::ScannerClient, error::Result};
+
+/// Parsed `ScannerStatus` document of an eSCL scanner.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ScannerStatus {
+    pub version: Option<String>,
+    pub state: ScannerState,
+    pub adf_state: Option<AdfState>,
+    #[serde(default, deserialize_with = "list")]
+    pub jobs: Vec<JobInfo>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum ScannerState {
+    Idle,
+    Processing,
+    Testing,
+    Stopped,
+    Down,
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum AdfState {
+    #[serde(rename = "ScannerAdfProcessing")]
+    Processing,
+    #[serde(rename = "ScannerAdfEmpty")]
+    Empty,
+    #[serde(rename = "ScannerAdfJam")]
+    Jam,
+    #[serde(rename = "ScannerAdfLoaded")]
+    Loaded,
+    #[serde(rename = "ScannerAdfMispick")]
+    Mispick,
+    #[serde(rename = "ScannerAdfHatchOpen")]
+    HatchOpen,
+    #[serde(rename = "ScannerAdfDuplexPageTooShort")]
+    DuplexPageTooShort,
+    #[serde(rename = "ScannerAdfDuplexPageTooLong")]
+    DuplexPageTooLong,
+    #[serde(rename = "ScannerAdfMultipickDetected")]
+    MultipickDetected,
+    #[serde(rename = "ScannerAdfInputTrayFailed")]
+    InputTrayFailed,
+    #[serde(rename = "ScannerAdfInputTrayOverloaded")]
+    InputTrayOverloaded,
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct JobInfo {
+    pub job_uri: String,
+    pub job_uuid: Option<String>,
+    pub age: Option<u32>,
+    pub images_completed: Option<u32>,
+    pub images_to_transfer: Option<u32>,
+    pub job_state: JobState,
+    #[serde(default, deserialize_with = "list")]
+    pub job_state_reasons: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum JobState {
+    Pending,
+    Processing,
+    Completed,
+    Canceled,
+    Aborted,
+    #[serde(other)]
+    Unknown,
+}
+
+impl ScannerStatus {
+    pub fn from_xml(xml: &str) -> Result<Self> {
+        Ok(serde_xml_rs::from_str(xml)?)
+    }
+
+    /// Looks up the job whose `JobUri` or `JobUuid` matches the location returned by
+    /// `post_scanrequest`, comparing the last path segment only.
+    pub fn find_job(&self, location: &Url) -> Option<&JobInfo> {
+        let id = last_segment(location.path())?;
+        self.jobs.iter().find(|job| {
+            last_segment(&job.job_uri) == Some(id) || job.job_uuid.as_deref() == Some(id)
+        })
+    }
+}
+
+impl AdfState {
+    /// States in which the feeder cannot deliver any page without user intervention.
+    pub fn is_error(&self) -> bool {
+        !matches!(
+            self,
+            AdfState::Processing | AdfState::Loaded | AdfState::Unknown
+        )
+    }
+}
+
+impl JobState {
+    pub fn is_final(&self) -> bool {
+        matches!(
+            self,
+            JobState::Completed | JobState::Canceled | JobState::Aborted
+        )
+    }
+}
+
+/// Last non-empty segment of a URL path, e.g. the job UUID of `/eSCL/ScanJobs/{uuid}/`.
+fn last_segment(path: &str) -> Option<&str> {
+    path.rsplit('/').find(|segment| !segment.is_empty())
+}
+
+pub async fn get_status(url: &str) -> Result<ScannerStatus> {
+    ScannerClient::new(url)?.status().await
+}
+
+/// Base URL of the scanner that owns the job at `location` (`{url}/ScanJobs/{uuid}/`).
+pub(crate) fn scanner_url_for_job(location: &Url) -> Option<String> {
+    let url = location.join("../../").ok()?;
+    Some(url.as_str().trim_end_matches('/').to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::AirscanError;
+
+    const BROTHER: &str = include_str!("../testdata/status/brother_idle_jobs.xml");
+    const HP: &str = include_str!("../testdata/status/hp_processing.xml");
+    const CANON: &str = include_str!("../testdata/status/canon_adf_jam.xml");
+    const EPSON: &str = include_str!("../testdata/status/epson_empty_no_jobs.xml");
+
+    #[test]
+    fn parse_idle_with_jobs() {
+        let status = ScannerStatus::from_xml(BROTHER).unwrap();
+
+        assert_eq!(status.state, ScannerState::Idle);
+        assert_eq!(status.adf_state, Some(AdfState::Loaded));
+        assert_eq!(status.jobs.len(), 2);
+        let job = &status.jobs[0];
+        assert_eq!(
+            job.job_uri,
+            "/eSCL/ScanJobs/b30a11f0-5834-11b2-8325-3c2af490c39a"
+        );
+        assert_eq!(job.images_completed, Some(3));
+        assert_eq!(job.images_to_transfer, Some(0));
+        assert_eq!(job.job_state, JobState::Completed);
+        assert_eq!(job.job_state_reasons, vec!["JobCompletedSuccessfully"]);
+        assert_eq!(status.jobs[1].job_state, JobState::Canceled);
+    }
+
+    #[test]
+    fn parse_processing() {
+        let status = ScannerStatus::from_xml(HP).unwrap();
+
+        assert_eq!(status.state, ScannerState::Processing);
+        assert_eq!(status.adf_state, Some(AdfState::Processing));
+        assert!(!status.jobs[0].job_state.is_final());
+        assert_eq!(status.jobs[0].images_to_transfer, Some(1));
+    }
+
+    #[test]
+    fn parse_jam() {
+        let status = ScannerStatus::from_xml(CANON).unwrap();
+
+        assert_eq!(status.state, ScannerState::Stopped);
+        assert!(status.adf_state.unwrap().is_error());
+        assert_eq!(status.jobs[0].job_state, JobState::Aborted);
+        assert_eq!(status.jobs[0].images_to_transfer, None);
+    }
+
+    #[test]
+    fn parse_empty_without_jobs() {
+        let status = ScannerStatus::from_xml(EPSON).unwrap();
+
+        assert_eq!(status.adf_state, Some(AdfState::Empty));
+        assert!(status.jobs.is_empty());
+    }
+
+    #[test]
+    fn parse_unknown_adf_state() {
+        let xml = BROTHER.replace("ScannerAdfLoaded", "ScannerAdfSomethingNew");
+        let status = ScannerStatus::from_xml(&xml).unwrap();
+
+        assert_eq!(status.adf_state, Some(AdfState::Unknown));
+        assert!(!AdfState::Unknown.is_error());
+    }
+
+    #[test]
+    fn parse_unknown_scanner_and_job_states() {
+        let xml = BROTHER
+            .replace(
+                "<pwg:State>Idle</pwg:State>",
+                "<pwg:State>Warming</pwg:State>",
+            )
+            .replace(
+                "<pwg:JobState>Completed</pwg:JobState>",
+                "<pwg:JobState>Held</pwg:JobState>",
+            );
+        let status = ScannerStatus::from_xml(&xml).unwrap();
+
+        assert_eq!(status.state, ScannerState::Unknown);
+        assert_eq!(status.jobs[0].job_state, JobState::Unknown);
+        assert!(!JobState::Unknown.is_final());
+    }
+
+    #[test]
+    fn find_job_by_location() {
+        let status = ScannerStatus::from_xml(BROTHER).unwrap();
+        let location =
+            Url::parse("http://192.168.2.38/eSCL/ScanJobs/a1f3c2e0-5834-11b2-8325-3c2af490c39a/")
+                .unwrap();
+
+        let job = status.find_job(&location).unwrap();
+
+        assert_eq!(job.job_state, JobState::Canceled);
+        let unknown = Url::parse("http://192.168.2.38/eSCL/ScanJobs/unknown/").unwrap();
+        assert!(status.find_job(&unknown).is_none());
+    }
+
+    #[test]
+    fn find_job_compares_whole_segments() {
+        let mut status = ScannerStatus::from_xml(BROTHER).unwrap();
+        status.jobs[0].job_uri = String::new();
+        status.jobs[0].job_uuid = None;
+        status.jobs[1].job_uri = String::from("1");
+        status.jobs[1].job_uuid = None;
+        let location = |id: &str| Url::parse(&format!("http://10.0.0.2/eSCL/ScanJobs/{}/", id));
+
+        assert!(status.find_job(&location("11").unwrap()).is_none());
+        assert_eq!(
+            status.find_job(&location("1").unwrap()),
+            Some(&status.jobs[1])
+        );
+
+        status.jobs[1].job_uri = String::from("/jobs/by-number/7");
+        status.jobs[1].job_uuid = Some(String::from("f00d"));
+        assert_eq!(
+            status.find_job(&location("f00d").unwrap()),
+            Some(&status.jobs[1])
+        );
+    }
+
+    #[test]
+    fn scanner_url_from_job_location() {
+        let location =
+            Url::parse("http://192.168.2.38/eSCL/ScanJobs/b30a11f0-5834-11b2-8325-3c2af490c39a/")
+                .unwrap();
+
+        assert_eq!(
+            scanner_url_for_job(&location).unwrap(),
+            "http://192.168.2.38/eSCL"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_status_success() {
+        let mut server = mockito::Server::new_async().await;
+        let _m = server
+            .mock("GET", "/ScannerStatus")
+            .with_status(200)
+            .with_header("content-type", "text/xml")
+            .with_body(HP)
+            .create_async()
+            .await;
+
+        let status = get_status(server.url().as_str()).await.unwrap();
+
+        assert_eq!(status.state, ScannerState::Processing);
+    }
+
+    #[tokio::test]
+    async fn test_get_status_error() {
+        let mut server = mockito::Server::new_async().await;
+        let _m = server
+            .mock("GET", "/ScannerStatus")
+            .with_status(404)
+            .create_async()
+            .await;
+
+        assert!(matches!(
+            get_status(server.url().as_str()).await,
+            Err(AirscanError::Http { status, .. }) if status == 404
+        ));
+    }
+}
@@ -0,0 +1,607 @@
+use std::{
+    io::{Cursor, Seek, Write},
+    path::PathBuf,
+    str::FromStr,
+};
+
+use bytes::Bytes;
+use fax::{encoder::Encoder, Color, VecWriter};
+use image::{DynamicImage, GrayImage, ImageFormat};
+use reqwest::Url;
+use tiff::{
+    encoder::{colortype, DirectoryEncoder, Rational, TiffEncoder, TiffKindStandard},
+    tags::{CompressionMethod, PhotometricInterpretation, ResolutionUnit, Tag},
+};
+
+use crate::{
+    capabilities::InputCaps,
+    error::{AirscanError, Result},
+    jpeg::{jpeg_info, JpegInfo},
+    postprocess::{encode, is_image},
+    settings::ColorMode,
+    sink::{PageMetadata, PageSink},
+};
+
+/// Gray values below are black in Group 4 pages.
+const BLACK_THRESHOLD: u8 = 128;
+/// `NewSubfileType` flag marking a page of a multi-page document.
+const SUBFILE_PAGE: u32 = 2;
+/// `PageNumber`, which the tiff crate has no name for.
+const PAGE_NUMBER: Tag = Tag::Unknown(297);
+
+/// Compression of the pages written by [`TiffAssembler`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TiffCompression {
+    /// CCITT Group 4, pages are reduced to black and white.
+    Group4,
+    /// Lossless LZW, pages keep their gray or RGB samples.
+    #[default]
+    Lzw,
+    /// JPEG, JPEG pages are embedded as is.
+    Jpeg,
+}
+
+impl FromStr for TiffCompression {
+    type Err = AirscanError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "g4" | "group4" | "ccitt" => Ok(TiffCompression::Group4),
+            "lzw" => Ok(TiffCompression::Lzw),
+            "jpg" | "jpeg" => Ok(TiffCompression::Jpeg),
+            _ => Err(AirscanError::InvalidValue(format!(
+                "Unknown TIFF compression {}, expected g4, lzw or jpeg",
+                s
+            ))),
+        }
+    }
+}
+
+impl TiffCompression {
+    /// Group 4 for black and white pages, LZW for gray and color pages.
+    pub fn for_color_mode(color_mode: ColorMode) -> Self {
+        match color_mode {
+            ColorMode::BlackAndWhite1 => TiffCompression::Group4,
+            _ => TiffCompression::Lzw,
+        }
+    }
+
+    /// Color mode to scan pages in for this compression. Group 4 pages end up black and
+    /// white, so color scans become `BlackAndWhite1` if `caps` offer it, else `Grayscale8`.
+    pub fn scan_color_mode(&self, requested: ColorMode, caps: Option<&InputCaps>) -> ColorMode {
+        if *self != TiffCompression::Group4
+            || !matches!(requested, ColorMode::RGB24 | ColorMode::RGB48)
+        {
+            return requested;
+        }
+        let bilevel = ColorMode::BlackAndWhite1.as_str();
+        if caps.is_some_and(|caps| caps.color_modes().contains(&bilevel)) {
+            ColorMode::BlackAndWhite1
+        } else {
+            ColorMode::Grayscale8
+        }
+    }
+
+    /// Format to scan pages in for this compression. Lossless PNG for Group 4 and LZW
+    /// unless `caps` lack it, JPEG for JPEG compression.
+    pub fn scan_document_format(&self, caps: Option<&InputCaps>) -> &'static str {
+        let png = caps.is_none_or(|caps| caps.document_formats().contains(&"image/png"));
+        match self {
+            TiffCompression::Group4 | TiffCompression::Lzw if png => "image/png",
+            _ => "image/jpeg",
+        }
+    }
+}
+
+/// Builds a multi-page TIFF from JPEG or PNG pages, tagged with the scan resolution.
+#[derive(Debug, Clone)]
+pub struct TiffAssembler {
+    x_resolution: u32,
+    y_resolution: u32,
+    compression: TiffCompression,
+    pages: Vec<Bytes>,
+}
+
+impl TiffAssembler {
+    /// Resolution of the scanned pages in dots per inch.
+    pub fn new(x_resolution: u32, y_resolution: u32) -> Self {
+        TiffAssembler {
+            x_resolution: x_resolution.max(1),
+            y_resolution: y_resolution.max(1),
+            compression: TiffCompression::default(),
+            pages: Vec::new(),
+        }
+    }
+
+    pub fn with_compression(mut self, compression: TiffCompression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    pub fn compression(&self) -> TiffCompression {
+        self.compression
+    }
+
+    pub fn add_page(&mut self, page: impl Into<Bytes>) -> Result<()> {
+        let page = page.into();
+        if !is_image(&page) {
+            return Err(AirscanError::InvalidValue(String::from(
+                "Not a JPEG or PNG page",
+            )));
+        }
+        self.pages.push(page);
+        Ok(())
+    }
+
+    pub fn page_count(&self) -> usize {
+        self.pages.len()
+    }
+
+    /// Decodes the pages as needed and writes them as one TIFF.
+    pub fn to_tiff(&self) -> Result<Vec<u8>> {
+        let mut out = Cursor::new(Vec::new());
+        let mut tiff =
+            TiffEncoder::new(&mut out)?.with_compression(tiff::encoder::Compression::Lzw);
+        let count = u16::try_from(self.pages.len())
+            .map_err(|_| AirscanError::InvalidValue(String::from("Too many pages for a TIFF")))?;
+
+        for (number, page) in (0..count).zip(&self.pages) {
+            let page_number = [number, count];
+            match self.compression {
+                TiffCompression::Group4 => {
+                    let image = image::load_from_memory(page)?.into_luma8();
+                    let mut directory = tiff.image_directory()?;
+                    self.write_group4(&mut directory, &image)?;
+                    directory.write_tag(PAGE_NUMBER, &page_number[..])?;
+                    directory.finish()?;
+                }
+                TiffCompression::Lzw => {
+                    let image = image::load_from_memory(page)?;
+                    if image.color().has_color() {
+                        let image = image.into_rgb8();
+                        let mut encoder =
+                            tiff.new_image::<colortype::RGB8>(image.width(), image.height())?;
+                        self.write_page_tags(encoder.encoder())?;
+                        encoder.encoder().write_tag(PAGE_NUMBER, &page_number[..])?;
+                        encoder.write_data(&image)?;
+                    } else {
+                        let image = image.into_luma8();
+                        let mut encoder =
+                            tiff.new_image::<colortype::Gray8>(image.width(), image.height())?;
+                        self.write_page_tags(encoder.encoder())?;
+                        encoder.encoder().write_tag(PAGE_NUMBER, &page_number[..])?;
+                        encoder.write_data(&image)?;
+                    }
+                }
+                TiffCompression::Jpeg => {
+                    let mut directory = tiff.image_directory()?;
+                    self.write_jpeg(&mut directory, page)?;
+                    directory.write_tag(PAGE_NUMBER, &page_number[..])?;
+                    directory.finish()?;
+                }
+            }
+        }
+
+        Ok(out.into_inner())
+    }
+
+    fn write_page_tags<W: Write + Seek>(
+        &self,
+        directory: &mut DirectoryEncoder<W, TiffKindStandard>,
+    ) -> Result<()> {
+        directory.write_tag(Tag::NewSubfileType, SUBFILE_PAGE)?;
+        directory.write_tag(
+            Tag::XResolution,
+            Rational {
+                n: self.x_resolution,
+                d: 1,
+            },
+        )?;
+        directory.write_tag(
+            Tag::YResolution,
+            Rational {
+                n: self.y_resolution,
+                d: 1,
+            },
+        )?;
+        directory.write_tag(Tag::ResolutionUnit, ResolutionUnit::Inch.to_u16())?;
+        directory.write_tag(
+            Tag::Software,
+            concat!("airscan-rust ", env!("CARGO_PKG_VERSION")),
+        )?;
+        Ok(())
+    }
+
+    fn write_group4<W: Write + Seek>(
+        &self,
+        directory: &mut DirectoryEncoder<W, TiffKindStandard>,
+        image: &GrayImage,
+    ) -> Result<()> {
+        let (width, height) = image.dimensions();
+        let line_width = u16::try_from(width).map_err(|_| {
+            AirscanError::InvalidValue(format!("Page too wide for Group 4: {} pixels", width))
+        })?;
+        let mut encoder = Encoder::new(VecWriter::new());
+        for row in image.rows() {
+            let pels = row.map(|pixel| {
+                if pixel.0[0] < BLACK_THRESHOLD {
+                    Color::Black
+                } else {
+                    Color::White
+                }
+            });
+            let Ok(()) = encoder.encode_line(pels, line_width);
+        }
+        let Ok(writer) = encoder.finish();
+        let data = writer.finish();
+
+        let offset = directory.write_data(&data[..])?;
+        write_strip(directory, width, height, offset, data.len())?;
+        directory.write_tag(Tag::BitsPerSample, 1u16)?;
+        directory.write_tag(Tag::SamplesPerPixel, 1u16)?;
+        directory.write_tag(Tag::Compression, CompressionMethod::Fax4.to_u16())?;
+        directory.write_tag(
+            Tag::PhotometricInterpretation,
+            PhotometricInterpretation::WhiteIsZero.to_u16(),
+        )?;
+        self.write_page_tags(directory)
+    }
+
+    fn write_jpeg<W: Write + Seek>(
+        &self,
+        directory: &mut DirectoryEncoder<W, TiffKindStandard>,
+        page: &Bytes,
+    ) -> Result<()> {
+        let (data, info) = match jpeg_info(page) {
+            Ok(
+                info @ JpegInfo {
+                    components: 1 | 3, ..
+                },
+            ) => (page.to_vec(), info),
+            // PNG and CMYK pages are encoded as RGB JPEG.
+            _ => {
+                let image = image::load_from_memory(page)?.into_rgb8();
+                let data = encode(&DynamicImage::ImageRgb8(image), ImageFormat::Jpeg)?;
+                let info = jpeg_info(&data)?;
+                (data, info)
+            }
+        };
+
+        let offset = directory.write_data(&data[..])?;
+        write_strip(directory, info.width, info.height, offset, data.len())?;
+        directory.write_tag(Tag::Compression, CompressionMethod::ModernJPEG.to_u16())?;
+        if info.components == 1 {
+            directory.write_tag(Tag::BitsPerSample, 8u16)?;
+            directory.write_tag(Tag::SamplesPerPixel, 1u16)?;
+            directory.write_tag(
+                Tag::PhotometricInterpretation,
+                PhotometricInterpretation::BlackIsZero.to_u16(),
+            )?;
+        } else {
+            directory.write_tag(Tag::BitsPerSample, &[8u16, 8, 8][..])?;
+            directory.write_tag(Tag::SamplesPerPixel, 3u16)?;
+            directory.write_tag(
+                Tag::PhotometricInterpretation,
+                PhotometricInterpretation::YCbCr.to_u16(),
+            )?;
+            let (horizontal, vertical) = info.subsampling;
+            directory.write_tag(
+                Tag::ChromaSubsampling,
+                &[horizontal as u16, vertical as u16][..],
+            )?;
+        }
+        self.write_page_tags(directory)
+    }
+}
+
+/// Writes the tags of a page stored as a single strip at `offset`.
+fn write_strip<W: Write + Seek>(
+    directory: &mut DirectoryEncoder<W, TiffKindStandard>,
+    width: u32,
+    height: u32,
+    offset: u64,
+    length: usize,
+) -> Result<()> {
+    let invalid = || AirscanError::InvalidValue(String::from("TIFF larger than 4 GiB"));
+    directory.write_tag(Tag::ImageWidth, width)?;
+    directory.write_tag(Tag::ImageLength, height)?;
+    directory.write_tag(Tag::RowsPerStrip, height)?;
+    directory.write_tag(
+        Tag::StripOffsets,
+        u32::try_from(offset).map_err(|_| invalid())?,
+    )?;
+    directory.write_tag(
+        Tag::StripByteCounts,
+        u32::try_from(length).map_err(|_| invalid())?,
+    )?;
+    Ok(())
+}
+
+/// Collects the pages of a job and writes them as one multi-page TIFF to `path` when the job ends.
+#[derive(Debug, Clone)]
+pub struct TiffSink {
+    path: PathBuf,
+    assembler: TiffAssembler,
+}
+
+impl TiffSink {
+    pub fn new(path: impl Into<PathBuf>, x_resolution: u32, y_resolution: u32) -> Self {
+        TiffSink {
+            path: path.into(),
+            assembler: TiffAssembler::new(x_resolution, y_resolution),
+        }
+    }
+
+    pub fn with_compression(mut self, compression: TiffCompression) -> Self {
+        self.assembler = self.assembler.with_compression(compression);
+        self
+    }
+}
+
+impl PageSink for TiffSink {
+    async fn begin_job(&mut self, _location: &Url) -> Result<()> {
+        self.assembler.pages.clear();
+        Ok(())
+    }
+
+    async fn page(&mut self, content: Bytes, _metadata: &PageMetadata) -> Result<()> {
+        self.assembler.add_page(content)
+    }
+
+    async fn end_job(&mut self) -> Result<()> {
+        let tiff = self.assembler.to_tiff()?;
+        tokio::fs::write(&self.path, tiff).await?;
+        self.assembler.pages.clear();
+        Ok(())
+    }
+
+    async fn abort(&mut self) -> Result<()> {
+        self.assembler.pages.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ExtendedColorType, RgbImage};
+    use tiff::decoder::{ifd::Value, Decoder, DecodingResult};
+
+    use super::*;
+    use crate::{capabilities::ScannerCapabilities, postprocess::test_png, settings::InputSource};
+
+    fn jpeg(image: &RgbImage) -> Vec<u8> {
+        let mut out = Cursor::new(Vec::new());
+        image::codecs::jpeg::JpegEncoder::new(&mut out)
+            .encode(
+                image.as_raw(),
+                image.width(),
+                image.height(),
+                ExtendedColorType::Rgb8,
+            )
+            .unwrap();
+        out.into_inner()
+    }
+
+    fn decoder(tiff: Vec<u8>) -> Decoder<Cursor<Vec<u8>>> {
+        Decoder::new(Cursor::new(tiff)).unwrap()
+    }
+
+    fn assert_page(decoder: &mut Decoder<Cursor<Vec<u8>>>, page: [u16; 2], resolution: (u32, u32)) {
+        assert_eq!(decoder.get_tag_u16_vec(PAGE_NUMBER).unwrap(), page);
+        assert_eq!(decoder.get_tag_u32(Tag::NewSubfileType).unwrap(), 2);
+        assert_eq!(
+            decoder.get_tag(Tag::XResolution).unwrap(),
+            Value::Rational(resolution.0, 1)
+        );
+        assert_eq!(
+            decoder.get_tag(Tag::YResolution).unwrap(),
+            Value::Rational(resolution.1, 1)
+        );
+        assert_eq!(decoder.get_tag_u32(Tag::ResolutionUnit).unwrap(), 2);
+    }
+
+    #[test]
+    fn parse_compression() {
+        assert_eq!(
+            "g4".parse::<TiffCompression>().unwrap(),
+            TiffCompression::Group4
+        );
+        assert_eq!(
+            "LZW".parse::<TiffCompression>().unwrap(),
+            TiffCompression::Lzw
+        );
+        assert_eq!(
+            "jpeg".parse::<TiffCompression>().unwrap(),
+            TiffCompression::Jpeg
+        );
+        assert!("zip".parse::<TiffCompression>().is_err());
+    }
+
+    #[test]
+    fn compression_and_color_mode() {
+        assert_eq!(
+            TiffCompression::for_color_mode(ColorMode::BlackAndWhite1),
+            TiffCompression::Group4
+        );
+        assert_eq!(
+            TiffCompression::for_color_mode(ColorMode::RGB24),
+            TiffCompression::Lzw
+        );
+
+        let hp = ScannerCapabilities::from_xml(include_str!(
+            "../testdata/capabilities/hp_color_laserjet_m479fdw.xml"
+        ))
+        .unwrap();
+        let platen = hp.input_caps(InputSource::Platen, false);
+        let group4 = TiffCompression::Group4;
+        assert_eq!(
+            group4.scan_color_mode(ColorMode::RGB24, platen),
+            ColorMode::BlackAndWhite1
+        );
+        assert_eq!(
+            group4.scan_color_mode(ColorMode::RGB24, None),
+            ColorMode::Grayscale8
+        );
+        assert_eq!(
+            group4.scan_color_mode(ColorMode::Grayscale16, platen),
+            ColorMode::Grayscale16
+        );
+        assert_eq!(
+            TiffCompression::Lzw.scan_color_mode(ColorMode::RGB24, platen),
+            ColorMode::RGB24
+        );
+    }
+
+    #[test]
+    fn scan_document_format() {
+        let hp = include_str!("../testdata/capabilities/hp_color_laserjet_m479fdw.xml");
+        let jpeg_only = ScannerCapabilities::from_xml(hp).unwrap();
+        let with_png = ScannerCapabilities::from_xml(&hp.replace(
+            "<pwg:DocumentFormat>application/pdf</pwg:DocumentFormat>",
+            "<pwg:DocumentFormat>image/png</pwg:DocumentFormat>",
+        ))
+        .unwrap();
+        let jpeg_only = jpeg_only.input_caps(InputSource::Platen, false);
+        let with_png = with_png.input_caps(InputSource::Platen, false);
+
+        for compression in [TiffCompression::Group4, TiffCompression::Lzw] {
+            assert_eq!(compression.scan_document_format(with_png), "image/png");
+            assert_eq!(compression.scan_document_format(None), "image/png");
+            assert_eq!(compression.scan_document_format(jpeg_only), "image/jpeg");
+        }
+        assert_eq!(
+            TiffCompression::Jpeg.scan_document_format(with_png),
+            "image/jpeg"
+        );
+    }
+
+    #[test]
+    fn group4_pages() {
+        // Left half black, right half light gray.
+        let pixels: Vec<u8> = (0..64).map(|i| if i % 16 < 8 { 20 } else { 200 }).collect();
+        let mut assembler = TiffAssembler::new(300, 600).with_compression(TiffCompression::Group4);
+        assembler.add_page(test_png(&pixels, 16)).unwrap();
+        assembler.add_page(test_png(&pixels, 8)).unwrap();
+
+        let mut decoder = decoder(assembler.to_tiff().unwrap());
+
+        assert_eq!(decoder.dimensions().unwrap(), (16, 4));
+        assert_eq!(
+            decoder.get_tag_u32(Tag::Compression).unwrap(),
+            CompressionMethod::Fax4.to_u16() as u32
+        );
+        assert_page(&mut decoder, [0, 2], (300, 600));
+        let DecodingResult::U8(rows) = decoder.read_image().unwrap() else {
+            panic!("expected 8 bit samples");
+        };
+        // One bit per pixel, the decoder turns WhiteIsZero into black is zero.
+        assert_eq!(rows, [0x00, 0xFF].repeat(4));
+
+        assert!(decoder.more_images());
+        decoder.next_image().unwrap();
+        assert_eq!(decoder.dimensions().unwrap(), (8, 8));
+        assert_page(&mut decoder, [1, 2], (300, 600));
+        assert!(!decoder.more_images());
+    }
+
+    #[test]
+    fn group4_rejects_wide_pages() {
+        let mut assembler = TiffAssembler::new(300, 300).with_compression(TiffCompression::Group4);
+        assembler
+            .add_page(test_png(&vec![0; 70_000], 70_000))
+            .unwrap();
+
+        assert!(matches!(
+            assembler.to_tiff(),
+            Err(AirscanError::InvalidValue(_))
+        ));
+    }
+
+    #[test]
+    fn lzw_pages_are_lossless() {
+        let color = RgbImage::from_fn(5, 3, |x, y| image::Rgb([x as u8 * 50, y as u8 * 100, 7]));
+        let mut png = Cursor::new(Vec::new());
+        color.write_to(&mut png, ImageFormat::Png).unwrap();
+        let mut assembler = TiffAssembler::new(150, 150);
+        assembler
+            .add_page(test_png(&[0, 50, 100, 150, 200, 250], 3))
+            .unwrap();
+        assembler.add_page(png.into_inner()).unwrap();
+
+        let mut decoder = decoder(assembler.to_tiff().unwrap());
+
+        assert_eq!(decoder.colortype().unwrap(), tiff::ColorType::Gray(8));
+        assert_page(&mut decoder, [0, 2], (150, 150));
+        let DecodingResult::U8(gray) = decoder.read_image().unwrap() else {
+            panic!("expected 8 bit samples");
+        };
+        assert_eq!(gray, [0, 50, 100, 150, 200, 250]);
+
+        decoder.next_image().unwrap();
+        assert_eq!(decoder.colortype().unwrap(), tiff::ColorType::RGB(8));
+        assert_page(&mut decoder, [1, 2], (150, 150));
+        let DecodingResult::U8(rgb) = decoder.read_image().unwrap() else {
+            panic!("expected 8 bit samples");
+        };
+        assert_eq!(rgb, color.into_raw());
+    }
+
+    #[test]
+    fn jpeg_pages_are_embedded() {
+        let page = jpeg(&RgbImage::from_pixel(32, 16, image::Rgb([200, 30, 30])));
+        let mut assembler = TiffAssembler::new(300, 300).with_compression(TiffCompression::Jpeg);
+        assembler.add_page(page.clone()).unwrap();
+        assembler.add_page(test_png(&[128; 64], 8)).unwrap();
+
+        let tiff = assembler.to_tiff().unwrap();
+        assert!(tiff.windows(page.len()).any(|window| window == page));
+
+        let mut decoder = decoder(tiff);
+        assert_eq!(decoder.dimensions().unwrap(), (32, 16));
+        assert_eq!(
+            decoder.get_tag_u32(Tag::Compression).unwrap(),
+            CompressionMethod::ModernJPEG.to_u16() as u32
+        );
+        assert_eq!(
+            decoder.get_tag_u16_vec(Tag::ChromaSubsampling).unwrap(),
+            [1, 1]
+        );
+        assert_page(&mut decoder, [0, 2], (300, 300));
+        let DecodingResult::U8(pixels) = decoder.read_image().unwrap() else {
+            panic!("expected 8 bit samples");
+        };
+        // Samples stay YCbCr, dark luma and strong red chroma.
+        assert!(pixels[0].abs_diff(81) < 5 && pixels[2] > 200);
+
+        decoder.next_image().unwrap();
+        assert_eq!(decoder.dimensions().unwrap(), (8, 8));
+        assert_eq!(decoder.colortype().unwrap(), tiff::ColorType::YCbCr(8));
+        assert_page(&mut decoder, [1, 2], (300, 300));
+    }
+
+    #[tokio::test]
+    async fn tiff_sink_writes_file() {
+        let path = std::env::temp_dir().join(format!("airscan-sink-{}.tiff", std::process::id()));
+        let mut sink = TiffSink::new(&path, 300, 300).with_compression(TiffCompression::Group4);
+        let location = Url::parse("http://127.0.0.1/eSCL/ScanJobs/1/").unwrap();
+        let metadata = PageMetadata {
+            number: 1,
+            content_type: Some(String::from("image/png")),
+        };
+
+        sink.begin_job(&location).await.unwrap();
+        sink.page(Bytes::from(test_png(&[0; 16], 4)), &metadata)
+            .await
+            .unwrap();
+        assert!(sink
+            .page(Bytes::from_static(b"%PDF-1.4"), &metadata)
+            .await
+            .is_err());
+        sink.end_job().await.unwrap();
+
+        let mut decoder = decoder(std::fs::read(&path).unwrap());
+        assert_eq!(decoder.dimensions().unwrap(), (4, 4));
+        assert!(!decoder.more_images());
+        std::fs::remove_file(path).unwrap();
+    }
+}
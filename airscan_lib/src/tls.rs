@@ -0,0 +1,509 @@
+//! Certificate checks for `https` scanners, which mostly use self-signed certificates.
+
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Write as _},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use reqwest::Url;
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    ClientConfig, DigitallySignedStruct, SignatureScheme,
+};
+use sha2::{Digest, Sha256};
+
+use crate::error::{AirscanError, Result};
+
+/// How [`crate::ScannerClient`] checks the certificate of an `https` scanner.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum TlsVerification {
+    /// Certificates signed by a CA the system trusts.
+    #[default]
+    Standard,
+    /// Certificates signed by a CA from this PEM bundle.
+    CaBundle(PathBuf),
+    /// Trust the certificate a scanner presents the first time and pin its fingerprint
+    /// in this [`KnownScanners`] file. Other certificates are rejected afterwards.
+    TrustOnFirstUse(PathBuf),
+    /// Accept any certificate. Exposes the connection to anyone on the network.
+    Insecure,
+}
+
+/// Called with the `host:port` and fingerprint when a scanner's certificate is pinned
+/// for the first time with [`TlsVerification::TrustOnFirstUse`].
+#[derive(Clone)]
+pub struct PinCallback(Arc<PinFn>);
+
+type PinFn = dyn Fn(&str, &str) + Send + Sync;
+
+impl PinCallback {
+    pub fn new(callback: impl Fn(&str, &str) + Send + Sync + 'static) -> Self {
+        PinCallback(Arc::new(callback))
+    }
+}
+
+impl fmt::Debug for PinCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PinCallback")
+    }
+}
+
+/// SHA-256 fingerprint of a DER encoded certificate, in lowercase hex.
+pub fn fingerprint(certificate: &[u8]) -> String {
+    Sha256::digest(certificate)
+        .iter()
+        .fold(String::new(), |mut hex, byte| {
+            let _ = write!(hex, "{:02x}", byte);
+            hex
+        })
+}
+
+/// Pinned certificate fingerprints by `host:port`, stored one `host:port fingerprint` per line.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KnownScanners {
+    path: PathBuf,
+    fingerprints: BTreeMap<String, String>,
+}
+
+impl KnownScanners {
+    /// Reads the file at `path`, empty if it does not exist yet.
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let content = match std::fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(error) => return Err(error.into()),
+        };
+
+        let mut fingerprints = BTreeMap::new();
+        for (number, line) in (1..).zip(content.lines()) {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((scanner, fingerprint)) = line.split_once(char::is_whitespace) else {
+                return Err(AirscanError::InvalidValue(format!(
+                    "{}:{}: expected host:port and fingerprint",
+                    path.display(),
+                    number
+                )));
+            };
+            fingerprints.insert(scanner.to_string(), fingerprint.trim().to_string());
+        }
+        Ok(KnownScanners { path, fingerprints })
+    }
+
+    /// `$XDG_CONFIG_HOME/airscan/known_scanners`, or below `~/.config`.
+    pub fn default_path() -> Option<PathBuf> {
+        let config = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+        Some(config.join("airscan").join("known_scanners"))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn fingerprint(&self, scanner: &str) -> Option<&str> {
+        self.fingerprints.get(scanner).map(String::as_str)
+    }
+
+    pub fn insert(&mut self, scanner: impl Into<String>, fingerprint: impl Into<String>) {
+        self.fingerprints.insert(scanner.into(), fingerprint.into());
+    }
+
+    /// Forgets a scanner, e.g. after it got a new certificate.
+    pub fn remove(&mut self, scanner: &str) -> Option<String> {
+        self.fingerprints.remove(scanner)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        if let Some(parent) = self
+            .path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut content = String::from("# Certificate fingerprints of eSCL scanners\n");
+        for (scanner, fingerprint) in &self.fingerprints {
+            let _ = writeln!(content, "{} {}", scanner, fingerprint);
+        }
+        Ok(std::fs::write(&self.path, content)?)
+    }
+}
+
+/// `host:port` under which the scanner at `url` is pinned.
+pub(crate) fn scanner_key(url: &str) -> Result<String> {
+    let url = Url::parse(url)?;
+    let host = url
+        .host_str()
+        .ok_or_else(|| AirscanError::InvalidValue(format!("No host in {}", url)))?;
+    Ok(format!(
+        "{}:{}",
+        host,
+        url.port_or_known_default().unwrap_or(443)
+    ))
+}
+
+/// Host of a TLS server name, written like the host of a URL.
+fn server_host(server_name: &ServerName<'_>) -> Option<String> {
+    match server_name {
+        ServerName::DnsName(name) => Some(name.as_ref().to_ascii_lowercase()),
+        ServerName::IpAddress(ip) => Some(match std::net::IpAddr::from(*ip) {
+            std::net::IpAddr::V6(ip) => format!("[{}]", ip),
+            ip => ip.to_string(),
+        }),
+        _ => None,
+    }
+}
+
+/// TLS configuration pinning the certificate of the scanner at `url` in `known_scanners`.
+/// New pins are reported to `on_pin`, or logged without one.
+pub(crate) fn pinning_config(
+    url: &str,
+    known_scanners: &Path,
+    on_pin: Option<PinCallback>,
+) -> Result<ClientConfig> {
+    let provider = Arc::new(ring::default_provider());
+    let scanner = scanner_key(url)?;
+    let verifier = PinningVerifier {
+        host: scanner
+            .rsplit_once(':')
+            .map_or("", |(host, _)| host)
+            .to_string(),
+        scanner,
+        known: Mutex::new(KnownScanners::load(known_scanners)?),
+        provider: provider.clone(),
+        on_pin,
+    };
+    let config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|error| AirscanError::InvalidValue(error.to_string()))?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+    Ok(config)
+}
+
+/// Accepts the pinned certificate of one scanner, or pins the first one it sees.
+/// Other hosts, e.g. from a redirect, need a pin of their own.
+#[derive(Debug)]
+struct PinningVerifier {
+    /// `host:port` of the scanner.
+    scanner: String,
+    host: String,
+    known: Mutex<KnownScanners>,
+    provider: Arc<CryptoProvider>,
+    on_pin: Option<PinCallback>,
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        let actual = fingerprint(end_entity);
+        let mut known = self.known.lock().unwrap();
+        let host = server_host(server_name).unwrap_or_default();
+        if host != self.host {
+            // The port is unknown here, any pin of the host will do.
+            let pinned = known.fingerprints.iter().any(|(scanner, pinned)| {
+                scanner.rsplit_once(':').map(|(host, _)| host) == Some(host.as_str())
+                    && pinned.eq_ignore_ascii_case(&actual)
+            });
+            if pinned {
+                return Ok(ServerCertVerified::assertion());
+            }
+            return Err(rustls::Error::General(format!(
+                "Certificate {} of {} is not pinned in {}, only {} is trusted on first use",
+                actual,
+                host,
+                known.path().display(),
+                self.scanner
+            )));
+        }
+        match known.fingerprint(&self.scanner) {
+            Some(pinned) if pinned.eq_ignore_ascii_case(&actual) => {}
+            Some(pinned) => {
+                return Err(rustls::Error::General(format!(
+                    "Certificate of {} changed from {} to {}, remove it from {} if this is expected",
+                    self.scanner,
+                    pinned,
+                    actual,
+                    known.path().display()
+                )));
+            }
+            None => {
+                known.insert(self.scanner.clone(), actual.clone());
+                known
+                    .save()
+                    .map_err(|error| rustls::Error::General(error.to_string()))?;
+                match &self.on_pin {
+                    Some(on_pin) => (on_pin.0)(&self.scanner, &actual),
+                    None => log::warn!("Trusting certificate {} of {}", actual, self.scanner),
+                }
+            }
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        certificate: &CertificateDer<'_>,
+        signature: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            certificate,
+            signature,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        certificate: &CertificateDer<'_>,
+        signature: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            certificate,
+            signature,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::Infallible, net::SocketAddr, time::Duration};
+
+    use bytes::Bytes;
+    use http_body_util::Full;
+    use hyper::{server::conn::http1, service::service_fn, Response};
+    use hyper_util::rt::TokioIo;
+    use rcgen::{BasicConstraints, CertificateParams, CertifiedKey, IsCa, KeyPair};
+    use rustls::{pki_types::PrivateKeyDer, ServerConfig};
+    use tokio::net::TcpListener;
+    use tokio_rustls::TlsAcceptor;
+
+    use super::*;
+    use crate::{client::ScannerClient, retry::RetryPolicy};
+
+    const STATUS: &str = include_str!("../testdata/status/brother_idle_jobs.xml");
+
+    /// `https` stand-in for a scanner that answers every request with a `ScannerStatus`.
+    async fn tls_scanner(certificate: CertificateDer<'static>, key: &KeyPair) -> SocketAddr {
+        let key = PrivateKeyDer::try_from(key.serialize_der()).unwrap();
+        let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(vec![certificate], key)
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    // Clients that reject the certificate end the handshake.
+                    let Ok(stream) = acceptor.accept(stream).await else {
+                        return;
+                    };
+                    let service = service_fn(|_request| async {
+                        Ok::<_, Infallible>(Response::new(Full::new(Bytes::from_static(
+                            STATUS.as_bytes(),
+                        ))))
+                    });
+                    let _ = http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), service)
+                        .await;
+                });
+            }
+        });
+        address
+    }
+
+    fn self_signed() -> CertifiedKey {
+        rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap()
+    }
+
+    fn client(address: SocketAddr, verification: TlsVerification) -> ScannerClient {
+        ScannerClient::builder(&format!("https://localhost:{}/eSCL", address.port()))
+            .tls(verification)
+            .retry_policy(RetryPolicy::fixed(Duration::ZERO).with_max_attempts(1))
+            .build()
+            .unwrap()
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("airscan-{}-{}", name, std::process::id()))
+    }
+
+    #[tokio::test]
+    async fn reject_self_signed_by_default() {
+        let scanner = self_signed();
+        let address = tls_scanner(scanner.cert.der().clone(), &scanner.key_pair).await;
+
+        let result = client(address, TlsVerification::Standard).status().await;
+
+        assert!(matches!(result, Err(AirscanError::Transport(_))));
+        client(address, TlsVerification::Insecure)
+            .status()
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn trust_on_first_use() {
+        let path = temp_path("known-scanners").join("known_scanners");
+        let scanner = self_signed();
+        let address = tls_scanner(scanner.cert.der().clone(), &scanner.key_pair).await;
+        let key = format!("localhost:{}", address.port());
+        let pinning = TlsVerification::TrustOnFirstUse(path.clone());
+        let pins = Arc::new(Mutex::new(Vec::new()));
+        let pinning_client = || {
+            let pins = pins.clone();
+            ScannerClient::builder(&format!("https://localhost:{}/eSCL", address.port()))
+                .tls(pinning.clone())
+                .on_pin(PinCallback::new(move |scanner, fingerprint| {
+                    pins.lock()
+                        .unwrap()
+                        .push((scanner.to_string(), fingerprint.to_string()))
+                }))
+                .build()
+                .unwrap()
+        };
+
+        pinning_client().status().await.unwrap();
+        let known = KnownScanners::load(&path).unwrap();
+        let expected = fingerprint(scanner.cert.der());
+        assert_eq!(known.fingerprint(&key), Some(expected.as_str()));
+        // Pinned now, also for a new client.
+        pinning_client().status().await.unwrap();
+        assert_eq!(*pins.lock().unwrap(), [(key.clone(), expected)]);
+
+        let mut known = known;
+        known.insert(key.clone(), fingerprint(b"another certificate"));
+        known.save().unwrap();
+        let result = client(address, pinning).status().await;
+        let error = format!("{:?}", result.unwrap_err());
+        assert!(error.contains("Certificate of localhost"), "{}", error);
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn trust_ca_bundle() {
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca_key = KeyPair::generate().unwrap();
+        let ca = params.self_signed(&ca_key).unwrap();
+        let scanner_key = KeyPair::generate().unwrap();
+        let scanner = CertificateParams::new(vec![String::from("localhost")])
+            .unwrap()
+            .signed_by(&scanner_key, &ca, &ca_key)
+            .unwrap();
+        let address = tls_scanner(scanner.der().clone(), &scanner_key).await;
+        let bundle = temp_path("ca.pem");
+        std::fs::write(&bundle, ca.pem()).unwrap();
+        let other = temp_path("other-ca.pem");
+        std::fs::write(&other, self_signed().cert.pem()).unwrap();
+
+        client(address, TlsVerification::CaBundle(bundle.clone()))
+            .status()
+            .await
+            .unwrap();
+        let result = client(address, TlsVerification::CaBundle(other.clone()))
+            .status()
+            .await;
+        assert!(matches!(result, Err(AirscanError::Transport(_))));
+
+        std::fs::write(&other, "no certificates").unwrap();
+        assert!(ScannerClient::builder("https://localhost/eSCL")
+            .tls(TlsVerification::CaBundle(other.clone()))
+            .build()
+            .is_err());
+        std::fs::remove_file(bundle).unwrap();
+        std::fs::remove_file(other).unwrap();
+    }
+
+    #[test]
+    fn other_hosts_need_their_own_pin() {
+        let path = temp_path("known-scanners-other-hosts");
+        let certificate = CertificateDer::from(b"scanner certificate".to_vec());
+        let verify = |server_name: &'static str| {
+            let verifier = PinningVerifier {
+                scanner: String::from("scanner.local:443"),
+                host: String::from("scanner.local"),
+                known: Mutex::new(KnownScanners::load(&path).unwrap()),
+                provider: Arc::new(ring::default_provider()),
+                on_pin: Some(PinCallback::new(|_, _| {})),
+            };
+            let server_name = ServerName::try_from(server_name).unwrap();
+            verifier
+                .verify_server_cert(&certificate, &[], &server_name, &[], UnixTime::now())
+                .is_ok()
+        };
+
+        assert!(!verify("other.local"));
+        assert!(!path.exists());
+        assert!(verify("scanner.local"));
+        assert!(!verify("other.local"));
+
+        let mut known = KnownScanners::load(&path).unwrap();
+        known.insert("other.local:8443", fingerprint(&certificate));
+        known.insert("[::1]:443", fingerprint(b"another certificate"));
+        known.save().unwrap();
+        assert!(verify("other.local"));
+        assert!(!verify("::1"));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn known_scanners_file() {
+        let path = temp_path("known-scanners-file");
+        std::fs::write(
+            &path,
+            "# pinned\n\n192.168.1.20:443 abcd\nscanner.local:8443  ef01\n",
+        )
+        .unwrap();
+
+        let mut known = KnownScanners::load(&path).unwrap();
+        assert_eq!(known.fingerprint("192.168.1.20:443"), Some("abcd"));
+        assert_eq!(known.fingerprint("scanner.local:8443"), Some("ef01"));
+        assert_eq!(known.remove("192.168.1.20:443"), Some(String::from("abcd")));
+        known.save().unwrap();
+        assert_eq!(KnownScanners::load(&path).unwrap(), known);
+
+        std::fs::write(&path, "missing-fingerprint\n").unwrap();
+        assert!(KnownScanners::load(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(KnownScanners::load(&path).unwrap().fingerprint("x:1"), None);
+        assert_eq!(
+            scanner_key("https://192.168.1.20/eSCL").unwrap(),
+            "192.168.1.20:443"
+        );
+        assert_eq!(fingerprint(b"").len(), 64);
+    }
+}
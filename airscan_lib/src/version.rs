@@ -0,0 +1,115 @@
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{AirscanError, Result};
+
+/// eSCL protocol version, e.g. `2.63` from `pwg:Version`. Versions compare as decimal
+/// numbers, so 2.6 is newer than 2.51 and older than 2.63.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct EsclVersion {
+    major: u32,
+    /// Hundredths, 60 for 2.6.
+    minor: u32,
+}
+
+impl EsclVersion {
+    /// Version sent when the scanner does not advertise one.
+    pub const DEFAULT: EsclVersion = EsclVersion::hundredths(2, 0);
+
+    /// First versions with `scan:Intent`, `scan:DocumentFormatExt`, `scan:FeedDirection`
+    /// and `scan:BlankPageDetection` in `ScanSettings`.
+    pub(crate) const INTENT: EsclVersion = EsclVersion::hundredths(2, 0);
+    pub(crate) const DOCUMENT_FORMAT_EXT: EsclVersion = EsclVersion::hundredths(2, 10);
+    pub(crate) const FEED_DIRECTION: EsclVersion = EsclVersion::hundredths(2, 50);
+    pub(crate) const BLANK_PAGE_DETECTION: EsclVersion = EsclVersion::hundredths(2, 60);
+
+    const fn hundredths(major: u32, minor: u32) -> Self {
+        EsclVersion { major, minor }
+    }
+
+    pub fn major(&self) -> u32 {
+        self.major
+    }
+}
+
+impl Default for EsclVersion {
+    fn default() -> Self {
+        EsclVersion::DEFAULT
+    }
+}
+
+impl fmt::Display for EsclVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.minor.is_multiple_of(10) {
+            write!(f, "{}.{}", self.major, self.minor / 10)
+        } else {
+            write!(f, "{}.{:02}", self.major, self.minor)
+        }
+    }
+}
+
+impl FromStr for EsclVersion {
+    type Err = AirscanError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || AirscanError::InvalidValue(format!("Invalid eSCL version: {}", s));
+        let (major, minor) = s.trim().split_once('.').unwrap_or((s.trim(), "0"));
+        if major.is_empty() || !major.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        if minor.is_empty() || !minor.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        // Digits after the second are below the precision of any released version.
+        let digits: String = minor
+            .chars()
+            .chain(std::iter::repeat('0'))
+            .take(2)
+            .collect();
+        Ok(EsclVersion {
+            major: major.parse().map_err(|_| invalid())?,
+            minor: digits.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+impl TryFrom<String> for EsclVersion {
+    type Error = AirscanError;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<EsclVersion> for String {
+    fn from(version: EsclVersion) -> Self {
+        version.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(s: &str) -> EsclVersion {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_and_compare_versions() {
+        assert!(version("2.6") > version("2.51"));
+        assert!(version("2.6") < version("2.63"));
+        assert!(version("1.4") < EsclVersion::DEFAULT);
+        assert_eq!(version("2"), EsclVersion::DEFAULT);
+        assert_eq!(version(" 2.60 "), version("2.6"));
+
+        for s in ["2.0", "2.6", "2.63", "2.05", "10.1"] {
+            assert_eq!(version(s).to_string(), s);
+        }
+        for s in ["", "2.", "v2.0", "2.6.1", "two"] {
+            assert!(s.parse::<EsclVersion>().is_err(), "{}", s);
+        }
+    }
+}
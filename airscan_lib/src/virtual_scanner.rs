@@ -0,0 +1,695 @@
+//! In-process eSCL scanner for end-to-end tests of the client.
+//!
+//! Serves `ScannerCapabilities`, `ScannerStatus`, `ScanJobs` and `NextDocument`
+//! on a local port and simulates busy periods, feeder page counts, jams and slow scans.
+//! Public with the `virtual-scanner` feature, built for this crate's tests either way.
+#![cfg_attr(not(feature = "virtual-scanner"), allow(dead_code))]
+
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper::{
+    body::Incoming,
+    header::{CONTENT_TYPE, LOCATION},
+    server::conn::http1,
+    service::service_fn,
+    Method, Request, Response, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use serde::Deserialize;
+use tokio::{net::TcpListener, task::JoinHandle, time::sleep};
+use xml::{
+    common::XmlVersion,
+    writer::{EmitterConfig, XmlEvent},
+};
+
+use crate::{
+    error::{AirscanError, Result},
+    settings::{write_element, InputSource, PWG_NS, SCAN_NS},
+    status::{AdfState, JobInfo, JobState},
+};
+
+const DEFAULT_CAPABILITIES: &str =
+    include_str!("../testdata/capabilities/hp_color_laserjet_m479fdw.xml");
+const DEFAULT_PAGE: &[u8] = b"%PDF-1.4\n% airscan virtual page\n%%EOF\n";
+const JOBS_PATH: &str = "/eSCL/ScanJobs";
+
+/// A document returned by `NextDocument`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VirtualPage {
+    pub content: Vec<u8>,
+    pub content_type: String,
+}
+
+impl VirtualPage {
+    pub fn new(content: impl Into<Vec<u8>>, content_type: impl Into<String>) -> Self {
+        VirtualPage {
+            content: content.into(),
+            content_type: content_type.into(),
+        }
+    }
+
+    /// Reads a page, the content type is guessed from the file extension.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        let content_type = match extension.as_str() {
+            "pdf" => "application/pdf",
+            "jpg" | "jpeg" => "image/jpeg",
+            "png" => "image/png",
+            "tif" | "tiff" => "image/tiff",
+            _ => "application/octet-stream",
+        };
+        Ok(VirtualPage::new(std::fs::read(path)?, content_type))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct VirtualScannerBuilder {
+    capabilities: String,
+    pages: Vec<VirtualPage>,
+    adf_sheets: u32,
+    busy_responses: u32,
+    page_busy_responses: u32,
+    jam_after: Option<u32>,
+    page_delay: Duration,
+}
+
+impl Default for VirtualScannerBuilder {
+    fn default() -> Self {
+        VirtualScannerBuilder {
+            capabilities: DEFAULT_CAPABILITIES.to_string(),
+            pages: Vec::new(),
+            adf_sheets: 1,
+            busy_responses: 0,
+            page_busy_responses: 0,
+            jam_after: None,
+            page_delay: Duration::ZERO,
+        }
+    }
+}
+
+impl VirtualScannerBuilder {
+    /// `ScannerCapabilities` XML, defaults to a duplex capable HP scanner.
+    pub fn capabilities(mut self, xml: impl Into<String>) -> Self {
+        self.capabilities = xml.into();
+        self
+    }
+
+    /// Adds a page. Jobs cycle through the pages, a small PDF is used if none is added.
+    pub fn page(mut self, page: VirtualPage) -> Self {
+        self.pages.push(page);
+        self
+    }
+
+    pub fn page_file(self, path: impl AsRef<Path>) -> Result<Self> {
+        Ok(self.page(VirtualPage::from_file(path)?))
+    }
+
+    /// Sheets loaded into the feeder, 0 reports an empty ADF. Defaults to 1.
+    pub fn adf_sheets(mut self, sheets: u32) -> Self {
+        self.adf_sheets = sheets;
+        self
+    }
+
+    /// Answers this many `ScanJobs` requests with 503 before accepting a job.
+    pub fn busy_responses(mut self, count: u32) -> Self {
+        self.busy_responses = count;
+        self
+    }
+
+    /// Answers `NextDocument` with 503 this many times before each page, as while scanning.
+    pub fn page_busy_responses(mut self, count: u32) -> Self {
+        self.page_busy_responses = count;
+        self
+    }
+
+    /// Jams the feeder after delivering `pages` pages, aborting the job.
+    pub fn jam_after(mut self, pages: u32) -> Self {
+        self.jam_after = Some(pages);
+        self
+    }
+
+    /// Delay before each page is returned.
+    pub fn page_delay(mut self, delay: Duration) -> Self {
+        self.page_delay = delay;
+        self
+    }
+
+    /// Starts serving on a free port of 127.0.0.1.
+    pub async fn start(self) -> Result<VirtualScanner> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        let state = Arc::new(Mutex::new(State::new(self, address)));
+
+        let server = tokio::spawn({
+            let state = state.clone();
+            async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    let state = state.clone();
+                    tokio::spawn(async move {
+                        let service = service_fn(move |request| handle(state.clone(), request));
+                        // Connection errors only affect the client that caused them.
+                        let _ = http1::Builder::new()
+                            .serve_connection(TokioIo::new(stream), service)
+                            .await;
+                    });
+                }
+            }
+        });
+
+        Ok(VirtualScanner {
+            address,
+            state,
+            server,
+        })
+    }
+}
+
+/// Running virtual scanner, stops accepting connections when dropped.
+#[derive(Debug)]
+pub struct VirtualScanner {
+    address: SocketAddr,
+    state: Arc<Mutex<State>>,
+    server: JoinHandle<()>,
+}
+
+impl VirtualScanner {
+    pub fn builder() -> VirtualScannerBuilder {
+        VirtualScannerBuilder::default()
+    }
+
+    /// eSCL base URL, e.g. `http://127.0.0.1:38117/eSCL`.
+    pub fn url(&self) -> String {
+        format!("http://{}/eSCL", self.address)
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Jobs created so far, as reported by `ScannerStatus`.
+    pub fn jobs(&self) -> Vec<JobInfo> {
+        let state = self.state.lock().unwrap();
+        state.jobs.iter().map(Job::info).collect()
+    }
+
+    /// `ScanSettings` documents posted so far.
+    pub fn scan_settings(&self) -> Vec<String> {
+        self.state.lock().unwrap().scan_settings.clone()
+    }
+
+    /// Loads sheets into the feeder and clears a jam.
+    pub fn load_adf(&self, sheets: u32) {
+        let mut state = self.state.lock().unwrap();
+        state.adf_sheets = sheets;
+        state.jammed = false;
+    }
+}
+
+impl Drop for VirtualScanner {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    config: VirtualScannerBuilder,
+    address: SocketAddr,
+    adf_sheets: u32,
+    busy_responses: u32,
+    jammed: bool,
+    pages_delivered: u32,
+    next_job: u32,
+    jobs: Vec<Job>,
+    scan_settings: Vec<String>,
+}
+
+#[derive(Debug)]
+struct Job {
+    uuid: String,
+    created: Instant,
+    pages: u32,
+    pages_delivered: u32,
+    busy_responses: u32,
+    from_adf: bool,
+    state: JobState,
+    reasons: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ReceivedSettings {
+    input_source: Option<InputSource>,
+    duplex: Option<bool>,
+}
+
+impl State {
+    fn new(config: VirtualScannerBuilder, address: SocketAddr) -> Self {
+        State {
+            adf_sheets: config.adf_sheets,
+            busy_responses: config.busy_responses,
+            config,
+            address,
+            jammed: false,
+            pages_delivered: 0,
+            next_job: 1,
+            jobs: Vec::new(),
+            scan_settings: Vec::new(),
+        }
+    }
+
+    fn adf_state(&self) -> AdfState {
+        if self.jammed {
+            AdfState::Jam
+        } else if self.adf_sheets > 0 {
+            AdfState::Loaded
+        } else {
+            AdfState::Empty
+        }
+    }
+
+    fn job_mut(&mut self, uuid: &str) -> Option<&mut Job> {
+        self.jobs.iter_mut().find(|job| job.uuid == uuid)
+    }
+
+    fn create_job(&mut self, settings: &ReceivedSettings) -> Option<String> {
+        if self.busy_responses > 0 {
+            self.busy_responses -= 1;
+            return None;
+        }
+        let source = settings.input_source.unwrap_or(InputSource::Platen);
+        let from_adf = source == InputSource::Feeder;
+        let pages = if from_adf {
+            if self.adf_state() != AdfState::Loaded {
+                return None;
+            }
+            let sides = if settings.duplex == Some(true) { 2 } else { 1 };
+            self.adf_sheets * sides
+        } else {
+            1
+        };
+
+        let uuid = format!(
+            "{:08x}-0000-4000-8000-{:012x}",
+            self.next_job, self.next_job
+        );
+        self.next_job += 1;
+        self.jobs.push(Job {
+            uuid: uuid.clone(),
+            created: Instant::now(),
+            pages,
+            pages_delivered: 0,
+            busy_responses: self.config.page_busy_responses,
+            from_adf,
+            state: JobState::Processing,
+            reasons: vec![String::from("JobScanning")],
+        });
+        Some(uuid)
+    }
+
+    /// Next page of the job, `Err` holds the status code if there is none yet or anymore.
+    fn next_document(&mut self, uuid: &str) -> std::result::Result<VirtualPage, StatusCode> {
+        let page_busy_responses = self.config.page_busy_responses;
+        let jam_after = self.config.jam_after;
+        let pages_delivered = self.pages_delivered;
+        let page = self.page(pages_delivered);
+
+        let job = self.job_mut(uuid).ok_or(StatusCode::NOT_FOUND)?;
+        match job.state {
+            JobState::Aborted => return Err(StatusCode::SERVICE_UNAVAILABLE),
+            state if state.is_final() => return Err(StatusCode::NOT_FOUND),
+            _ => {}
+        }
+        if job.busy_responses > 0 {
+            job.busy_responses -= 1;
+            return Err(StatusCode::SERVICE_UNAVAILABLE);
+        }
+        if job.from_adf && jam_after.is_some_and(|jam_after| job.pages_delivered >= jam_after) {
+            job.state = JobState::Aborted;
+            job.reasons = vec![String::from("AdfJam")];
+            self.jammed = true;
+            return Err(StatusCode::SERVICE_UNAVAILABLE);
+        }
+
+        job.pages_delivered += 1;
+        job.busy_responses = page_busy_responses;
+        if job.pages_delivered >= job.pages {
+            job.state = JobState::Completed;
+            job.reasons = vec![String::from("JobCompletedSuccessfully")];
+        }
+        let from_adf = job.from_adf;
+        let finished = job.state == JobState::Completed;
+
+        self.pages_delivered += 1;
+        if from_adf && finished {
+            self.adf_sheets = 0;
+        }
+        Ok(page)
+    }
+
+    fn page(&self, index: u32) -> VirtualPage {
+        let pages = &self.config.pages;
+        if pages.is_empty() {
+            return VirtualPage::new(DEFAULT_PAGE, "application/pdf");
+        }
+        pages[index as usize % pages.len()].clone()
+    }
+
+    fn cancel_job(&mut self, uuid: &str) -> bool {
+        let Some(job) = self.job_mut(uuid) else {
+            return false;
+        };
+        if !job.state.is_final() {
+            job.state = JobState::Canceled;
+            job.reasons = vec![String::from("JobCanceledByUser")];
+        }
+        true
+    }
+
+    fn status_xml(&self) -> Result<String> {
+        let processing = self.jobs.iter().any(|job| !job.state.is_final());
+        let mut writer = EmitterConfig::new().create_writer(Vec::new());
+        writer.write(XmlEvent::StartDocument {
+            version: XmlVersion::Version10,
+            encoding: Some("UTF-8"),
+            standalone: None,
+        })?;
+        writer.write(
+            XmlEvent::start_element("scan:ScannerStatus")
+                .ns("pwg", PWG_NS)
+                .ns("scan", SCAN_NS),
+        )?;
+        write_element(&mut writer, "pwg:Version", "2.63")?;
+        write_element(
+            &mut writer,
+            "pwg:State",
+            if processing { "Processing" } else { "Idle" },
+        )?;
+        write_element(
+            &mut writer,
+            "scan:AdfState",
+            adf_state_name(self.adf_state()),
+        )?;
+        writer.write(XmlEvent::start_element("scan:Jobs"))?;
+        for job in &self.jobs {
+            let info = job.info();
+            writer.write(XmlEvent::start_element("scan:JobInfo"))?;
+            write_element(&mut writer, "pwg:JobUri", &info.job_uri)?;
+            write_element(&mut writer, "pwg:JobUuid", &job.uuid)?;
+            write_element(&mut writer, "scan:Age", &info.age.unwrap_or(0).to_string())?;
+            write_element(
+                &mut writer,
+                "pwg:ImagesCompleted",
+                &job.pages_delivered.to_string(),
+            )?;
+            write_element(
+                &mut writer,
+                "pwg:ImagesToTransfer",
+                &info.images_to_transfer.unwrap_or(0).to_string(),
+            )?;
+            write_element(&mut writer, "pwg:JobState", &format!("{:?}", job.state))?;
+            writer.write(XmlEvent::start_element("pwg:JobStateReasons"))?;
+            for reason in &job.reasons {
+                write_element(&mut writer, "pwg:JobStateReason", reason)?;
+            }
+            writer.write(XmlEvent::end_element())?;
+            writer.write(XmlEvent::end_element())?;
+        }
+        writer.write(XmlEvent::end_element())?;
+        writer.write(XmlEvent::end_element())?;
+
+        String::from_utf8(writer.into_inner()).map_err(|error| AirscanError::Xml(error.to_string()))
+    }
+}
+
+impl Job {
+    fn info(&self) -> JobInfo {
+        let images_to_transfer = match self.state {
+            JobState::Pending | JobState::Processing => self.pages - self.pages_delivered,
+            _ => 0,
+        };
+        JobInfo {
+            job_uri: format!("{}/{}", JOBS_PATH, self.uuid),
+            job_uuid: Some(self.uuid.clone()),
+            age: Some(self.created.elapsed().as_secs() as u32),
+            images_completed: Some(self.pages_delivered),
+            images_to_transfer: Some(images_to_transfer),
+            job_state: self.state,
+            job_state_reasons: self.reasons.clone(),
+        }
+    }
+}
+
+fn adf_state_name(state: AdfState) -> &'static str {
+    match state {
+        AdfState::Jam => "ScannerAdfJam",
+        AdfState::Loaded => "ScannerAdfLoaded",
+        _ => "ScannerAdfEmpty",
+    }
+}
+
+async fn handle(
+    state: Arc<Mutex<State>>,
+    request: Request<Incoming>,
+) -> std::result::Result<Response<Full<Bytes>>, Infallible> {
+    let method = request.method().clone();
+    let path = request.uri().path().trim_end_matches('/').to_string();
+    let job_path = path
+        .strip_prefix(JOBS_PATH)
+        .and_then(|rest| rest.strip_prefix('/'));
+    let body = match request.into_body().collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(_) => return Ok(empty(StatusCode::BAD_REQUEST)),
+    };
+
+    let response = match (method, path.as_str(), job_path) {
+        (Method::GET, "/eSCL/ScannerCapabilities", _) => {
+            let capabilities = state.lock().unwrap().config.capabilities.clone();
+            xml(capabilities)
+        }
+        (Method::GET, "/eSCL/ScannerStatus", _) => match state.lock().unwrap().status_xml() {
+            Ok(status) => xml(status),
+            Err(_) => empty(StatusCode::INTERNAL_SERVER_ERROR),
+        },
+        (Method::POST, JOBS_PATH, _) => {
+            let Ok(body) = std::str::from_utf8(&body) else {
+                return Ok(empty(StatusCode::BAD_REQUEST));
+            };
+            let Ok(settings) = serde_xml_rs::from_str::<ReceivedSettings>(body) else {
+                return Ok(empty(StatusCode::BAD_REQUEST));
+            };
+            let mut state = state.lock().unwrap();
+            state.scan_settings.push(body.to_string());
+            match state.create_job(&settings) {
+                Some(uuid) => Response::builder()
+                    .status(StatusCode::CREATED)
+                    .header(
+                        LOCATION,
+                        format!("http://{}{}/{}", state.address, JOBS_PATH, uuid),
+                    )
+                    .body(Full::default())
+                    .unwrap(),
+                None => empty(StatusCode::SERVICE_UNAVAILABLE),
+            }
+        }
+        (Method::GET, _, Some(job)) if job.ends_with("/NextDocument") => {
+            let uuid = job.trim_end_matches("/NextDocument");
+            let (page, delay) = {
+                let mut state = state.lock().unwrap();
+                (state.next_document(uuid), state.config.page_delay)
+            };
+            match page {
+                Ok(page) => {
+                    sleep(delay).await;
+                    Response::builder()
+                        .status(StatusCode::OK)
+                        .header(CONTENT_TYPE, page.content_type)
+                        .body(Full::new(Bytes::from(page.content)))
+                        .unwrap()
+                }
+                Err(status) => empty(status),
+            }
+        }
+        (Method::DELETE, _, Some(uuid)) => {
+            if state.lock().unwrap().cancel_job(uuid) {
+                empty(StatusCode::OK)
+            } else {
+                empty(StatusCode::NOT_FOUND)
+            }
+        }
+        _ => empty(StatusCode::NOT_FOUND),
+    };
+    Ok(response)
+}
+
+fn xml(body: String) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "text/xml")
+        .body(Full::new(Bytes::from(body)))
+        .unwrap()
+}
+
+fn empty(status: StatusCode) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(status)
+        .body(Full::default())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::{
+        client::{FetchOptions, ScannerClient},
+        fetch_result, get_status, post_scanrequest,
+        retry::RetryPolicy,
+        settings::ScanSettings,
+        status::ScannerState,
+    };
+
+    fn fast_client(scanner: &VirtualScanner) -> ScannerClient {
+        ScannerClient::builder(&scanner.url())
+            .retry_policy(RetryPolicy::fixed(Duration::from_millis(10)).with_max_attempts(20))
+            .build()
+            .unwrap()
+    }
+
+    fn feeder(duplex: bool) -> ScanSettings {
+        ScanSettings::builder()
+            .input_source(InputSource::Feeder)
+            .document_format("image/jpeg")
+            .duplex(duplex)
+            .build()
+    }
+
+    #[tokio::test]
+    async fn platen_job_end_to_end() {
+        let scanner = VirtualScanner::builder().start().await.unwrap();
+
+        let location = post_scanrequest(&scanner.url(), &ScanSettings::default())
+            .await
+            .unwrap();
+        fetch_result(location, "test_virtual.pdf", false)
+            .await
+            .unwrap();
+
+        assert_eq!(fs::read("test_virtual.pdf").unwrap(), DEFAULT_PAGE);
+        fs::remove_file("test_virtual.pdf").unwrap();
+        let jobs = scanner.jobs();
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].job_state, JobState::Completed);
+        let status = get_status(&scanner.url()).await.unwrap();
+        assert_eq!(status.state, ScannerState::Idle);
+        assert_eq!(status.jobs[0].images_completed, Some(1));
+    }
+
+    #[tokio::test]
+    async fn duplex_feeder_with_busy_periods() {
+        let scanner = VirtualScanner::builder()
+            .page(VirtualPage::new(b"front".to_vec(), "image/png"))
+            .page(VirtualPage::new(b"back".to_vec(), "image/png"))
+            .adf_sheets(2)
+            .busy_responses(2)
+            .page_busy_responses(1)
+            .page_delay(Duration::from_millis(20))
+            .start()
+            .await
+            .unwrap();
+        let client = fast_client(&scanner);
+
+        let location = client.submit_job(&feeder(true)).await.unwrap();
+        client
+            .fetch_result(
+                &location,
+                "test_virtual_duplex.png",
+                true,
+                &FetchOptions::default(),
+            )
+            .await
+            .unwrap();
+
+        for (page, content) in [(1, "front"), (2, "back"), (3, "front"), (4, "back")] {
+            let filename = format!("test_virtual_duplex-{}.png", page);
+            assert_eq!(fs::read_to_string(&filename).unwrap(), content);
+            fs::remove_file(filename).unwrap();
+        }
+        assert!(!Path::new("test_virtual_duplex-5.png").exists());
+        let status = client.status().await.unwrap();
+        assert_eq!(status.adf_state, Some(AdfState::Empty));
+    }
+
+    #[tokio::test]
+    async fn empty_feeder() {
+        let scanner = VirtualScanner::builder()
+            .adf_sheets(0)
+            .start()
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            fast_client(&scanner).submit_job(&feeder(false)).await,
+            Err(AirscanError::AdfEmpty)
+        ));
+        assert!(scanner.jobs().is_empty());
+    }
+
+    #[tokio::test]
+    async fn jam_aborts_job() {
+        let scanner = VirtualScanner::builder()
+            .adf_sheets(3)
+            .jam_after(1)
+            .start()
+            .await
+            .unwrap();
+        let client = fast_client(&scanner);
+
+        let location = client.submit_job(&feeder(false)).await.unwrap();
+        assert!(client.next_document(&location).await.unwrap().is_some());
+        let error = client.next_document(&location).await.unwrap_err();
+
+        assert!(matches!(
+            error,
+            AirscanError::JobFailed { state: JobState::Aborted, ref reasons } if reasons == &["AdfJam"]
+        ));
+        assert_eq!(
+            client.status().await.unwrap().adf_state,
+            Some(AdfState::Jam)
+        );
+        assert!(matches!(
+            client.submit_job(&feeder(false)).await,
+            Err(AirscanError::AdfJam)
+        ));
+    }
+
+    #[tokio::test]
+    async fn cancel_running_job() {
+        let scanner = VirtualScanner::builder()
+            .adf_sheets(5)
+            .start()
+            .await
+            .unwrap();
+        let client = fast_client(&scanner);
+
+        let location = client.submit_job(&feeder(false)).await.unwrap();
+        client.cancel_job(&location).await.unwrap();
+
+        assert_eq!(scanner.jobs()[0].job_state, JobState::Canceled);
+        assert_eq!(client.next_document(&location).await.unwrap(), None);
+    }
+}